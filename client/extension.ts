@@ -1,7 +1,7 @@
 // Based on https://github.com/microsoft/vscode-extension-samples/blob/main/lsp-sample/client/src/extension.ts (MIT licensed).
 
 import * as os from "os";
-import { ExtensionContext } from "vscode";
+import { ExtensionContext, workspace } from "vscode";
 import {
 	LanguageClient,
 	LanguageClientOptions,
@@ -42,6 +42,14 @@ export function activate(context: ExtensionContext): void {
 	let clientOptions: LanguageClientOptions = {
 		// Register the server for Sail documents
 		documentSelector: [{ scheme: "file", language: "sail" }],
+		// Which files are compiled together, so names are resolved correctly.
+		initializationOptions: {
+			targets: workspace.getConfiguration("sail").get("targets", []),
+			activeTarget: workspace.getConfiguration("sail").get("activeTarget"),
+			compiler: workspace.getConfiguration("sail").get("compiler", {}),
+			cache: workspace.getConfiguration("sail").get("cache", {}),
+			scan: workspace.getConfiguration("sail").get("scan", {}),
+		},
 	};
 
 	// Create the language client and start the client.
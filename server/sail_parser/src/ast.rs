@@ -0,0 +1,455 @@
+//! Sail abstract syntax tree.
+//!
+//! This is produced by `parser::parse()` from the token stream. It is quite a
+//! lot simpler than the one in the Sail compiler; the aim is to support IDE
+//! features rather than type checking. Every node carries the span of the
+//! source it was parsed from.
+use crate::{Span, Token};
+
+pub type Spanned<T> = (T, Span);
+
+/// An identifier. For operators this is the operator text, e.g. `==`.
+pub type Ident = Spanned<String>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceFile {
+    pub defs: Vec<Spanned<Def>>,
+}
+
+/// A top-level definition.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Def {
+    /// `val foo : forall 'n. bits('n) -> unit`
+    Val(ValSpec),
+    /// `function foo(x) = ...`, possibly with several clauses joined by `and`.
+    Function(FunctionDef),
+    /// `function clause foo(x) = ...`
+    FunctionClause(FunctionClause),
+    /// `mapping foo : a <-> b = { ... }`
+    Mapping(MappingDef),
+    /// `mapping clause foo = a <-> b`
+    MappingClause {
+        name: Ident,
+        clause: Spanned<MappingClause>,
+    },
+    /// `type foo = bits(5)`
+    Type(TypeDef),
+    /// `struct foo = { a : int, b : bool }`
+    Struct(StructDef),
+    /// `union foo = { A : int, B : bool }`
+    Union(UnionDef),
+    /// `union clause foo = A : int`
+    UnionClause { name: Ident, variant: UnionVariant },
+    /// `enum foo = { A, B }` or `enum foo = A | B`
+    Enum(EnumDef),
+    /// `enum clause foo = A`
+    EnumClause { name: Ident, member: Ident },
+    /// `bitfield foo : bits(32) = { A : 31 .. 16, B : 0 }`
+    Bitfield(BitfieldDef),
+    /// `register foo : bits(64) = 0x0`
+    Register(RegisterDef),
+    /// `overload foo = { a, b }`
+    Overload(OverloadDef),
+    /// `scattered function foo`, `scattered union foo`, etc.
+    Scattered(ScatteredDef),
+    /// `end foo`
+    End(Ident),
+    /// `let foo : int = 5`
+    Let(LetBinding),
+    /// `default Order dec`
+    DefaultOrder(Spanned<Order>),
+    /// `infixl 5 <<`
+    Fixity(FixityDecl),
+    /// `termination_measure foo(x) = x`
+    TerminationMeasure {
+        name: Ident,
+        pat: Spanned<Pat>,
+        exp: Spanned<Exp>,
+    },
+    /// `$include <prelude.sail>` and similar.
+    Directive(String),
+    /// `$[attribute ...]`
+    Attribute,
+    /// A definition that couldn't be parsed. The parser skips to the next
+    /// top-level keyword. If it started with keywords and a name, e.g.
+    /// `function clause foo`, those are kept.
+    Error(Option<(Vec<Token>, Ident)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValSpec {
+    pub name: Ident,
+    /// External names, e.g. `{c: "foo", ocaml: "bar"}` or just `"foo"`, in
+    /// which case the backend name is `None`.
+    pub externs: Vec<(Option<Ident>, Spanned<String>)>,
+    pub typschm: Spanned<TypeScheme>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionDef {
+    pub is_rec: bool,
+    pub clauses: Vec<Spanned<FunctionClause>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionClause {
+    pub name: Ident,
+    /// Usually a tuple pattern of the parameters, e.g. `(x, y)`, but could be
+    /// any pattern, e.g. `ADD(rs1, rs2)` for `execute` clauses.
+    pub params: Spanned<Pat>,
+    pub return_type: Option<Spanned<Typ>>,
+    pub body: Spanned<Exp>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappingDef {
+    pub name: Ident,
+    pub typschm: Option<Spanned<TypeScheme>>,
+    pub clauses: Vec<Spanned<MappingClause>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MappingClause {
+    /// `a <-> b`
+    Bidirectional(Spanned<MappingPat>, Spanned<MappingPat>),
+    /// `a => b` or `forwards a => b`
+    Forwards(Spanned<MappingPat>, Spanned<Exp>),
+    /// `backwards a => b`
+    Backwards(Spanned<MappingPat>, Spanned<Exp>),
+}
+
+/// A pattern in a mapping, with an optional guard (`pat if exp`).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappingPat {
+    pub pat: Spanned<Pat>,
+    pub guard: Option<Spanned<Exp>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TypeDef {
+    pub name: Ident,
+    pub params: Vec<KindedId>,
+    pub kind: Option<Spanned<Kind>>,
+    /// `None` for abstract types, e.g. `type xlen : Int`.
+    pub typ: Option<Spanned<Typ>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StructDef {
+    pub name: Ident,
+    pub params: Vec<KindedId>,
+    pub fields: Vec<(Ident, Spanned<Typ>)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnionDef {
+    pub name: Ident,
+    pub params: Vec<KindedId>,
+    pub variants: Vec<UnionVariant>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnionVariant {
+    pub name: Ident,
+    pub typ: Spanned<Typ>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnumDef {
+    pub name: Ident,
+    /// Enum functions, e.g. `enum foo with bar -> int = { A => 1 }`.
+    pub functions: Vec<(Ident, Spanned<Typ>)>,
+    pub members: Vec<EnumMember>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnumMember {
+    pub name: Ident,
+    /// The value for enum functions, e.g. `A => struct { bar = 1 }`.
+    pub value: Option<Spanned<Exp>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BitfieldDef {
+    pub name: Ident,
+    pub typ: Spanned<Typ>,
+    pub fields: Vec<BitfieldField>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BitfieldField {
+    pub name: Ident,
+    pub high: Spanned<Typ>,
+    /// `None` for single bit fields.
+    pub low: Option<Spanned<Typ>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegisterDef {
+    pub name: Ident,
+    pub is_configuration: bool,
+    pub typ: Spanned<Typ>,
+    pub init: Option<Spanned<Exp>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OverloadDef {
+    pub name: Ident,
+    pub members: Vec<Ident>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScatteredDef {
+    Function(Ident),
+    Mapping {
+        name: Ident,
+        typschm: Option<Spanned<TypeScheme>>,
+    },
+    Union {
+        name: Ident,
+        params: Vec<KindedId>,
+    },
+    Enum(Ident),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LetBinding {
+    pub pat: Spanned<Pat>,
+    pub exp: Spanned<Exp>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Order {
+    Inc,
+    Dec,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Assoc {
+    /// `infix`
+    None,
+    /// `infixl`
+    Left,
+    /// `infixr`
+    Right,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FixityDecl {
+    pub assoc: Assoc,
+    pub level: Spanned<u8>,
+    pub op: Ident,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Kind {
+    Int,
+    Bool,
+    Type,
+    Order,
+}
+
+/// A type variable with an optional kind, e.g. `'n` or `('n : Int)`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KindedId {
+    pub name: Ident,
+    pub kind: Option<Spanned<Kind>>,
+}
+
+/// `forall 'n, 'n > 0. bits('n) -> unit`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TypeScheme {
+    pub quantifiers: Vec<KindedId>,
+    pub constraint: Option<Spanned<Typ>>,
+    pub typ: Spanned<Typ>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Typ {
+    /// `_`
+    Wild,
+    Id(String),
+    /// `'n` (the `'` is included)
+    TyVar(String),
+    Lit(Lit),
+    Order(Order),
+    /// `bits(32)`
+    App(Ident, Vec<Spanned<Typ>>),
+    /// `(a, b)`
+    Tuple(Vec<Spanned<Typ>>),
+    /// Numeric or constraint operators, e.g. `'n + 1` or `'n in {32, 64}`.
+    Infix(Box<Spanned<Typ>>, Ident, Box<Spanned<Typ>>),
+    /// `-'n`
+    Neg(Box<Spanned<Typ>>),
+    /// `{32, 64}`
+    Set(Vec<Spanned<Typ>>),
+    /// `{'n, 'n > 0. int('n)}`
+    Exist {
+        vars: Vec<KindedId>,
+        constraint: Option<Box<Spanned<Typ>>>,
+        typ: Box<Spanned<Typ>>,
+    },
+    /// An anonymous struct type, used in union variants, e.g. `{ a : int }`.
+    Record(Vec<(Ident, Spanned<Typ>)>),
+    /// `a -> b`. The effects (which are obsolete) are discarded.
+    Function(Box<Spanned<Typ>>, Box<Spanned<Typ>>),
+    /// `a <-> b`
+    Bidirectional(Box<Spanned<Typ>>, Box<Spanned<Typ>>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Lit {
+    Unit,
+    Bool(bool),
+    Num(String),
+    Hex(String),
+    Bin(String),
+    Real(String),
+    String(String),
+    BitZero,
+    BitOne,
+    Undefined,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Pat {
+    /// `_`
+    Wild,
+    Id(String),
+    TyVar(String),
+    Lit(Lit),
+    /// `Some(x)`
+    App(Ident, Vec<Spanned<Pat>>),
+    Tuple(Vec<Spanned<Pat>>),
+    /// `[a, b]`
+    Vector(Vec<Spanned<Pat>>),
+    /// `[| a, b |]`
+    List(Vec<Spanned<Pat>>),
+    /// `a @ b`, `a :: b`, `a ^ b`, etc.
+    Infix(Box<Spanned<Pat>>, Ident, Box<Spanned<Pat>>),
+    /// `x : int`
+    Typed(Box<Spanned<Pat>>, Box<Spanned<Typ>>),
+    /// `x as y`
+    As(Box<Spanned<Pat>>, Ident),
+    /// `x[7 .. 0]` or `x[3]`
+    Subrange(Ident, Box<Spanned<Typ>>, Option<Box<Spanned<Typ>>>),
+    /// `struct { a = x, b = y }`
+    Struct(Vec<(Ident, Spanned<Pat>)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchArm {
+    pub pat: Spanned<Pat>,
+    pub guard: Option<Spanned<Exp>>,
+    pub body: Spanned<Exp>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Exp {
+    Id(String),
+    TyVar(String),
+    Lit(Lit),
+    /// `{ a; b; c }`
+    Block(Vec<Spanned<Exp>>),
+    /// `let x = 5 in ...`, or `let x = 5;` inside a block, in which case the
+    /// body is `None` and the binding scopes over the rest of the block.
+    Let(Box<LetBinding>, Option<Box<Spanned<Exp>>>),
+    /// `var x = 5 in ...`, or `var x = 5;` inside a block.
+    Var(Box<LetBinding>, Option<Box<Spanned<Exp>>>),
+    /// `x = 5`
+    Assign(Box<Spanned<Exp>>, Box<Spanned<Exp>>),
+    If(
+        Box<Spanned<Exp>>,
+        Box<Spanned<Exp>>,
+        Option<Box<Spanned<Exp>>>,
+    ),
+    Match(Box<Spanned<Exp>>, Vec<Spanned<MatchArm>>),
+    Try(Box<Spanned<Exp>>, Vec<Spanned<MatchArm>>),
+    /// `foreach (i from 0 to 31 by 1 in inc) ...`
+    Foreach {
+        var: Ident,
+        from: Box<Spanned<Exp>>,
+        to: Box<Spanned<Exp>>,
+        step: Option<Box<Spanned<Exp>>>,
+        order: Option<Spanned<Order>>,
+        body: Box<Spanned<Exp>>,
+    },
+    While(Box<Spanned<Exp>>, Box<Spanned<Exp>>),
+    /// `repeat ... until ...`
+    Repeat(Box<Spanned<Exp>>, Box<Spanned<Exp>>),
+    /// `foo(a, b)`
+    Call(Ident, Vec<Spanned<Exp>>),
+    Tuple(Vec<Spanned<Exp>>),
+    /// `[a, b]`
+    Vector(Vec<Spanned<Exp>>),
+    /// `[| a, b |]`
+    List(Vec<Spanned<Exp>>),
+    /// `x.foo`
+    Field(Box<Spanned<Exp>>, Ident),
+    /// `x.foo()`, which is mostly used for bitfield accessors.
+    Method(Box<Spanned<Exp>>, Ident, Vec<Spanned<Exp>>),
+    /// `x[5]`
+    Index(Box<Spanned<Exp>>, Box<Spanned<Exp>>),
+    /// `x[7 .. 0]`
+    Slice(Box<Spanned<Exp>>, Box<Spanned<Exp>>, Box<Spanned<Exp>>),
+    /// `[x with 3 = a, 7 .. 4 = b]`
+    VectorUpdate(Box<Spanned<Exp>>, Vec<VectorUpdate>),
+    /// `struct { a = 1, b = 2 }`
+    Struct(Vec<(Ident, Spanned<Exp>)>),
+    /// `{ x with a = 1 }`
+    StructUpdate(Box<Spanned<Exp>>, Vec<(Ident, Spanned<Exp>)>),
+    /// `x : bits(5)`
+    Typed(Box<Spanned<Exp>>, Spanned<Typ>),
+    Infix(Box<Spanned<Exp>>, Ident, Box<Spanned<Exp>>),
+    /// `-x`
+    Neg(Box<Spanned<Exp>>),
+    Return(Box<Spanned<Exp>>),
+    Throw(Box<Spanned<Exp>>),
+    Exit(Option<Box<Spanned<Exp>>>),
+    Assert(Box<Spanned<Exp>>, Option<Box<Spanned<Exp>>>),
+    /// `ref foo`
+    Ref(Ident),
+    Sizeof(Spanned<Typ>),
+    Constraint(Spanned<Typ>),
+    /// An expression that couldn't be parsed.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VectorUpdate {
+    pub high: Spanned<Exp>,
+    pub low: Option<Spanned<Exp>>,
+    pub value: Spanned<Exp>,
+}
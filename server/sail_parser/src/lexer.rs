@@ -16,6 +16,7 @@ pub type Span = SimpleSpan<usize>;
 // TODO: Make tokens zero copy &str when we have a parser as well as a lexer.
 // For now they are String to keep things simple.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
     // Identifiers
     Id(String),
@@ -30,6 +31,12 @@ pub enum Token {
     // String literal.
     String(String),
 
+    // User-defined operator, e.g. <_u or >>.
+    Op(String),
+
+    // Compiler directive, e.g. $include <prelude.sail> (the $ is discarded).
+    Directive(String),
+
     // Operators and control characters.
     Dollar,
     LeftBracket,        // (
@@ -69,6 +76,7 @@ pub enum Token {
     RightSquareBar, // |]
     Underscore,     // _
     Unit,           // ()
+    DotDot,         // ..
 
     // Keywords.
     KwAnd,
@@ -170,6 +178,12 @@ impl fmt::Display for Token {
             // String literal.
             Token::String(s) => write!(f, "{}", s),
 
+            // User-defined operator.
+            Token::Op(s) => write!(f, "{}", s),
+
+            // Compiler directive.
+            Token::Directive(s) => write!(f, "${}", s),
+
             // Operators and other control characters.
             Token::Dollar => write!(f, "$"),
             Token::LeftBracket => write!(f, "("),
@@ -209,6 +223,7 @@ impl fmt::Display for Token {
             Token::RightSquareBar => write!(f, "|]"),
             Token::Underscore => write!(f, "_"),
             Token::Unit => write!(f, "()"),
+            Token::DotDot => write!(f, ".."),
 
             // Keywords.
             Token::KwAnd => write!(f, "and"),
@@ -296,11 +311,99 @@ impl fmt::Display for Token {
     }
 }
 
+/// The keyword token for `ident`, or `None` if it isn't a keyword.
+pub fn keyword(ident: &str) -> Option<Token> {
+    Some(match ident {
+        "and" => Token::KwAnd,
+        "as" => Token::KwAs,
+        "assert" => Token::KwAssert,
+        "backwards" => Token::KwBackwards,
+        "barr" => Token::KwBarr,
+        "bitfield" => Token::KwBitfield,
+        "bitone" => Token::KwBitone,
+        "bitzero" => Token::KwBitzero,
+        "Bool" => Token::KwBool,
+        "by" => Token::KwBy,
+        "cast" => Token::KwCast,
+        "catch" => Token::KwCatch,
+        "clause" => Token::KwClause,
+        "configuration" => Token::KwConfiguration,
+        "constant" => Token::KwConstant,
+        "constraint" => Token::KwConstraint,
+        "dec" => Token::KwDec,
+        "default" => Token::KwDefault,
+        "depend" => Token::KwDepend,
+        "do" => Token::KwDo,
+        "eamem" => Token::KwEamem,
+        "effect" => Token::KwEffect,
+        "else" => Token::KwElse,
+        "end" => Token::KwEnd,
+        "enum" => Token::KwEnum,
+        "escape" => Token::KwEscape,
+        "exit" => Token::KwExit,
+        "exmem" => Token::KwExmem,
+        "false" => Token::KwFalse,
+        "forall" => Token::KwForall,
+        "foreach" => Token::KwForeach,
+        "forwards" => Token::KwForwards,
+        "function" => Token::KwFunction,
+        "if" => Token::KwIf,
+        "impl" => Token::KwImpl,
+        "in" => Token::KwIn,
+        "inc" => Token::KwInc,
+        "infix" => Token::KwInfix,
+        "infixl" => Token::KwInfixl,
+        "infixr" => Token::KwInfixr,
+        "instantiation" => Token::KwInstantiation,
+        "Int" => Token::KwInt,
+        "let" => Token::KwLet,
+        "mapping" => Token::KwMapping,
+        "match" => Token::KwMatch,
+        "monadic" => Token::KwMonadic,
+        "mutual" => Token::KwMutual,
+        "mwv" => Token::KwMwv,
+        "newtype" => Token::KwNewtype,
+        "nondet" => Token::KwNondet,
+        "Order" => Token::KwOrder,
+        "outcome" => Token::KwOutcome,
+        "overload" => Token::KwOverload,
+        "pure" => Token::KwPure,
+        "ref" => Token::KwRef,
+        "register" => Token::KwRegister,
+        "repeat" => Token::KwRepeat,
+        "return" => Token::KwReturn,
+        "rmem" => Token::KwRmem,
+        "rreg" => Token::KwRreg,
+        "scattered" => Token::KwScattered,
+        "sizeof" => Token::KwSizeof,
+        "struct" => Token::KwStruct,
+        "termination_measure" => Token::KwTerminationMeasure,
+        "then" => Token::KwThen,
+        "throw" => Token::KwThrow,
+        "true" => Token::KwTrue,
+        "try" => Token::KwTry,
+        "type" => Token::KwType,
+        "Type" => Token::KwTypeUpper,
+        "undef" => Token::KwUndef,
+        "undefined" => Token::KwUndefined,
+        "union" => Token::KwUnion,
+        "unspec" => Token::KwUnspec,
+        "until" => Token::KwUntil,
+        "val" => Token::KwVal,
+        "var" => Token::KwVar,
+        "while" => Token::KwWhile,
+        "with" => Token::KwWith,
+        "wmem" => Token::KwWmem,
+        "wreg" => Token::KwWreg,
+        _ => return None,
+    })
+}
+
 /// Same as C identifiers but ? is allowed and ' is allowed after the first character.
 /// Also '~' is allowed as a special identifier.
 #[must_use]
 pub fn ident<'a, I: ValueInput<'a> + StrInput<'a, char>, E: ParserExtra<'a, I>>(
-) -> impl Parser<'a, I, &'a str, E> + Copy + Clone {
+) -> impl Parser<'a, I, &'a str, E> + Copy {
     any()
         // Use try_map over filter to get a better error on failure
         .try_map(|c: char, span| {
@@ -326,7 +429,7 @@ pub fn ident<'a, I: ValueInput<'a> + StrInput<'a, char>, E: ParserExtra<'a, I>>(
 pub fn n_digits<'a, C, I, E>(
     radix: u32,
     count: usize,
-) -> Repeated<impl Parser<'a, I, C, E> + Copy + Clone, C, I, E>
+) -> Repeated<impl Parser<'a, I, C, E> + Copy, C, I, E>
 where
     C: Char,
     I: ValueInput<'a> + Input<'a, Token = C>,
@@ -347,6 +450,18 @@ where
 
 pub fn lexer<'src>(
 ) -> impl Parser<'src, &'src str, Vec<(Token, Span)>, extra::Err<Rich<'src, char, Span>>> {
+    padded_token()
+        .repeated()
+        .collect::<Vec<_>>()
+        .map(|tokens| tokens.into_iter().flatten().collect())
+        .then_ignore(end())
+}
+
+/// A token and the whitespace and comments around it, or `None` if a
+/// character was skipped. The lexer is just this repeated, so lexing can be
+/// restarted at any token (see `relex()`).
+pub(crate) fn padded_token<'src>(
+) -> impl Parser<'src, &'src str, Option<(Token, Span)>, extra::Err<Rich<'src, char, Span>>> {
     // Arbitrary length positive or negative integer.
     let num = just('-')
         .or_not()
@@ -391,11 +506,11 @@ pub fn lexer<'src>(
             just('r').to('\r'),
             just('\n').to(' '), // TODO: Handle this properly.
             just('d').ignore_then(n_digits(10, 3).to_slice().try_map(|digits: &str, span| {
-                char::from_u32(u32::from_str_radix(&digits, 10).unwrap())
+                char::from_u32(digits.parse::<u32>().unwrap())
                     .ok_or_else(|| Rich::custom(span, "invalid decimal unicode value"))
             })),
             just('x').ignore_then(n_digits(16, 2).to_slice().try_map(|digits: &str, span| {
-                char::from_u32(u32::from_str_radix(&digits, 16).unwrap())
+                char::from_u32(u32::from_str_radix(digits, 16).unwrap())
                     .ok_or_else(|| Rich::custom(span, "invalid hex unicode value"))
             })),
         )))
@@ -408,54 +523,84 @@ pub fn lexer<'src>(
         .map(|s: &str| Token::String(s.to_owned()))
         .boxed();
 
-    // The order of these is important, e.g. <= must come before < otherwise
-    // <= will be parsed as <, =.
-    // Have to split it into two choices because there's more than 26 and
-    // they are different types.
-    let op = choice((
+    // Brackets and other control characters. The bracket/bar combinations
+    // have to come first, otherwise e.g. |] would be lexed as an operator
+    // followed by ].
+    let ctrl = choice((
         just("|}").to(Token::RightCurlyBar),
         just("|]").to(Token::RightSquareBar),
-        just(">=").to(Token::GreaterThanOrEqualTo),
-        just("=>").to(Token::FatRightArrow),
-        just("==").to(Token::EqualTo),
-        just("<=").to(Token::LessThanOrEqualTo),
-        just("<->").to(Token::DoubleArrow),
-        just("<-").to(Token::LeftArrow),
         just("{|").to(Token::LeftCurlyBar),
         just("[|").to(Token::LeftSquareBar),
         just("()").to(Token::Unit),
-        just("!=").to(Token::NotEqualTo),
-        just("::").to(Token::Scope),
-        just("->").to(Token::RightArrow),
-    ))
-    .or(choice((
-        just('$').to(Token::Dollar),
-        just('|').to(Token::Or),
-        just('>').to(Token::GreaterThan),
-        just('=').to(Token::Equal),
-        just('<').to(Token::LessThan),
-        just('+').to(Token::Plus),
-        just('^').to(Token::Caret),
-        just('%').to(Token::Modulus),
-        just('&').to(Token::And),
-        just('/').to(Token::Divide),
-        just('*').to(Token::Multiply),
-        just('@').to(Token::At),
         just('}').to(Token::RightCurlyBracket),
         just('{').to(Token::LeftCurlyBracket),
         just(']').to(Token::RightSquareBracket),
         just('[').to(Token::LeftSquareBracket),
         just(')').to(Token::RightBracket),
         just('(').to(Token::LeftBracket),
-        just('.').to(Token::Dot),
-        just(':').to(Token::Colon),
         just(';').to(Token::Semicolon),
         just(',').to(Token::Comma),
-        just('-').to(Token::Minus),
         just('_').to(Token::Underscore),
-    )))
+    ))
     .boxed();
 
+    // Operators. Like the Sail compiler this takes the longest run of operator
+    // characters (plus an optional `_foo` suffix, as in `<_u`) and then checks
+    // if it is one of the built-in ones. Anything else is a user-defined
+    // operator that can be declared with `infix`. A run can't contain the start
+    // of a comment.
+    let op = one_of("!%&*+-./:<=>@^|")
+        .and_is(just("//").or(just("/*")).not())
+        .repeated()
+        .at_least(1)
+        .then(
+            just('_')
+                .then(
+                    any()
+                        .filter(|&c: &char| c.is_ascii_alphanumeric() || c == '_' || c == '\'')
+                        .repeated()
+                        .at_least(1),
+                )
+                .or_not(),
+        )
+        .to_slice()
+        .map(|s: &str| match s {
+            "|" => Token::Or,
+            ">=" => Token::GreaterThanOrEqualTo,
+            "=>" => Token::FatRightArrow,
+            "==" => Token::EqualTo,
+            "<=" => Token::LessThanOrEqualTo,
+            "<->" => Token::DoubleArrow,
+            "<-" => Token::LeftArrow,
+            "!=" => Token::NotEqualTo,
+            "::" => Token::Scope,
+            "->" => Token::RightArrow,
+            ".." => Token::DotDot,
+            ">" => Token::GreaterThan,
+            "=" => Token::Equal,
+            "<" => Token::LessThan,
+            "+" => Token::Plus,
+            "^" => Token::Caret,
+            "%" => Token::Modulus,
+            "&" => Token::And,
+            "/" => Token::Divide,
+            "*" => Token::Multiply,
+            "@" => Token::At,
+            "." => Token::Dot,
+            ":" => Token::Colon,
+            "-" => Token::Minus,
+            _ => Token::Op(s.to_owned()),
+        })
+        .boxed();
+
+    // Directives like `$include <foo.sail>` run to the end of the line. `$` on
+    // its own is used for attributes, e.g. `$[sv_module]`.
+    let directive = just('$')
+        .ignore_then(ident().then(none_of("\r\n").repeated()).to_slice())
+        .map(|s: &str| Token::Directive(s.trim_end().to_owned()))
+        .or(just('$').to(Token::Dollar))
+        .boxed();
+
     // TyVar
     let tyvar = just('\'')
         .ignore_then(ident())
@@ -466,96 +611,17 @@ pub fn lexer<'src>(
     // A parser for identifiers and keywords.
     // '~' is a specially allowed identifier.
     let ident = ident()
-        .map(|ident: &str| match ident {
-            "and" => Token::KwAnd,
-            "as" => Token::KwAs,
-            "assert" => Token::KwAssert,
-            "backwards" => Token::KwBackwards,
-            "barr" => Token::KwBarr,
-            "bitfield" => Token::KwBitfield,
-            "bitone" => Token::KwBitone,
-            "bitzero" => Token::KwBitzero,
-            "Bool" => Token::KwBool,
-            "by" => Token::KwBy,
-            "cast" => Token::KwCast,
-            "catch" => Token::KwCatch,
-            "clause" => Token::KwClause,
-            "configuration" => Token::KwConfiguration,
-            "constant" => Token::KwConstant,
-            "constraint" => Token::KwConstraint,
-            "dec" => Token::KwDec,
-            "default" => Token::KwDefault,
-            "depend" => Token::KwDepend,
-            "do" => Token::KwDo,
-            "eamem" => Token::KwEamem,
-            "effect" => Token::KwEffect,
-            "else" => Token::KwElse,
-            "end" => Token::KwEnd,
-            "enum" => Token::KwEnum,
-            "escape" => Token::KwEscape,
-            "exit" => Token::KwExit,
-            "exmem" => Token::KwExmem,
-            "false" => Token::KwFalse,
-            "forall" => Token::KwForall,
-            "foreach" => Token::KwForeach,
-            "forwards" => Token::KwForwards,
-            "function" => Token::KwFunction,
-            "if" => Token::KwIf,
-            "impl" => Token::KwImpl,
-            "in" => Token::KwIn,
-            "inc" => Token::KwInc,
-            "infix" => Token::KwInfix,
-            "infixl" => Token::KwInfixl,
-            "infixr" => Token::KwInfixr,
-            "instantiation" => Token::KwInstantiation,
-            "Int" => Token::KwInt,
-            "let" => Token::KwLet,
-            "mapping" => Token::KwMapping,
-            "match" => Token::KwMatch,
-            "monadic" => Token::KwMonadic,
-            "mutual" => Token::KwMutual,
-            "mwv" => Token::KwMwv,
-            "newtype" => Token::KwNewtype,
-            "nondet" => Token::KwNondet,
-            "Order" => Token::KwOrder,
-            "outcome" => Token::KwOutcome,
-            "overload" => Token::KwOverload,
-            "pure" => Token::KwPure,
-            "ref" => Token::KwRef,
-            "register" => Token::KwRegister,
-            "repeat" => Token::KwRepeat,
-            "return" => Token::KwReturn,
-            "rmem" => Token::KwRmem,
-            "rreg" => Token::KwRreg,
-            "scattered" => Token::KwScattered,
-            "sizeof" => Token::KwSizeof,
-            "struct" => Token::KwStruct,
-            "termination_measure" => Token::KwTerminationMeasure,
-            "then" => Token::KwThen,
-            "throw" => Token::KwThrow,
-            "true" => Token::KwTrue,
-            "try" => Token::KwTry,
-            "type" => Token::KwType,
-            "Type" => Token::KwTypeUpper,
-            "undef" => Token::KwUndef,
-            "undefined" => Token::KwUndefined,
-            "union" => Token::KwUnion,
-            "unspec" => Token::KwUnspec,
-            "until" => Token::KwUntil,
-            "val" => Token::KwVal,
-            "var" => Token::KwVar,
-            "while" => Token::KwWhile,
-            "with" => Token::KwWith,
-            "wmem" => Token::KwWmem,
-            "wreg" => Token::KwWreg,
-            _ => Token::Id(ident.to_string()),
-        })
+        .map(|ident: &str| keyword(ident).unwrap_or_else(|| Token::Id(ident.to_string())))
         .boxed();
 
-    // A single token can be one of the above
-    let token = choice((tyvar, hex, bin, real, num, string, ident, op))
-        .recover_with(skip_then_retry_until(any().ignored(), end()))
-        .boxed();
+    // A single token can be one of the above. Characters that don't start a
+    // token are reported and skipped, so there is always some output.
+    let token = choice((
+        tyvar, hex, bin, real, num, string, ident, ctrl, op, directive,
+    ))
+    .map(Some)
+    .recover_with(via_parser(any().to(None)))
+    .boxed();
 
     let line_comment = just("//").then(none_of('\n').repeated()).padded().ignored();
     let block_comment = just("/*")
@@ -567,12 +633,9 @@ pub fn lexer<'src>(
     let comment = line_comment.or(block_comment);
 
     token
-        .map_with(|tok, e| (tok, e.span()))
+        .map_with(|tok, e| tok.map(|tok| (tok, e.span())))
         .padded_by(comment.repeated())
         .padded()
-        .repeated()
-        .collect()
-        .then_ignore(end())
 }
 
 #[cfg(test)]
@@ -627,4 +690,15 @@ val __TraceMemoryRead  : forall 'n 'm. (atom('n), bits('m), bits(8 * 'n)) -> uni
         let result = lexer().parse(code);
         dbg!(result);
     }
+
+    #[test]
+    fn test_recovery() {
+        // Unknown characters are skipped, even at the end of the file.
+        let code = "val foo : int `\nval bar : int '";
+        let (tokens, errors) = lexer().parse(code).into_output_errors();
+        let tokens = tokens.unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(tokens.len(), 8);
+        assert_eq!(tokens[4], (Token::KwVal, Span::new(16, 19)));
+    }
 }
@@ -1,3 +1,14 @@
+mod ast;
 mod lexer;
 mod parser;
+mod precedence;
+mod relex;
+pub use ast::*;
 pub use lexer::*;
+pub use parser::*;
+pub use precedence::*;
+pub use relex::*;
+
+/// Increment this when the tokens or the AST change, so that anything saved
+/// with the old ones (e.g. the server's index cache) is thrown away.
+pub const VERSION: u32 = 2;
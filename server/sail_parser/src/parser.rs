@@ -1 +1,1680 @@
+//! Sail parser using Chumsky. This parses the output of `lexer()` into an AST.
+use crate::ast::*;
+use crate::precedence::FixityTable;
+use crate::{Span, Token};
+use chumsky::{
+    input::{InputRef, SpannedInput},
+    prelude::*,
+};
 
+pub type ParserInput<'a> = SpannedInput<Token, Span, &'a [(Token, Span)]>;
+
+type Extra<'a> = extra::Err<Rich<'a, Token, Span>>;
+
+/// A located parse error.
+pub type ParseError = Rich<'static, Token, Span>;
+
+type BoxedParser<'a, O> = Boxed<'a, 'a, ParserInput<'a>, O, Extra<'a>>;
+
+/// Match a single token with `f`. This is the same as chumsky's `select!`
+/// except that it reports errors at the position of the token rather than
+/// after it, so that they are merged with other errors and labelled properly.
+fn token_where<'a, O>(
+    f: impl Fn(Token) -> Option<O> + Clone,
+) -> impl Parser<'a, ParserInput<'a>, O, Extra<'a>> + Clone {
+    custom(move |inp| {
+        let before = inp.offset();
+        let token = inp.next();
+        match token.clone().and_then(&f) {
+            Some(out) => Ok(out),
+            None => Err(
+                <Rich<_, _> as chumsky::error::Error<ParserInput>>::expected_found(
+                    None,
+                    token.map(Into::into),
+                    inp.span_since(before),
+                ),
+            ),
+        }
+    })
+}
+
+macro_rules! select_token {
+    ($($p:pat $(if $guard:expr)? => $out:expr),+ $(,)?) => {
+        token_where(move |token| match token {
+            $($p $(if $guard)? => Some($out),)+
+            #[allow(unreachable_patterns)]
+            _ => None,
+        })
+    };
+}
+
+/// Convert the output of `lexer()` into input for `parser()`.
+pub fn parser_input(tokens: &[(Token, Span)]) -> ParserInput<'_> {
+    let end = tokens.last().map_or(0, |(_, span)| span.end);
+    tokens.spanned(Span::new(end, end))
+}
+
+/// Is this token a binary operator in expressions and patterns?
+fn is_infix_op(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Op(_)
+            | Token::Plus
+            | Token::Minus
+            | Token::Multiply
+            | Token::Divide
+            | Token::Modulus
+            | Token::Caret
+            | Token::At
+            | Token::Scope
+            | Token::LessThan
+            | Token::GreaterThan
+            | Token::LessThanOrEqualTo
+            | Token::GreaterThanOrEqualTo
+            | Token::EqualTo
+            | Token::NotEqualTo
+            | Token::And
+            | Token::Or
+    )
+}
+
+/// Is this token a binary operator in types? These are the numeric and
+/// constraint operators.
+fn is_type_op(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Plus
+            | Token::Minus
+            | Token::Multiply
+            | Token::Caret
+            | Token::LessThan
+            | Token::GreaterThan
+            | Token::LessThanOrEqualTo
+            | Token::GreaterThanOrEqualTo
+            | Token::EqualTo
+            | Token::NotEqualTo
+            | Token::And
+            | Token::Or
+            | Token::KwIn
+    )
+}
+
+/// Can this token, followed by `next`, start a top-level definition? This is
+/// used to resynchronise after errors. `let` is not included because it can
+/// also appear inside definitions. `struct`, `register` and `$` only count
+/// when they are followed by what a definition would have, as `struct { ... }`
+/// is an expression.
+fn is_def_start(token: &Token, next: Option<&Token>) -> bool {
+    match token {
+        Token::KwStruct | Token::KwRegister => {
+            matches!(next, Some(Token::Id(_) | Token::KwConfiguration))
+        }
+        Token::Dollar => next == Some(&Token::LeftSquareBracket),
+        _ => matches!(
+            token,
+            Token::KwVal
+                | Token::KwFunction
+                | Token::KwMapping
+                | Token::KwType
+                | Token::KwUnion
+                | Token::KwEnum
+                | Token::KwBitfield
+                | Token::KwOverload
+                | Token::KwScattered
+                | Token::KwEnd
+                | Token::KwDefault
+                | Token::KwInfix
+                | Token::KwInfixl
+                | Token::KwInfixr
+                | Token::KwTerminationMeasure
+                | Token::Directive(_)
+        ),
+    }
+}
+
+/// Does the input start a top-level definition? This doesn't consume anything.
+fn at_def_start<'a>(inp: &mut InputRef<'a, '_, ParserInput<'a>, Extra<'a>>) -> bool {
+    let Some(token) = inp.peek() else {
+        return false;
+    };
+    let marker = inp.save();
+    inp.skip();
+    let next = inp.peek();
+    inp.rewind(marker);
+    is_def_start(&token, next.as_ref())
+}
+
+/// If this is an opening bracket, return the matching closing bracket.
+fn closing_bracket(token: &Token) -> Option<Token> {
+    match token {
+        Token::LeftBracket => Some(Token::RightBracket),
+        Token::LeftSquareBracket => Some(Token::RightSquareBracket),
+        Token::LeftCurlyBracket => Some(Token::RightCurlyBracket),
+        Token::LeftSquareBar => Some(Token::RightSquareBar),
+        Token::LeftCurlyBar => Some(Token::RightCurlyBar),
+        _ => None,
+    }
+}
+
+fn is_closing_bracket(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::RightBracket
+            | Token::RightSquareBracket
+            | Token::RightCurlyBracket
+            | Token::RightSquareBar
+            | Token::RightCurlyBar
+    )
+}
+
+/// Skip at least one token for error recovery. This stops before `stop` or a
+/// closing bracket that wasn't opened in the skipped tokens, so a broken
+/// statement doesn't eat the end of the block it is in. It never skips the
+/// start of a top-level definition.
+fn skip_until<'a>(stop: Token) -> impl Parser<'a, ParserInput<'a>, (), Extra<'a>> + Clone {
+    custom(move |inp| {
+        let before = inp.offset();
+        // Closing brackets that we expect to see.
+        let mut brackets: Vec<Token> = Vec::new();
+        let mut skipped = 0;
+        while let Some(token) = inp.peek() {
+            if at_def_start(inp) || (brackets.is_empty() && token == stop) {
+                break;
+            }
+            if let Some(close) = closing_bracket(&token) {
+                brackets.push(close);
+            } else if is_closing_bracket(&token) {
+                match brackets.iter().rposition(|close| *close == token) {
+                    Some(i) => brackets.truncate(i),
+                    None => break,
+                }
+            }
+            inp.skip();
+            skipped += 1;
+        }
+        if skipped == 0 {
+            return Err(Rich::custom(inp.span_since(before), "nothing to skip"));
+        }
+        Ok(())
+    })
+}
+
+fn spanned<'a, O>(
+    parser: impl Parser<'a, ParserInput<'a>, O, Extra<'a>> + Clone,
+) -> impl Parser<'a, ParserInput<'a>, Spanned<O>, Extra<'a>> + Clone {
+    parser.map_with(|o, e| (o, e.span()))
+}
+
+fn ident<'a>() -> impl Parser<'a, ParserInput<'a>, Ident, Extra<'a>> + Clone {
+    spanned(select_token! { Token::Id(name) => name }).labelled("identifier")
+}
+
+/// An infix operator, e.g. `+` or `<_u`.
+fn infix_op<'a>() -> impl Parser<'a, ParserInput<'a>, Ident, Extra<'a>> + Clone {
+    spanned(select_token! { token if is_infix_op(&token) => token.to_string() })
+        .labelled("operator")
+}
+
+/// A name that can be given to a function, which is either an identifier or
+/// `operator <op>`.
+fn def_name<'a>() -> impl Parser<'a, ParserInput<'a>, Ident, Extra<'a>> + Clone {
+    just(Token::Id("operator".to_string()))
+        .ignore_then(infix_op())
+        .or(ident())
+}
+
+fn tyvar<'a>() -> impl Parser<'a, ParserInput<'a>, Ident, Extra<'a>> + Clone {
+    spanned(select_token! { Token::TyVal(name) => name }).labelled("type variable")
+}
+
+fn lit<'a>() -> impl Parser<'a, ParserInput<'a>, Lit, Extra<'a>> + Clone {
+    select_token! {
+        Token::Num(s) => Lit::Num(s),
+        Token::Hex(s) => Lit::Hex(s),
+        Token::Bin(s) => Lit::Bin(s),
+        Token::Real(s) => Lit::Real(s),
+        Token::String(s) => Lit::String(s),
+        Token::KwTrue => Lit::Bool(true),
+        Token::KwFalse => Lit::Bool(false),
+        Token::Unit => Lit::Unit,
+        Token::KwBitzero => Lit::BitZero,
+        Token::KwBitone => Lit::BitOne,
+        Token::KwUndefined => Lit::Undefined,
+    }
+    .labelled("literal")
+}
+
+fn kind<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<Kind>, Extra<'a>> + Clone {
+    spanned(select_token! {
+        Token::KwInt => Kind::Int,
+        Token::KwBool => Kind::Bool,
+        Token::KwTypeUpper => Kind::Type,
+        Token::KwOrder => Kind::Order,
+    })
+    .labelled("kind")
+}
+
+fn order<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<Order>, Extra<'a>> + Clone {
+    spanned(select_token! {
+        Token::KwInc => Order::Inc,
+        Token::KwDec => Order::Dec,
+    })
+}
+
+/// Quantified type variables, e.g. `'n ('m : Int) ('a 'b : Type)`.
+fn kinded_ids<'a>() -> impl Parser<'a, ParserInput<'a>, Vec<KindedId>, Extra<'a>> + Clone {
+    let bare = tyvar().map(|name| vec![KindedId { name, kind: None }]);
+    let with_kind = tyvar()
+        .repeated()
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .then_ignore(just(Token::Colon))
+        .then(kind())
+        .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+        .map(|(names, kind)| {
+            names
+                .into_iter()
+                .map(|name| KindedId {
+                    name,
+                    kind: Some(kind),
+                })
+                .collect::<Vec<_>>()
+        });
+    bare.or(with_kind)
+        .repeated()
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .map(|ids| ids.into_iter().flatten().collect())
+}
+
+/// Type parameters of a type definition, e.g. `('n : Int, 'a : Type)`.
+fn type_params<'a>() -> impl Parser<'a, ParserInput<'a>, Vec<KindedId>, Extra<'a>> + Clone {
+    tyvar()
+        .then(just(Token::Colon).ignore_then(kind()).or_not())
+        .map(|(name, kind)| KindedId { name, kind })
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+        .or_not()
+        .map(Option::unwrap_or_default)
+}
+
+/// Obsolete effect annotations, e.g. `effect {rreg, wreg}`. These are
+/// ignored.
+fn effects<'a>() -> impl Parser<'a, ParserInput<'a>, (), Extra<'a>> + Clone {
+    just(Token::KwEffect)
+        .ignore_then(
+            any()
+                .and_is(just(Token::RightCurlyBracket).not())
+                .repeated()
+                .delimited_by(
+                    just(Token::LeftCurlyBracket),
+                    just(Token::RightCurlyBracket),
+                )
+                .or(any().ignored()),
+        )
+        .ignored()
+}
+
+/// Parsers for atomic types (e.g. `bits(5)`) and types (e.g. `'n + 1`).
+/// Types don't include function types; they only appear in type schemes.
+fn typ<'a>(
+    fixities: &'a FixityTable,
+) -> (BoxedParser<'a, Spanned<Typ>>, BoxedParser<'a, Spanned<Typ>>) {
+    let mut typ = Recursive::declare();
+
+    let typ_list = typ
+        .clone()
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>();
+
+    let name = ident().or(spanned(select_token! {
+        Token::KwInt => "Int".to_string(),
+        Token::KwBool => "Bool".to_string(),
+        Token::KwTypeUpper => "Type".to_string(),
+        Token::KwRegister => "register".to_string(),
+    }));
+
+    let app_or_id = name
+        .then(
+            typ_list
+                .clone()
+                .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+                .or_not(),
+        )
+        .map(|(name, args)| match args {
+            Some(args) => Typ::App(name, args),
+            None if name.0 == "_" => Typ::Wild,
+            None => Typ::Id(name.0),
+        });
+
+    let parens = typ_list
+        .clone()
+        .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+        .map_with(|mut typs, e| {
+            if typs.len() == 1 {
+                typs.pop().unwrap()
+            } else {
+                (Typ::Tuple(typs), e.span())
+            }
+        });
+
+    // {'n, 'n > 0. int('n)}
+    let exist = kinded_ids()
+        .then(just(Token::Comma).ignore_then(typ.clone()).or_not())
+        .then_ignore(just(Token::Dot))
+        .then(typ.clone())
+        .map(|((vars, constraint), typ)| Typ::Exist {
+            vars,
+            constraint: constraint.map(Box::new),
+            typ: Box::new(typ),
+        });
+
+    // { a : int, b : bool }
+    let record = ident()
+        .then_ignore(just(Token::Colon))
+        .then(typ.clone())
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .map(Typ::Record);
+
+    // {32, 64}
+    let set = typ_list.clone().map(Typ::Set);
+
+    let braces = choice((exist, record, set)).delimited_by(
+        just(Token::LeftCurlyBracket),
+        just(Token::RightCurlyBracket),
+    );
+
+    let atom = recursive(|atom| {
+        choice((
+            spanned(choice((
+                app_or_id,
+                tyvar().map(|name| Typ::TyVar(name.0)),
+                lit().map(Typ::Lit),
+                order().map(|order| Typ::Order(order.0)),
+                braces,
+                just(Token::Minus)
+                    .ignore_then(atom)
+                    .map(|typ| Typ::Neg(Box::new(typ))),
+            ))),
+            parens,
+        ))
+    })
+    .labelled("type")
+    .boxed();
+
+    let op = spanned(select_token! { token if is_type_op(&token) => token.to_string() });
+
+    typ.define(
+        atom.clone()
+            .then(op.then(atom.clone()).repeated().collect::<Vec<_>>())
+            .map(|(first, rest)| fixities.resolve(first, rest, Typ::Infix)),
+    );
+
+    (atom, typ.boxed())
+}
+
+/// A type scheme, e.g. `forall 'n, 'n > 0. bits('n) -> unit`.
+fn typschm<'a>(
+    typ: BoxedParser<'a, Spanned<Typ>>,
+) -> impl Parser<'a, ParserInput<'a>, Spanned<TypeScheme>, Extra<'a>> + Clone {
+    let quantifiers = just(Token::KwForall)
+        .ignore_then(kinded_ids())
+        .then(just(Token::Comma).ignore_then(typ.clone()).or_not())
+        .then_ignore(just(Token::Dot))
+        .or_not()
+        .map(Option::unwrap_or_default);
+
+    let function = typ
+        .clone()
+        .then(
+            choice((
+                just(Token::RightArrow).to(true),
+                just(Token::DoubleArrow).to(false),
+            ))
+            .then(typ)
+            .then_ignore(effects().or_not())
+            .or_not(),
+        )
+        .map_with(|(arg, ret), e| match ret {
+            Some((true, ret)) => (Typ::Function(Box::new(arg), Box::new(ret)), e.span()),
+            Some((false, ret)) => (Typ::Bidirectional(Box::new(arg), Box::new(ret)), e.span()),
+            None => arg,
+        });
+
+    spanned(
+        quantifiers
+            .then(function)
+            .map(|((quantifiers, constraint), typ)| TypeScheme {
+                quantifiers,
+                constraint,
+                typ,
+            }),
+    )
+}
+
+/// Patterns, e.g. `Some(x : int)` or `0b01 @ rs1`.
+fn pat<'a>(
+    fixities: &'a FixityTable,
+    atomic_typ: BoxedParser<'a, Spanned<Typ>>,
+) -> BoxedParser<'a, Spanned<Pat>> {
+    recursive(|pat| {
+        let pat_list = pat
+            .clone()
+            .separated_by(just(Token::Comma))
+            .allow_trailing()
+            .collect::<Vec<_>>();
+
+        // Foo(x, y), Foo(), x[7 .. 0], x
+        let app_or_id = ident()
+            .then(
+                choice((
+                    pat_list
+                        .clone()
+                        .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+                        .map(|args| (Some(args), None)),
+                    just(Token::Unit).map(|_| (Some(Vec::new()), None)),
+                    atomic_typ
+                        .clone()
+                        .then(just(Token::DotDot).ignore_then(atomic_typ.clone()).or_not())
+                        .delimited_by(
+                            just(Token::LeftSquareBracket),
+                            just(Token::RightSquareBracket),
+                        )
+                        .map(|range| (None, Some(range))),
+                ))
+                .or_not(),
+            )
+            .map(|(name, suffix)| match suffix {
+                Some((Some(args), _)) => Pat::App(name, args),
+                Some((None, Some((high, low)))) => {
+                    Pat::Subrange(name, Box::new(high), low.map(Box::new))
+                }
+                _ if name.0 == "_" => Pat::Wild,
+                _ => Pat::Id(name.0),
+            });
+
+        let parens = pat_list
+            .clone()
+            .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+            .map_with(|mut pats, e| {
+                if pats.len() == 1 {
+                    pats.pop().unwrap()
+                } else {
+                    (Pat::Tuple(pats), e.span())
+                }
+            });
+
+        let field_pats = ident()
+            .then_ignore(just(Token::Equal))
+            .then(pat.clone())
+            .separated_by(just(Token::Comma))
+            .allow_trailing()
+            .collect::<Vec<_>>();
+
+        let atom = choice((
+            spanned(choice((
+                app_or_id,
+                just(Token::Underscore).to(Pat::Wild),
+                lit().map(Pat::Lit),
+                tyvar().map(|name| Pat::TyVar(name.0)),
+                pat_list
+                    .clone()
+                    .delimited_by(
+                        just(Token::LeftSquareBracket),
+                        just(Token::RightSquareBracket),
+                    )
+                    .map(Pat::Vector),
+                pat_list
+                    .clone()
+                    .delimited_by(just(Token::LeftSquareBar), just(Token::RightSquareBar))
+                    .map(Pat::List),
+                just(Token::KwStruct)
+                    .ignore_then(field_pats.delimited_by(
+                        just(Token::LeftCurlyBracket),
+                        just(Token::RightCurlyBracket),
+                    ))
+                    .map(Pat::Struct),
+            ))),
+            parens,
+        ))
+        .labelled("pattern");
+
+        // Type annotations bind tighter than operators, e.g. in
+        // `imm_19 : bits(1) @ imm_18_13 : bits(6)`.
+        let atom = atom
+            .then(just(Token::Colon).ignore_then(atomic_typ).or_not())
+            .map_with(|(pat, typ), e| match typ {
+                Some(typ) => (Pat::Typed(Box::new(pat), Box::new(typ)), e.span()),
+                None => pat,
+            })
+            .boxed();
+
+        let chain = atom
+            .clone()
+            .then(infix_op().then(atom).repeated().collect::<Vec<_>>())
+            .map(|(first, rest)| fixities.resolve(first, rest, Pat::Infix));
+
+        chain
+            .then(just(Token::KwAs).ignore_then(ident()).or_not())
+            .map_with(|(pat, name), e| match name {
+                Some(name) => (Pat::As(Box::new(pat), name), e.span()),
+                None => pat,
+            })
+    })
+    .boxed()
+}
+
+/// Expressions.
+fn exp<'a>(
+    fixities: &'a FixityTable,
+    atomic_typ: BoxedParser<'a, Spanned<Typ>>,
+    typ: BoxedParser<'a, Spanned<Typ>>,
+    pat: BoxedParser<'a, Spanned<Pat>>,
+) -> BoxedParser<'a, Spanned<Exp>> {
+    recursive(|exp| {
+        let exp_list = exp
+            .clone()
+            .separated_by(just(Token::Comma))
+            .allow_trailing()
+            .collect::<Vec<_>>();
+
+        let field_exps = ident()
+            .then_ignore(just(Token::Equal))
+            .then(exp.clone())
+            .separated_by(just(Token::Comma))
+            .allow_trailing()
+            .collect::<Vec<_>>();
+
+        let args = exp_list
+            .clone()
+            .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+            .or(just(Token::Unit).to(Vec::new()));
+
+        let call_or_id = ident()
+            .then(args.clone().or_not())
+            .map(|(name, args)| match args {
+                Some(args) => Exp::Call(name, args),
+                None => Exp::Id(name.0),
+            });
+
+        let parens = exp_list
+            .clone()
+            .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+            .map_with(|mut exps, e| {
+                if exps.len() == 1 {
+                    exps.pop().unwrap()
+                } else {
+                    (Exp::Tuple(exps), e.span())
+                }
+            });
+
+        let paren_typ = typ
+            .clone()
+            .delimited_by(just(Token::LeftBracket), just(Token::RightBracket));
+
+        // `[a, b]` or `[x with 7 .. 4 = a, 3 = b]`. These are parsed together
+        // to avoid backtracking.
+        enum SquareContents {
+            Update(Vec<VectorUpdate>),
+            Elements(Vec<Spanned<Exp>>),
+        }
+
+        let vector_updates = exp
+            .clone()
+            .then(just(Token::DotDot).ignore_then(exp.clone()).or_not())
+            .then_ignore(just(Token::Equal))
+            .then(exp.clone())
+            .map(|((high, low), value)| VectorUpdate { high, low, value })
+            .separated_by(just(Token::Comma))
+            .allow_trailing()
+            .collect::<Vec<_>>();
+
+        let vector = exp
+            .clone()
+            .then(choice((
+                just(Token::KwWith)
+                    .ignore_then(vector_updates)
+                    .map(SquareContents::Update),
+                just(Token::Comma)
+                    .ignore_then(exp_list.clone())
+                    .or_not()
+                    .map(|rest| SquareContents::Elements(rest.unwrap_or_default())),
+            )))
+            .or_not()
+            .delimited_by(
+                just(Token::LeftSquareBracket),
+                just(Token::RightSquareBracket),
+            )
+            .map(|contents| match contents {
+                None => Exp::Vector(Vec::new()),
+                Some((vector, SquareContents::Update(updates))) => {
+                    Exp::VectorUpdate(Box::new(vector), updates)
+                }
+                Some((first, SquareContents::Elements(rest))) => {
+                    Exp::Vector(std::iter::once(first).chain(rest).collect())
+                }
+            });
+
+        // `{ a; b }` or `{ x with a = 1 }`. Again these are parsed together to
+        // avoid backtracking, which is exponential for nested blocks.
+        enum CurlyContents {
+            Update(Vec<(Ident, Spanned<Exp>)>),
+            Statements(Vec<Spanned<Exp>>),
+        }
+
+        // A statement must be followed by `;` or `}` (or `with` for struct
+        // updates). Otherwise it is skipped up to the next `;`, so the rest of
+        // the block can still be parsed.
+        let statement = exp
+            .clone()
+            .then_ignore(
+                one_of([Token::Semicolon, Token::RightCurlyBracket, Token::KwWith]).rewind(),
+            )
+            .recover_with(via_parser(
+                skip_until(Token::Semicolon).map_with(|_, e| (Exp::Error, e.span())),
+            ))
+            .boxed();
+
+        let block = statement
+            .clone()
+            .then(choice((
+                just(Token::KwWith)
+                    .ignore_then(field_exps.clone())
+                    .map(CurlyContents::Update),
+                just(Token::Semicolon)
+                    .ignore_then(
+                        statement
+                            .separated_by(just(Token::Semicolon))
+                            .allow_trailing()
+                            .collect::<Vec<_>>(),
+                    )
+                    .or_not()
+                    .map(|rest| CurlyContents::Statements(rest.unwrap_or_default())),
+            )))
+            .or_not()
+            .delimited_by(
+                just(Token::LeftCurlyBracket),
+                just(Token::RightCurlyBracket),
+            )
+            .map(|contents| match contents {
+                None => Exp::Block(Vec::new()),
+                Some((base, CurlyContents::Update(fields))) => {
+                    Exp::StructUpdate(Box::new(base), fields)
+                }
+                Some((first, CurlyContents::Statements(rest))) => {
+                    Exp::Block(std::iter::once(first).chain(rest).collect())
+                }
+            });
+
+        let atom = choice((
+            spanned(choice((
+                call_or_id,
+                lit().map(Exp::Lit),
+                tyvar().map(|name| Exp::TyVar(name.0)),
+                just(Token::KwRef).ignore_then(ident()).map(Exp::Ref),
+                just(Token::KwSizeof)
+                    .ignore_then(paren_typ.clone())
+                    .map(Exp::Sizeof),
+                just(Token::KwConstraint)
+                    .ignore_then(paren_typ)
+                    .map(Exp::Constraint),
+                just(Token::KwAssert)
+                    .ignore_then(
+                        exp.clone()
+                            .then(just(Token::Comma).ignore_then(exp.clone()).or_not())
+                            .delimited_by(just(Token::LeftBracket), just(Token::RightBracket)),
+                    )
+                    .map(|(cond, msg)| Exp::Assert(Box::new(cond), msg.map(Box::new))),
+                just(Token::KwExit)
+                    .ignore_then(args.clone().or_not())
+                    .map(|args| Exp::Exit(args.and_then(|mut args| args.pop()).map(Box::new))),
+                vector,
+                exp_list
+                    .clone()
+                    .delimited_by(just(Token::LeftSquareBar), just(Token::RightSquareBar))
+                    .map(Exp::List),
+                just(Token::KwStruct)
+                    .ignore_then(ident().or_not())
+                    .ignore_then(field_exps.delimited_by(
+                        just(Token::LeftCurlyBracket),
+                        just(Token::RightCurlyBracket),
+                    ))
+                    .map(Exp::Struct),
+                block,
+            ))),
+            parens,
+        ))
+        .labelled("expression")
+        .boxed();
+
+        enum Postfix {
+            Field(Ident),
+            Method(Ident, Vec<Spanned<Exp>>),
+            Index(Spanned<Exp>, Option<Spanned<Exp>>),
+            Typed(Spanned<Typ>),
+        }
+
+        let postfix = choice((
+            just(Token::Dot)
+                .ignore_then(ident())
+                .then(args.or_not())
+                .map(|(name, args)| match args {
+                    Some(args) => Postfix::Method(name, args),
+                    None => Postfix::Field(name),
+                }),
+            exp.clone()
+                .then(just(Token::DotDot).ignore_then(exp.clone()).or_not())
+                .delimited_by(
+                    just(Token::LeftSquareBracket),
+                    just(Token::RightSquareBracket),
+                )
+                .map(|(high, low)| Postfix::Index(high, low)),
+            just(Token::Colon)
+                .ignore_then(atomic_typ)
+                .map(Postfix::Typed),
+        ));
+
+        let atom = atom.foldl_with(postfix.repeated(), |exp, postfix, e| {
+            let exp = Box::new(exp);
+            let node = match postfix {
+                Postfix::Field(field) => Exp::Field(exp, field),
+                Postfix::Method(name, args) => Exp::Method(exp, name, args),
+                Postfix::Index(index, None) => Exp::Index(exp, Box::new(index)),
+                Postfix::Index(high, Some(low)) => Exp::Slice(exp, Box::new(high), Box::new(low)),
+                Postfix::Typed(typ) => Exp::Typed(exp, typ),
+            };
+            (node, e.span())
+        });
+
+        let operand = just(Token::Minus)
+            .or_not()
+            .then(atom.clone())
+            .map_with(|(neg, exp), e| match neg {
+                Some(_) => (Exp::Neg(Box::new(exp)), e.span()),
+                None => exp,
+            })
+            .boxed();
+
+        let chain = operand
+            .clone()
+            .then(infix_op().then(operand).repeated().collect::<Vec<_>>())
+            .map(|(first, rest)| fixities.resolve(first, rest, Exp::Infix));
+
+        // Chains can be assigned to, e.g. `x[5] = y`.
+        let assign = chain
+            .then(just(Token::Equal).ignore_then(exp.clone()).or_not())
+            .map_with(|(lhs, rhs), e| match rhs {
+                Some(rhs) => (Exp::Assign(Box::new(lhs), Box::new(rhs)), e.span()),
+                None => lhs,
+            });
+
+        let let_binding = pat
+            .clone()
+            .then_ignore(just(Token::Equal))
+            .then(exp.clone())
+            .map(|(pat, exp)| Box::new(LetBinding { pat, exp }));
+
+        // The body is optional because inside blocks `let x = 5;` scopes over
+        // the rest of the block.
+        let body = just(Token::KwIn)
+            .ignore_then(exp.clone())
+            .map(Box::new)
+            .or_not();
+
+        let let_ = just(Token::KwLet)
+            .ignore_then(let_binding.clone())
+            .then(body.clone())
+            .map(|(binding, body)| Exp::Let(binding, body));
+
+        let var = just(Token::KwVar)
+            .ignore_then(let_binding)
+            .then(body)
+            .map(|(binding, body)| Exp::Var(binding, body));
+
+        let if_ = just(Token::KwIf)
+            .ignore_then(exp.clone())
+            .then_ignore(just(Token::KwThen))
+            .then(exp.clone())
+            .then(just(Token::KwElse).ignore_then(exp.clone()).or_not())
+            .map(|((cond, then), else_)| {
+                Exp::If(Box::new(cond), Box::new(then), else_.map(Box::new))
+            });
+
+        let arms = spanned(
+            pat.clone()
+                .then(just(Token::KwIf).ignore_then(exp.clone()).or_not())
+                .then_ignore(just(Token::FatRightArrow))
+                .then(exp.clone())
+                .map(|((pat, guard), body)| MatchArm { pat, guard, body }),
+        )
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(
+            just(Token::LeftCurlyBracket),
+            just(Token::RightCurlyBracket),
+        );
+
+        let match_ = just(Token::KwMatch)
+            .ignore_then(exp.clone())
+            .then(arms.clone())
+            .map(|(exp, arms)| Exp::Match(Box::new(exp), arms));
+
+        let try_ = just(Token::KwTry)
+            .ignore_then(exp.clone())
+            .then_ignore(just(Token::KwCatch))
+            .then(arms)
+            .map(|(exp, arms)| Exp::Try(Box::new(exp), arms));
+
+        // foreach (i from 0 to 31 by 1 in inc) ...
+        let keyword = |word: &'static str| just(Token::Id(word.to_string()));
+        let foreach = just(Token::KwForeach)
+            .ignore_then(
+                ident()
+                    .then_ignore(keyword("from"))
+                    .then(exp.clone())
+                    .then_ignore(keyword("to").or(keyword("downto")))
+                    .then(exp.clone())
+                    .then(just(Token::KwBy).ignore_then(exp.clone()).or_not())
+                    .then(just(Token::KwIn).ignore_then(order()).or_not())
+                    .delimited_by(just(Token::LeftBracket), just(Token::RightBracket)),
+            )
+            .then(exp.clone())
+            .map(|(((((var, from), to), step), order), body)| Exp::Foreach {
+                var,
+                from: Box::new(from),
+                to: Box::new(to),
+                step: step.map(Box::new),
+                order,
+                body: Box::new(body),
+            });
+
+        let while_ = just(Token::KwWhile)
+            .ignore_then(exp.clone())
+            .then_ignore(just(Token::KwDo))
+            .then(exp.clone())
+            .map(|(cond, body)| Exp::While(Box::new(cond), Box::new(body)));
+
+        let repeat = just(Token::KwRepeat)
+            .ignore_then(exp.clone())
+            .then_ignore(just(Token::KwUntil))
+            .then(exp.clone())
+            .map(|(body, cond)| Exp::Repeat(Box::new(body), Box::new(cond)));
+
+        let return_ = just(Token::KwReturn)
+            .ignore_then(exp.clone())
+            .map(|exp| Exp::Return(Box::new(exp)));
+
+        let throw = just(Token::KwThrow)
+            .ignore_then(exp.clone())
+            .map(|exp| Exp::Throw(Box::new(exp)));
+
+        choice((
+            spanned(choice((
+                let_, var, if_, match_, try_, foreach, while_, repeat, return_, throw,
+            ))),
+            assign,
+        ))
+        .labelled("expression")
+        .boxed()
+    })
+    .boxed()
+}
+
+/// The parser for a whole file. `fixities` is used to group operators.
+pub fn parser<'a>(
+    fixities: &'a FixityTable,
+) -> impl Parser<'a, ParserInput<'a>, SourceFile, Extra<'a>> {
+    let (atomic_typ, typ) = typ(fixities);
+    let typschm = typschm(typ.clone()).boxed();
+    let pat = pat(fixities, atomic_typ.clone());
+    let exp = exp(fixities, atomic_typ, typ.clone(), pat.clone());
+
+    let keyword = |word: &'static str| just(Token::Id(word.to_string()));
+
+    // Val specs.
+    let externs = ident()
+        .or_not()
+        .then_ignore(just(Token::Colon).or_not())
+        .then(spanned(select_token! { Token::String(s) => s }))
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(
+            just(Token::LeftCurlyBracket),
+            just(Token::RightCurlyBracket),
+        )
+        .or(spanned(select_token! { Token::String(s) => s }).map(|name| vec![(None, name)]));
+
+    // Vals can be named with a string, e.g. `val "print" : string -> unit`.
+    let val_name = def_name().or(spanned(
+        select_token! { Token::String(s) => s.trim_matches('"').to_string() },
+    ));
+
+    let val = just(Token::KwVal)
+        .ignore_then(just(Token::KwCast).or_not())
+        .ignore_then(val_name)
+        .then(
+            just(Token::Equal)
+                .ignore_then(just(Token::KwPure).or(just(Token::KwMonadic)).or_not())
+                .ignore_then(externs)
+                .or_not(),
+        )
+        .then_ignore(just(Token::Colon))
+        .then(typschm.clone())
+        .map(|((name, externs), typschm)| {
+            Def::Val(ValSpec {
+                name,
+                externs: externs.unwrap_or_default(),
+                typschm,
+            })
+        });
+
+    // Functions.
+    let funcl = spanned(
+        def_name()
+            .then(pat.clone())
+            .then(just(Token::RightArrow).ignore_then(typ.clone()).or_not())
+            .then_ignore(just(Token::Equal))
+            .then(exp.clone())
+            .map(|(((name, params), return_type), body)| FunctionClause {
+                name,
+                params,
+                return_type,
+                body,
+            }),
+    );
+
+    let function_clause = just(Token::KwFunction)
+        .ignore_then(just(Token::KwClause))
+        .ignore_then(funcl.clone())
+        .map(|(clause, _)| Def::FunctionClause(clause));
+
+    // The `rec` keyword is optional, but `rec` could also be the function
+    // name, so try it with and without.
+    let function = just(Token::KwFunction)
+        .ignore_then(
+            keyword("rec")
+                .to(true)
+                .then(
+                    funcl
+                        .clone()
+                        .separated_by(just(Token::KwAnd))
+                        .at_least(1)
+                        .collect::<Vec<_>>(),
+                )
+                .or(funcl
+                    .separated_by(just(Token::KwAnd))
+                    .at_least(1)
+                    .collect::<Vec<_>>()
+                    .map(|clauses| (false, clauses))),
+        )
+        .map(|(is_rec, clauses)| Def::Function(FunctionDef { is_rec, clauses }));
+
+    // Mappings.
+    let mapping_pat = spanned(
+        pat.clone()
+            .then(just(Token::KwIf).ignore_then(exp.clone()).or_not())
+            .map(|(pat, guard)| MappingPat { pat, guard }),
+    );
+
+    let mapping_clause = spanned(choice((
+        just(Token::KwForwards)
+            .ignore_then(mapping_pat.clone())
+            .then_ignore(just(Token::FatRightArrow))
+            .then(exp.clone())
+            .map(|(pat, exp)| MappingClause::Forwards(pat, exp)),
+        just(Token::KwBackwards)
+            .ignore_then(mapping_pat.clone())
+            .then_ignore(just(Token::FatRightArrow))
+            .then(exp.clone())
+            .map(|(pat, exp)| MappingClause::Backwards(pat, exp)),
+        mapping_pat
+            .clone()
+            .then(
+                just(Token::DoubleArrow)
+                    .ignore_then(mapping_pat)
+                    .map(Ok)
+                    .or(just(Token::FatRightArrow).ignore_then(exp.clone()).map(Err)),
+            )
+            .map(|(left, right)| match right {
+                Ok(right) => MappingClause::Bidirectional(left, right),
+                Err(exp) => MappingClause::Forwards(left, exp),
+            }),
+    )));
+
+    let mapping_def_clause = just(Token::KwMapping)
+        .ignore_then(just(Token::KwClause))
+        .ignore_then(ident())
+        .then_ignore(just(Token::Equal))
+        .then(mapping_clause.clone())
+        .map(|(name, clause)| Def::MappingClause { name, clause });
+
+    let mapping = just(Token::KwMapping)
+        .ignore_then(ident())
+        .then(just(Token::Colon).ignore_then(typschm.clone()).or_not())
+        .then_ignore(just(Token::Equal))
+        .then(
+            mapping_clause
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::LeftCurlyBracket),
+                    just(Token::RightCurlyBracket),
+                ),
+        )
+        .map(|((name, typschm), clauses)| {
+            Def::Mapping(MappingDef {
+                name,
+                typschm,
+                clauses,
+            })
+        });
+
+    // Types.
+    let type_def = just(Token::KwType)
+        .ignore_then(ident())
+        .then(type_params())
+        .then(just(Token::Colon).ignore_then(kind()).or_not())
+        .then(just(Token::Equal).ignore_then(typ.clone()).or_not())
+        .map(|(((name, params), kind), typ)| {
+            Def::Type(TypeDef {
+                name,
+                params,
+                kind,
+                typ,
+            })
+        });
+
+    let fields = ident()
+        .then_ignore(just(Token::Colon))
+        .then(typ.clone())
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(
+            just(Token::LeftCurlyBracket),
+            just(Token::RightCurlyBracket),
+        );
+
+    let struct_def = just(Token::KwStruct)
+        .ignore_then(ident())
+        .then(type_params())
+        .then_ignore(just(Token::Equal))
+        .then(fields)
+        .map(|((name, params), fields)| {
+            Def::Struct(StructDef {
+                name,
+                params,
+                fields,
+            })
+        });
+
+    let variant = ident()
+        .then_ignore(just(Token::Colon))
+        .then(typ.clone())
+        .map(|(name, typ)| UnionVariant { name, typ });
+
+    let union_clause = just(Token::KwUnion)
+        .ignore_then(just(Token::KwClause))
+        .ignore_then(ident())
+        .then_ignore(just(Token::Equal))
+        .then(variant.clone())
+        .map(|(name, variant)| Def::UnionClause { name, variant });
+
+    let union = just(Token::KwUnion)
+        .ignore_then(ident())
+        .then(type_params())
+        .then_ignore(just(Token::Equal))
+        .then(
+            variant
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::LeftCurlyBracket),
+                    just(Token::RightCurlyBracket),
+                ),
+        )
+        .map(|((name, params), variants)| {
+            Def::Union(UnionDef {
+                name,
+                params,
+                variants,
+            })
+        });
+
+    let enum_clause = just(Token::KwEnum)
+        .ignore_then(just(Token::KwClause))
+        .ignore_then(ident())
+        .then_ignore(just(Token::Equal))
+        .then(ident())
+        .map(|(name, member)| Def::EnumClause { name, member });
+
+    let enum_functions = just(Token::KwWith)
+        .ignore_then(
+            ident()
+                .then_ignore(just(Token::RightArrow))
+                .then(typ.clone())
+                .separated_by(just(Token::Comma))
+                .at_least(1)
+                .collect::<Vec<_>>(),
+        )
+        .or_not()
+        .map(Option::unwrap_or_default);
+
+    let enum_members = ident()
+        .then(just(Token::FatRightArrow).ignore_then(exp.clone()).or_not())
+        .map(|(name, value)| EnumMember { name, value })
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(
+            just(Token::LeftCurlyBracket),
+            just(Token::RightCurlyBracket),
+        )
+        .or(ident()
+            .map(|name| EnumMember { name, value: None })
+            .separated_by(just(Token::Or))
+            .at_least(1)
+            .collect::<Vec<_>>());
+
+    let enum_ = just(Token::KwEnum)
+        .ignore_then(ident())
+        .then(enum_functions)
+        .then_ignore(just(Token::Equal))
+        .then(enum_members)
+        .map(|((name, functions), members)| {
+            Def::Enum(EnumDef {
+                name,
+                functions,
+                members,
+            })
+        });
+
+    let bitfield = just(Token::KwBitfield)
+        .ignore_then(ident())
+        .then_ignore(just(Token::Colon))
+        .then(typ.clone())
+        .then_ignore(just(Token::Equal))
+        .then(
+            ident()
+                .then_ignore(just(Token::Colon))
+                .then(typ.clone())
+                .then(just(Token::DotDot).ignore_then(typ.clone()).or_not())
+                .map(|((name, high), low)| BitfieldField { name, high, low })
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::LeftCurlyBracket),
+                    just(Token::RightCurlyBracket),
+                ),
+        )
+        .map(|((name, typ), fields)| Def::Bitfield(BitfieldDef { name, typ, fields }));
+
+    let register = just(Token::KwRegister)
+        .ignore_then(just(Token::KwConfiguration).or_not().map(|c| c.is_some()))
+        .then(ident())
+        .then_ignore(just(Token::Colon))
+        .then(typ.clone())
+        .then(just(Token::Equal).ignore_then(exp.clone()).or_not())
+        .map(|(((is_configuration, name), typ), init)| {
+            Def::Register(RegisterDef {
+                name,
+                is_configuration,
+                typ,
+                init,
+            })
+        });
+
+    let overload = just(Token::KwOverload)
+        .ignore_then(def_name())
+        .then_ignore(just(Token::Equal))
+        .then(
+            def_name()
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::LeftCurlyBracket),
+                    just(Token::RightCurlyBracket),
+                )
+                .or(def_name()
+                    .separated_by(just(Token::Or))
+                    .at_least(1)
+                    .collect::<Vec<_>>()),
+        )
+        .map(|(name, members)| Def::Overload(OverloadDef { name, members }));
+
+    let scattered = just(Token::KwScattered)
+        .ignore_then(choice((
+            just(Token::KwFunction)
+                .ignore_then(def_name())
+                .map(ScatteredDef::Function),
+            just(Token::KwMapping)
+                .ignore_then(ident())
+                .then(just(Token::Colon).ignore_then(typschm.clone()).or_not())
+                .map(|(name, typschm)| ScatteredDef::Mapping { name, typschm }),
+            just(Token::KwUnion)
+                .ignore_then(ident())
+                .then(type_params())
+                .map(|(name, params)| ScatteredDef::Union { name, params }),
+            just(Token::KwEnum)
+                .ignore_then(ident())
+                .map(ScatteredDef::Enum),
+        )))
+        .map(Def::Scattered);
+
+    let end_def = just(Token::KwEnd).ignore_then(def_name()).map(Def::End);
+
+    let let_ = just(Token::KwLet)
+        .ignore_then(pat.clone())
+        .then_ignore(just(Token::Equal))
+        .then(exp.clone())
+        .map(|(pat, exp)| Def::Let(LetBinding { pat, exp }));
+
+    let default_order = just(Token::KwDefault)
+        .ignore_then(just(Token::KwOrder))
+        .ignore_then(order())
+        .map(Def::DefaultOrder);
+
+    let fixity = select_token! {
+        Token::KwInfix => Assoc::None,
+        Token::KwInfixl => Assoc::Left,
+        Token::KwInfixr => Assoc::Right,
+    }
+    .then(
+        spanned(select_token! { Token::Num(n) => n }).try_map(|(n, span), _| {
+            n.parse::<u8>()
+                .map(|level| (level, span))
+                .map_err(|_| Rich::custom(span, "invalid fixity level"))
+        }),
+    )
+    .then(infix_op().or(ident()))
+    .map(|((assoc, level), op)| Def::Fixity(FixityDecl { assoc, level, op }));
+
+    let termination_measure = just(Token::KwTerminationMeasure)
+        .ignore_then(ident())
+        .then(pat)
+        .then_ignore(just(Token::Equal))
+        .then(exp)
+        .map(|((name, pat), exp)| Def::TerminationMeasure { name, pat, exp });
+
+    let directive = select_token! { Token::Directive(d) => Def::Directive(d) };
+
+    let attribute = just(Token::Dollar)
+        .ignore_then(
+            any()
+                .and_is(just(Token::RightSquareBracket).not())
+                .repeated()
+                .delimited_by(
+                    just(Token::LeftSquareBracket),
+                    just(Token::RightSquareBracket),
+                ),
+        )
+        .to(Def::Attribute);
+
+    // After an error, skip to the start of the next definition, keeping the
+    // name if it parsed so that it can still be found.
+    let error_name = choice((
+        just(Token::KwFunction).then(just(Token::KwClause).or_not()),
+        just(Token::KwMapping).then(just(Token::KwClause).or_not()),
+        just(Token::KwRegister).then(just(Token::KwConfiguration).or_not()),
+        just(Token::KwVal).then(just(Token::KwCast).or_not()),
+        one_of([
+            Token::KwType,
+            Token::KwStruct,
+            Token::KwUnion,
+            Token::KwEnum,
+            Token::KwBitfield,
+            Token::KwOverload,
+        ])
+        .map(|keyword| (keyword, None)),
+    ))
+    .map(|(keyword, second)| [Some(keyword), second].into_iter().flatten().collect())
+    .then(def_name());
+    let skip_rest = custom(|inp| {
+        while !at_def_start(inp) && inp.next().is_some() {}
+        Ok(())
+    });
+    let skip_def = error_name
+        .map(Some)
+        .or(any().to(None))
+        .then_ignore(skip_rest)
+        .map(Def::Error);
+
+    let def = spanned(
+        choice((
+            val,
+            function_clause,
+            function,
+            mapping_def_clause,
+            mapping,
+            type_def,
+            struct_def,
+            union_clause,
+            union,
+            enum_clause,
+            enum_,
+            bitfield,
+            register,
+            overload,
+            scattered,
+            end_def,
+            let_,
+            default_order,
+            fixity,
+            termination_measure,
+            directive,
+            attribute,
+        ))
+        .labelled("definition")
+        .recover_with(via_parser(skip_def)),
+    );
+
+    def.repeated()
+        .collect::<Vec<_>>()
+        .then_ignore(end())
+        .map(|defs| SourceFile { defs })
+}
+
+/// Parse the output of `lexer()`. This recovers from errors, so there is
+/// always an AST, though parts of it may be `Error` nodes.
+pub fn parse(tokens: &[(Token, Span)], fixities: &FixityTable) -> (SourceFile, Vec<ParseError>) {
+    let (file, errors) = parser(fixities)
+        .parse(parser_input(tokens))
+        .into_output_errors();
+    // Chumsky gives errors at the end of the input a span covering the whole
+    // input, so move them to the end.
+    let end = tokens.last().map_or(0, |(_, span)| span.end);
+    let errors = errors
+        .into_iter()
+        .map(|error| match error.found() {
+            Some(_) => error.into_owned(),
+            None => Rich::custom(Span::new(end, end), error),
+        })
+        .collect();
+    (file.unwrap_or_default(), errors)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer;
+
+    fn parse_ok(code: &str) -> SourceFile {
+        let tokens = lexer().parse(code).into_result().unwrap();
+        let fixities = FixityTable::default();
+        let result = parser(&fixities).parse(parser_input(&tokens));
+        match result.into_result() {
+            Ok(file) => file,
+            Err(errors) => panic!("parse errors: {:?}", errors),
+        }
+    }
+
+    fn parse_exp(code: &str) -> Spanned<Exp> {
+        let file = parse_ok(&format!("let x = {}", code));
+        match file.defs.into_iter().next() {
+            Some((Def::Let(binding), _)) => binding.exp,
+            def => panic!("unexpected definition: {:?}", def),
+        }
+    }
+
+    #[test]
+    fn test_val() {
+        let file = parse_ok(
+            r#"
+val write_ram = {lem: "write_ram", coq: "write_ram"} : forall 'n, 0 < 'n <= max_mem_access . (write_kind, xlenbits, atom('n), bits(8 * 'n), mem_meta) -> bool effect {wmv, wmvt}
+val __TraceMemoryWrite : forall 'n 'm. (atom('n), bits('m), bits(8 * 'n)) -> unit
+val not_bool = pure "not" : bool -> bool
+val "print_bits" : forall 'n. (string, bits('n)) -> unit
+"#,
+        );
+        assert_eq!(file.defs.len(), 4);
+        let Def::Val(val) = &file.defs[0].0 else {
+            panic!("expected val");
+        };
+        assert_eq!(val.name.0, "write_ram");
+        assert_eq!(val.externs.len(), 2);
+        assert_eq!(val.typschm.0.quantifiers.len(), 1);
+        assert!(val.typschm.0.constraint.is_some());
+        let Typ::Function(args, _) = &val.typschm.0.typ.0 else {
+            panic!("expected function type");
+        };
+        let Typ::Tuple(args) = &args.0 else {
+            panic!("expected tuple");
+        };
+        assert_eq!(args.len(), 5);
+        let Def::Val(val) = &file.defs[3].0 else {
+            panic!("expected val");
+        };
+        assert_eq!(val.name.0, "print_bits");
+    }
+
+    #[test]
+    fn test_function() {
+        let file = parse_ok(
+            r#"
+function write_ram(wk, addr, width, data, meta) = {
+  let ret : bool = __write_mem(wk, sizeof(xlen), addr, width, data);
+  if ret then __WriteRAM_Meta(addr, width, meta);
+  ret
+}
+
+function read_ram(rk, addr, width, read_meta) =
+  let meta = if read_meta then __ReadRAM_Meta(addr, width) else default_meta in
+  (__read_mem(rk, sizeof(xlen), addr, width), meta)
+
+function clause execute (ITYPE(imm, rs1, rd, op)) = {
+  let immext : xlenbits = sign_extend(imm);
+  X(rd) = match op {
+    RISCV_ADDI  => X(rs1) + immext,
+    RISCV_SLTI  => zero_extend(bool_to_bits(X(rs1) <_s immext)),
+    RISCV_ANDI  => X(rs1) & immext,
+  };
+  foreach (i from 0 to (sizeof(xlen) - 1) by 1 in inc) {
+    result[i] = bitzero;
+  };
+  RETIRE_SUCCESS
+}
+"#,
+        );
+        assert_eq!(file.defs.len(), 3);
+        let Def::Function(function) = &file.defs[0].0 else {
+            panic!("expected function");
+        };
+        assert_eq!(function.clauses[0].0.name.0, "write_ram");
+        let Def::FunctionClause(clause) = &file.defs[2].0 else {
+            panic!("expected function clause");
+        };
+        assert_eq!(clause.name.0, "execute");
+        assert!(matches!(clause.params.0, Pat::App(ref name, _) if name.0 == "ITYPE"));
+    }
+
+    #[test]
+    fn test_type_definitions() {
+        let file = parse_ok(
+            r#"
+default Order dec
+type xlen : Int = 64
+type xlenbits = bits(xlen)
+type bits('n : Int) = bitvector('n, dec)
+struct Foo = {
+  a : bits(8),
+  b : {'n, 'n > 0. int('n)},
+}
+union option('a : Type) = { Some : 'a, None : unit }
+scattered union ast
+union clause ast = ITYPE : (bits(12), regidx, regidx, iop)
+enum iop = {RISCV_ADDI, RISCV_SLTI, RISCV_ANDI}
+enum Privilege = User | Supervisor | Machine
+bitfield Mstatus : bits(64) = {
+  SD : xlen - 1,
+  MPP : 12 .. 11,
+  MIE : 3,
+}
+register PC : xlenbits
+register configuration elen : int = 64
+overload operator == = {eq_bits, eq_int}
+overload X = {rX, wX}
+infix 4 <_s
+infixl 7 >>
+$include <prelude.sail>
+end ast
+"#,
+        );
+        assert_eq!(file.defs.len(), 19);
+        let Def::Bitfield(bitfield) = &file.defs[10].0 else {
+            panic!("expected bitfield");
+        };
+        assert_eq!(bitfield.fields.len(), 3);
+        assert!(bitfield.fields[1].low.is_some());
+        let Def::Overload(overload) = &file.defs[13].0 else {
+            panic!("expected overload");
+        };
+        assert_eq!(overload.name.0, "==");
+        assert_eq!(overload.members.len(), 2);
+        assert_eq!(
+            file.defs[17].0,
+            Def::Directive("include <prelude.sail>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mapping() {
+        let file = parse_ok(
+            r#"
+mapping bool_bits : bool <-> bits(1) = {
+  true  <-> 0b1,
+  false <-> 0b0,
+  forwards _ => false,
+}
+scattered mapping encdec : ast <-> bits(32)
+mapping clause encdec = ITYPE(imm, rs1, rd, RISCV_ADDI) if some_guard(imm)
+  <-> imm @ rs1 @ 0b000 @ rd @ 0b0010011
+mapping clause assembly = ITYPE(imm, rs1, rd, op)
+  <-> itype_mnemonic(op) ^ spc() ^ reg_name(rd) ^ sep() ^ hex_bits_12(imm)
+"#,
+        );
+        assert_eq!(file.defs.len(), 4);
+        let Def::Mapping(mapping) = &file.defs[0].0 else {
+            panic!("expected mapping");
+        };
+        assert_eq!(mapping.clauses.len(), 3);
+    }
+
+    #[test]
+    fn test_precedence() {
+        // a + (b * c) == d
+        let (exp, _) = parse_exp("a + b * c == d");
+        let Exp::Infix(lhs, op, _) = exp else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "==");
+        let Exp::Infix(_, op, rhs) = lhs.0 else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "+");
+        assert!(matches!(rhs.0, Exp::Infix(_, ref op, _) if op.0 == "*"));
+
+        // Right associative: a @ (b @ c)
+        let (exp, span) = parse_exp("a @ b @ c");
+        assert_eq!(span, Span::new(8, 17));
+        let Exp::Infix(lhs, _, rhs) = exp else {
+            panic!("expected infix");
+        };
+        assert_eq!(lhs.0, Exp::Id("a".to_string()));
+        assert_eq!(rhs.1, Span::new(12, 17));
+    }
+
+    #[test]
+    fn test_spans() {
+        let file = parse_ok("val foo : int -> int\nfunction foo(x) = x + 1");
+        assert_eq!(file.defs[0].1, Span::new(0, 20));
+        let Def::Function(function) = &file.defs[1].0 else {
+            panic!("expected function");
+        };
+        assert_eq!(function.clauses[0].0.name.1, Span::new(30, 33));
+        assert_eq!(function.clauses[0].0.body.1, Span::new(39, 44));
+    }
+
+    #[test]
+    fn test_error() {
+        let tokens = lexer().parse("function foo(x) = ").into_result().unwrap();
+        let fixities = FixityTable::default();
+        let result = parser(&fixities).parse(parser_input(&tokens));
+        assert!(result.has_errors());
+    }
+
+    #[test]
+    fn test_recovery() {
+        let code = r#"
+val foo : int -> int
+function foo(x) = {
+  let y = x + ;
+  bar(y);
+  baz(
+}
+val bar : int ->
+function bar(x) = x
+"#;
+        let tokens = lexer().parse(code).into_result().unwrap();
+        let (file, errors) = parse(&tokens, &FixityTable::default());
+
+        assert_eq!(errors.len(), 3);
+        // `x + ;`
+        assert_eq!(errors[0].span(), &Span::new(56, 57));
+        // `baz(`
+        assert_eq!(errors[1].span(), &Span::new(75, 76));
+        // `val bar : int ->`
+        assert_eq!(errors[2].span(), &Span::new(94, 102));
+
+        assert_eq!(file.defs.len(), 5);
+        let Def::Function(function) = &file.defs[1].0 else {
+            panic!("expected function");
+        };
+        let Exp::Block(statements) = &function.clauses[0].0.body.0 else {
+            panic!("expected block");
+        };
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[0], (Exp::Error, Span::new(44, 55)));
+        assert!(matches!(statements[1].0, Exp::Call(..)));
+        assert_eq!(statements[2], (Exp::Error, Span::new(70, 74)));
+        // `val bar : int` parses on its own, leaving `->`.
+        assert!(matches!(file.defs[2].0, Def::Val(..)));
+        assert_eq!(file.defs[3], (Def::Error(None), Span::new(91, 93)));
+        assert!(matches!(file.defs[4].0, Def::Function(..)));
+    }
+
+    #[test]
+    fn test_recovery_at_definitions() {
+        let code =
+            "function foo(x : ) = 1\nregister r : int\n$[sv_module]\nstruct S = { a : int }\n";
+        let tokens = lexer().parse(code).into_result().unwrap();
+        let (file, errors) = parse(&tokens, &FixityTable::default());
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(file.defs.len(), 4);
+        assert_eq!(
+            file.defs[0],
+            (
+                Def::Error(Some((
+                    vec![Token::KwFunction],
+                    ("foo".to_string(), Span::new(9, 12))
+                ))),
+                Span::new(0, 22)
+            )
+        );
+        assert!(matches!(file.defs[1].0, Def::Register(..)));
+        assert!(matches!(file.defs[2].0, Def::Attribute));
+        assert!(matches!(file.defs[3].0, Def::Struct(..)));
+    }
+}
@@ -0,0 +1,236 @@
+//! Operator precedence.
+//!
+//! Sail doesn't have a fixed operator precedence. Instead operators are
+//! declared with `infix`, `infixl` and `infixr`. The parser reads chains of
+//! operators and operands, e.g. `a + b * c`, and then groups them using a
+//! `FixityTable`.
+//!
+//! The declarations can be anywhere in the workspace, so the table is built
+//! from all files with `fixity_declarations()`.
+use crate::ast::{Assoc, Ident, Spanned};
+use crate::{Span, Token};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fixity {
+    pub assoc: Assoc,
+    pub level: u8,
+}
+
+/// Operators that are not in the table get this fixity.
+const DEFAULT_FIXITY: Fixity = Fixity {
+    assoc: Assoc::Left,
+    level: 9,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FixityTable {
+    fixities: HashMap<String, Fixity>,
+}
+
+impl Default for FixityTable {
+    /// The operators that are built in to the Sail compiler.
+    fn default() -> Self {
+        let builtin = [
+            ("|", Assoc::Right, 2),
+            ("&", Assoc::Right, 3),
+            ("==", Assoc::None, 4),
+            ("!=", Assoc::None, 4),
+            ("<", Assoc::None, 4),
+            (">", Assoc::None, 4),
+            ("<=", Assoc::None, 4),
+            (">=", Assoc::None, 4),
+            ("in", Assoc::None, 4),
+            ("@", Assoc::Right, 5),
+            ("::", Assoc::Right, 5),
+            ("+", Assoc::Left, 6),
+            ("-", Assoc::Left, 6),
+            ("*", Assoc::Left, 7),
+            ("/", Assoc::Left, 7),
+            ("%", Assoc::Left, 7),
+            ("^", Assoc::Right, 8),
+        ];
+        Self {
+            fixities: builtin
+                .into_iter()
+                .map(|(op, assoc, level)| (op.to_string(), Fixity { assoc, level }))
+                .collect(),
+        }
+    }
+}
+
+impl Extend<(String, Fixity)> for FixityTable {
+    fn extend<T: IntoIterator<Item = (String, Fixity)>>(&mut self, declarations: T) {
+        self.fixities.extend(declarations);
+    }
+}
+
+/// Find the fixity declarations in a file, e.g. `infixl 5 <<`. This works on
+/// tokens rather than the AST because parsing depends on the fixities.
+pub fn fixity_declarations(tokens: &[(Token, Span)]) -> Vec<(String, Fixity)> {
+    tokens
+        .windows(3)
+        .filter_map(|window| {
+            let assoc = match window[0].0 {
+                Token::KwInfix => Assoc::None,
+                Token::KwInfixl => Assoc::Left,
+                Token::KwInfixr => Assoc::Right,
+                _ => return None,
+            };
+            let Token::Num(level) = &window[1].0 else {
+                return None;
+            };
+            let level = level.parse().ok()?;
+            Some((window[2].0.to_string(), Fixity { assoc, level }))
+        })
+        .collect()
+}
+
+impl FixityTable {
+    pub fn get(&self, op: &str) -> Fixity {
+        self.fixities.get(op).copied().unwrap_or(DEFAULT_FIXITY)
+    }
+
+    pub fn insert(&mut self, op: String, fixity: Fixity) {
+        self.fixities.insert(op, fixity);
+    }
+
+    /// Group a chain of operands and operators, e.g. `a + b * c`, into a
+    /// tree using the operator fixities. `make` constructs a binary node.
+    ///
+    /// Non-associative operators are grouped to the left rather than
+    /// reported as an error, so that constraints like `0 < 'n <= 64` work.
+    pub fn resolve<T>(
+        &self,
+        first: Spanned<T>,
+        rest: Vec<(Ident, Spanned<T>)>,
+        make: impl Fn(Box<Spanned<T>>, Ident, Box<Spanned<T>>) -> T,
+    ) -> Spanned<T> {
+        let mut operands = vec![first];
+        let mut operators: Vec<(Ident, Fixity)> = Vec::new();
+
+        let reduce = |operands: &mut Vec<Spanned<T>>, op: Ident| {
+            let rhs = operands.pop().expect("missing rhs operand");
+            let lhs = operands.pop().expect("missing lhs operand");
+            let span = Span::new(lhs.1.start, rhs.1.end);
+            operands.push((make(Box::new(lhs), op, Box::new(rhs)), span));
+        };
+
+        for (op, operand) in rest {
+            let fixity = self.get(&op.0);
+            while let Some((_, top)) = operators.last() {
+                if top.level > fixity.level
+                    || (top.level == fixity.level && fixity.assoc != Assoc::Right)
+                {
+                    let (top_op, _) = operators.pop().unwrap();
+                    reduce(&mut operands, top_op);
+                } else {
+                    break;
+                }
+            }
+            operators.push((op, fixity));
+            operands.push(operand);
+        }
+
+        while let Some((op, _)) = operators.pop() {
+            reduce(&mut operands, op);
+        }
+
+        operands.pop().expect("missing operand")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lexer, parse, Def, Exp, Pat, SourceFile};
+    use chumsky::Parser;
+
+    fn parse_with_declarations(code: &str) -> SourceFile {
+        let tokens = lexer().parse(code).into_result().unwrap();
+        let mut fixities = FixityTable::default();
+        fixities.extend(fixity_declarations(&tokens));
+        let (file, errors) = parse(&tokens, &fixities);
+        assert!(errors.is_empty(), "parse errors: {:?}", errors);
+        file
+    }
+
+    #[test]
+    fn test_declarations() {
+        let tokens = lexer()
+            .parse("infix 4 <_s\ninfixl 5 ++\ninfixr 1 -->")
+            .into_result()
+            .unwrap();
+        assert_eq!(
+            fixity_declarations(&tokens),
+            [
+                (
+                    "<_s".to_string(),
+                    Fixity {
+                        assoc: Assoc::None,
+                        level: 4
+                    }
+                ),
+                (
+                    "++".to_string(),
+                    Fixity {
+                        assoc: Assoc::Left,
+                        level: 5
+                    }
+                ),
+                (
+                    "-->".to_string(),
+                    Fixity {
+                        assoc: Assoc::Right,
+                        level: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_user_operators() {
+        // Without a declaration `<_s` would get the default fixity (infixl 9)
+        // and bind tighter than `+`.
+        let file =
+            parse_with_declarations("infix 4 <_s\ninfixr 1 -->\nlet x = a + b <_s c --> d --> e");
+        let Some((Def::Let(binding), _)) = file.defs.last() else {
+            panic!("expected let");
+        };
+        // (((a + b) <_s c) --> (d --> e))
+        let Exp::Infix(lhs, op, rhs) = &binding.exp.0 else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "-->");
+        assert!(matches!(&rhs.0, Exp::Infix(_, op, _) if op.0 == "-->"));
+        assert_eq!(rhs.1, Span::new(49, 56));
+        let Exp::Infix(lhs, op, _) = &lhs.0 else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "<_s");
+        assert!(matches!(&lhs.0, Exp::Infix(_, op, _) if op.0 == "+"));
+    }
+
+    #[test]
+    fn test_pattern_operators() {
+        // `@` is infixr 5 and `^` is infixr 8, so this is a @ ((b ^ c) @ d).
+        let file = parse_with_declarations("let a @ b ^ c @ d = x");
+        let Some((Def::Let(binding), _)) = file.defs.first() else {
+            panic!("expected let");
+        };
+        let Pat::Infix(lhs, op, rhs) = &binding.pat.0 else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "@");
+        assert_eq!(lhs.0, Pat::Id("a".to_string()));
+        let Pat::Infix(lhs, op, _) = &rhs.0 else {
+            panic!("expected infix");
+        };
+        assert_eq!(op.0, "@");
+        assert!(matches!(&lhs.0, Pat::Infix(_, op, _) if op.0 == "^"));
+        assert_eq!(lhs.1, Span::new(8, 13));
+    }
+}
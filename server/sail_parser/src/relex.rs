@@ -0,0 +1,261 @@
+//! Incremental lexing.
+//!
+//! After an edit only the tokens near it can change, so instead of lexing
+//! the whole file again we restart at a token before the edit and lex until
+//! the new tokens line up with the old ones. Tokens never start inside a
+//! comment or string, and lexing from the start of a token only depends on
+//! the text after it, so once a new token starts where an old one did
+//! (after the edit) the rest of the old tokens are still right and only need
+//! to be moved.
+use crate::lexer::{lexer, padded_token};
+use crate::{Span, Token};
+use chumsky::Parser;
+
+/// A change to the text since it was lexed, as byte offsets. The old text
+/// from `start` to `old_end` was replaced by the new text from `start` to
+/// `new_end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl Edit {
+    /// Combine this with a later edit, which is relative to the text after
+    /// this one.
+    pub fn then(self, next: Edit) -> Edit {
+        // Everything after both edits is unchanged.
+        let end = self.new_end.max(next.old_end);
+        Edit {
+            start: self.start.min(next.start),
+            old_end: self.old_end + (end - self.new_end),
+            new_end: end + next.new_end - next.old_end,
+        }
+    }
+
+    /// Move a span of the old text that is after the edit.
+    pub fn shift(&self, span: Span) -> Span {
+        Span::new(
+            span.start - self.old_end + self.new_end,
+            span.end - self.old_end + self.new_end,
+        )
+    }
+}
+
+/// The result of `relex()`.
+pub struct Relexed {
+    // All the tokens in the new text.
+    pub tokens: Vec<(Token, Span)>,
+    // Errors in the part of the new text that was lexed, as (span, message).
+    pub errors: Vec<(Span, String)>,
+    // Where lexing started. Errors before this are unchanged.
+    pub start: usize,
+    // Where the old tokens were used from, in the old text. Errors after this
+    // are unchanged except that they move with the edit. If this is `None`
+    // then everything after `start` was lexed.
+    pub reused_from: Option<usize>,
+}
+
+/// Lex `source` after `edit`, reusing the `old` tokens that weren't affected.
+pub fn relex(source: &str, old: &[(Token, Span)], edit: Edit) -> Relexed {
+    // Restart at the last token that ends before the edit. The token after it
+    // can be changed even if the edit is after it, e.g. `ab` + `c`.
+    let before = old.partition_point(|(_, span)| span.end < edit.start);
+    let (kept, start) = match before.checked_sub(1) {
+        Some(last) => (last, old[last].1.start),
+        None => (0, 0),
+    };
+
+    let mut tokens = old[..kept].to_vec();
+    let mut errors = Vec::new();
+    let error = |span: Span, message: String, offset: usize| {
+        (Span::new(span.start + offset, span.end + offset), message)
+    };
+
+    // The first old token after the edit that we could line up with.
+    let mut next_old = old.partition_point(|(_, span)| span.start < edit.old_end);
+    let step = padded_token().map_with(|token, e| (token, e.span())).lazy();
+    let mut pos = start;
+    loop {
+        let (output, step_errors) = step.parse(&source[pos..]).into_output_errors();
+
+        // Trailing comments or the end of the file.
+        let Some((token, span)) = output else {
+            let (rest, rest_errors) = lexer().parse(&source[pos..]).into_output_errors();
+            tokens.extend(
+                rest.unwrap_or_default()
+                    .into_iter()
+                    .map(|(token, span)| (token, Span::new(span.start + pos, span.end + pos))),
+            );
+            errors.extend(
+                rest_errors
+                    .iter()
+                    .map(|e| error(*e.span(), e.to_string(), pos)),
+            );
+            return Relexed {
+                tokens,
+                errors,
+                start,
+                reused_from: None,
+            };
+        };
+        errors.extend(
+            step_errors
+                .iter()
+                .map(|e| error(*e.span(), e.to_string(), pos)),
+        );
+
+        if let Some((token, token_span)) = token {
+            let token_span = Span::new(token_span.start + pos, token_span.end + pos);
+            if token_span.start >= edit.new_end {
+                let old_start = token_span.start - edit.new_end + edit.old_end;
+                next_old += old[next_old..].partition_point(|(_, span)| span.start < old_start);
+                if old
+                    .get(next_old)
+                    .is_some_and(|(_, span)| span.start == old_start)
+                {
+                    tokens.extend(
+                        old[next_old..]
+                            .iter()
+                            .map(|(token, span)| (token.clone(), edit.shift(*span))),
+                    );
+                    return Relexed {
+                        tokens,
+                        errors,
+                        start,
+                        reused_from: Some(old_start),
+                    };
+                }
+            }
+            tokens.push((token, token_span));
+        }
+        pos += span.end;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = r#"/* Registers */
+register PC : bits(64)
+$include <prelude.sail>
+
+val foo : (int, string) -> unit
+function foo(x, s) = {
+  // Add one.
+  let y = x ` + 1;
+  print("value: \"y\"");
+  PC = 0xFFFF_0000 @ 0b01 <_u PC
+}
+"#;
+
+    /// Lex everything.
+    fn lex(source: &str) -> Relexed {
+        let (tokens, errors) = lexer().parse(source).into_output_errors();
+        Relexed {
+            tokens: tokens.unwrap_or_default(),
+            errors: errors.iter().map(|e| (*e.span(), e.to_string())).collect(),
+            start: 0,
+            reused_from: None,
+        }
+    }
+
+    /// Replace `range` of `SOURCE` with `text` and check that relexing gives
+    /// the same tokens as lexing everything. Returns where the old tokens
+    /// were used from.
+    fn check(range: std::ops::Range<usize>, text: &str) -> Option<usize> {
+        let old = lex(SOURCE);
+        let mut source = SOURCE.to_string();
+        source.replace_range(range.clone(), text);
+        let edit = Edit {
+            start: range.start,
+            old_end: range.end,
+            new_end: range.start + text.len(),
+        };
+        let relexed = relex(&source, &old.tokens, edit);
+        let expected = lex(&source);
+        assert_eq!(relexed.tokens, expected.tokens, "{:?} -> {:?}", range, text);
+
+        let mut errors: Vec<_> = old
+            .errors
+            .iter()
+            .filter(|(span, _)| span.start < relexed.start)
+            .cloned()
+            .collect();
+        errors.extend(relexed.errors);
+        if let Some(reused_from) = relexed.reused_from {
+            errors.extend(
+                old.errors
+                    .iter()
+                    .filter(|(span, _)| span.start >= reused_from)
+                    .map(|(span, message)| (edit.shift(*span), message.clone())),
+            );
+        }
+        assert_eq!(errors, expected.errors, "{:?} -> {:?}", range, text);
+        relexed.reused_from
+    }
+
+    #[test]
+    fn test_relex() {
+        // Changing a name only relexes nearby.
+        let offset = SOURCE.find("foo(x").unwrap();
+        assert_eq!(check(offset..offset + 3, "bar_baz"), Some(offset + 3));
+
+        // Things that change tokens further away.
+        check(offset..offset, "\"");
+        check(offset..offset, "/*");
+        check(0..2, "");
+        check(SOURCE.len()..SOURCE.len(), "// end");
+        check(SOURCE.len() - 2..SOURCE.len(), "");
+        check(0..SOURCE.len(), "a `b");
+
+        // Every single character insertion and deletion.
+        for offset in 0..SOURCE.len() {
+            for text in [
+                "", "a", " ", "\n", "\"", "/", "*", "-", "1", "_", "'", "$", "`",
+            ] {
+                let end = if text.is_empty() { offset + 1 } else { offset };
+                check(offset..end, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_combine_edits() {
+        // "abcdef" -> "abXYZdef" -> "aQYZdef"
+        let first = Edit {
+            start: 2,
+            old_end: 3,
+            new_end: 5,
+        };
+        let second = Edit {
+            start: 1,
+            old_end: 3,
+            new_end: 2,
+        };
+        assert_eq!(
+            first.then(second),
+            Edit {
+                start: 1,
+                old_end: 3,
+                new_end: 4,
+            }
+        );
+        // An edit after the first one.
+        let third = Edit {
+            start: 6,
+            old_end: 7,
+            new_end: 6,
+        };
+        assert_eq!(
+            first.then(third),
+            Edit {
+                start: 2,
+                old_end: 5,
+                new_end: 6,
+            }
+        );
+    }
+}
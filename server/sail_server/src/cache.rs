@@ -0,0 +1,275 @@
+// An on-disk cache of analysed files, so that only files that have changed
+// need to be lexed and parsed when the server starts. There is one entry
+// per file, named by a hash of its path. Entries are only used if the
+// file's modification time and content hash match, and they are in a
+// directory for the parser and server version so that changing either
+// invalidates everything. The version directories are in a `sail_lsp`
+// directory in the configured one, which may contain other things.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{file::Analysis, files};
+
+/// Where the cache is and whether to use it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    // If not set the user's cache directory is used.
+    pub directory: Option<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            directory: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    mtime: u64,
+    hash: u64,
+    // The workspace fixities when it was parsed.
+    parsed_with: sail_parser::FixityTable,
+    analysis: Analysis,
+}
+
+/// A cached analysis and the fixities it was parsed with.
+pub struct Cached {
+    pub parsed_with: sail_parser::FixityTable,
+    pub analysis: Analysis,
+}
+
+/// The modification time and content hash of a file, which identify the
+/// version that was analysed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Version {
+    mtime: u64,
+    hash: u64,
+}
+
+impl Version {
+    pub fn new(path: &Path, source: &str) -> Self {
+        Self {
+            mtime: files::modified_time(path).unwrap_or(0),
+            hash: hash(source.as_bytes()),
+        }
+    }
+}
+
+/// FNV-1a. The standard library's hasher isn't guaranteed to be the same
+/// between Rust versions.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Open the cache in `root`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            dir: root.join("sail_lsp").join(version()),
+        }
+    }
+
+    /// Delete caches from other versions. This only deletes directories
+    /// whose names are versions, in case something else is there.
+    pub fn remove_old_versions(&self) {
+        let (Some(parent), Some(current)) = (self.dir.parent(), self.dir.file_name()) else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(parent) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if name != current && name.to_str().is_some_and(is_version) {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+
+    /// The cache from the configuration, or `None` if it is disabled.
+    pub fn from_config(config: &CacheConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let root = match &config.directory {
+            Some(directory) => PathBuf::from(directory),
+            None => default_directory()?,
+        };
+        Some(Self::new(&root))
+    }
+
+    fn entry_path(&self, path: &Path) -> PathBuf {
+        let name = format!("{:016x}.json", hash(path.as_os_str().as_encoded_bytes()));
+        self.dir.join(name)
+    }
+
+    /// Get the analysis of a file if it is cached for this version.
+    pub fn load(&self, path: &Path, version: Version) -> Option<Cached> {
+        let json = fs::read(self.entry_path(path)).ok()?;
+        let entry: Entry = serde_json::from_slice(&json).ok()?;
+        if entry.path != path || entry.mtime != version.mtime || entry.hash != version.hash {
+            return None;
+        }
+        Some(Cached {
+            parsed_with: entry.parsed_with,
+            analysis: entry.analysis,
+        })
+    }
+
+    /// Save the analysis of a file. Errors are ignored because the cache is
+    /// only an optimisation.
+    pub fn store(
+        &self,
+        path: &Path,
+        version: Version,
+        parsed_with: &sail_parser::FixityTable,
+        analysis: Analysis,
+    ) {
+        let entry = Entry {
+            path: path.to_path_buf(),
+            mtime: version.mtime,
+            hash: version.hash,
+            parsed_with: parsed_with.clone(),
+            analysis,
+        };
+        let Ok(json) = serde_json::to_vec(&entry) else {
+            return;
+        };
+        // Write to a temporary file first so that other servers sharing the
+        // cache never see half an entry.
+        let entry_path = self.entry_path(path);
+        let temp = entry_path.with_extension(format!("{}.tmp", std::process::id()));
+        let _ = fs::create_dir_all(&self.dir)
+            .and_then(|()| fs::write(&temp, json))
+            .and_then(|()| fs::rename(&temp, &entry_path));
+    }
+}
+
+/// The name of this version's directory, e.g. `v1-0.1.0`.
+fn version() -> String {
+    format!("v{}-{}", sail_parser::VERSION, env!("CARGO_PKG_VERSION"))
+}
+
+/// Is `name` like those from `version()`, i.e. `v<number>-<semver>`?
+fn is_version(name: &str) -> bool {
+    let Some((number, semver)) = name.strip_prefix('v').and_then(|rest| rest.split_once('-'))
+    else {
+        return false;
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    // Pre-release and build metadata come after the first `-` or `+`.
+    let (core, suffix) = match semver.split_once(['-', '+']) {
+        Some((core, suffix)) => (core, Some(suffix)),
+        None => (semver, None),
+    };
+    let parts = core.split('.').collect::<Vec<_>>();
+    is_number(number)
+        && parts.len() == 3
+        && parts.iter().all(|part| is_number(part))
+        && suffix.is_none_or(|suffix| {
+            !suffix.is_empty()
+                && suffix
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-+.".contains(&b))
+        })
+}
+
+fn default_directory() -> Option<PathBuf> {
+    let env = |name| std::env::var_os(name).map(PathBuf::from);
+    let base = if cfg!(windows) {
+        env("LOCALAPPDATA")?
+    } else if cfg!(target_os = "macos") {
+        env("HOME")?.join("Library/Caches")
+    } else {
+        env("XDG_CACHE_HOME").or_else(|| env("HOME").map(|home| home.join(".cache")))?
+    };
+    Some(base)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::File;
+    use crate::text_document::PositionEncoding;
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("sail_cache_{}", std::process::id()));
+        let source = "infixl 5 <<<\nregister x : bits(8)\n";
+        let path = dir.join("a.sail");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, source).unwrap();
+
+        // Old versions are deleted, but nothing else is.
+        for name in [
+            "sail_lsp/v0-0.0.0",
+            "sail_lsp/v1-0.1.0-rc.1",
+            "sail_lsp/vendor",
+            "venv",
+        ] {
+            fs::create_dir_all(dir.join("cache").join(name)).unwrap();
+        }
+        let cache = Cache::new(&dir.join("cache"));
+        cache.remove_old_versions();
+        assert!(!dir.join("cache/sail_lsp/v0-0.0.0").exists());
+        assert!(!dir.join("cache/sail_lsp/v1-0.1.0-rc.1").exists());
+        assert!(dir.join("cache/sail_lsp/vendor").exists());
+        assert!(dir.join("cache/venv").exists());
+
+        let fixities = sail_parser::FixityTable::default();
+        let version = Version::new(&path, source);
+        assert!(cache.load(&path, version).is_none());
+
+        let file = File::new(source.to_string(), PositionEncoding::default(), &fixities);
+        cache.store(&path, version, &fixities, file.analysis());
+        let cached = cache.load(&path, version).unwrap();
+        assert_eq!(cached.parsed_with, fixities);
+        let restored = File::from_analysis(
+            source.to_string(),
+            PositionEncoding::default(),
+            cached.analysis,
+        );
+        assert_eq!(restored.tokens, file.tokens);
+        assert_eq!(restored.ast, file.ast);
+        assert_eq!(restored.fixities, file.fixities);
+        assert!(restored.definitions.contains_key("x"));
+
+        // A different source isn't used.
+        let changed = Version::new(&path, "register y : bits(8)\n");
+        assert!(cache.load(&path, changed).is_none());
+        assert!(cache.load(&dir.join("b.sail"), version).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_version() {
+        assert!(is_version(&version()));
+        assert!(is_version("v2-1.10.0"));
+        assert!(is_version("v2-1.0.0+build.5"));
+        assert!(!is_version("vendor"));
+        assert!(!is_version("venv"));
+        assert!(!is_version("v2"));
+        assert!(!is_version("v-1.0.0"));
+        assert!(!is_version("v2-1.0"));
+        assert!(!is_version("v2-1.0.0-"));
+        assert!(!is_version("v2-1.0.0/x"));
+    }
+}
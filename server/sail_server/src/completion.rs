@@ -1 +1,426 @@
+use std::collections::BTreeMap;
 
+use sail_parser::{Def, Token, Typ};
+use tower_lsp::lsp_types::{CompletionItem, CompletionItemKind, Url};
+
+use crate::definitions::DefinitionKind;
+use crate::file::File;
+
+/// Keywords that can start a top-level definition.
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "bitfield",
+    "default",
+    "end",
+    "enum",
+    "function",
+    "infix",
+    "infixl",
+    "infixr",
+    "let",
+    "mapping",
+    "overload",
+    "register",
+    "scattered",
+    "struct",
+    "termination_measure",
+    "type",
+    "union",
+    "val",
+];
+
+/// Keywords that can appear in expressions.
+const EXPRESSION_KEYWORDS: &[&str] = &[
+    "assert",
+    "by",
+    "catch",
+    "do",
+    "else",
+    "exit",
+    "false",
+    "foreach",
+    "if",
+    "in",
+    "let",
+    "match",
+    "ref",
+    "repeat",
+    "return",
+    "sizeof",
+    "struct",
+    "then",
+    "throw",
+    "true",
+    "try",
+    "undefined",
+    "until",
+    "var",
+    "while",
+];
+
+/// Keywords that can appear in type signatures.
+const TYPE_KEYWORDS: &[&str] = &["forall"];
+
+/// What sort of thing can be typed at the cursor.
+#[derive(Debug, PartialEq)]
+enum Context<'a> {
+    /// The start of a new definition.
+    TopLevel,
+    /// Inside a `val`, or a type definition.
+    Type,
+    /// Inside a function body or similar.
+    Expression,
+    /// After `a.b.`, so a field of `a.b`.
+    Field(Vec<&'a str>),
+}
+
+/// Work out the context for a word starting at `offset`.
+fn context(file: &File, offset: usize) -> Context<'_> {
+    let tokens = &file.tokens;
+    // Tokens that end before the word.
+    let end = tokens.partition_point(|(_, span)| span.end <= offset);
+
+    // `a.b.` - collect the path in reverse.
+    let mut path = Vec::new();
+    let mut i = end;
+    while let (Some((Token::Dot, _)), Some((Token::Id(name), _))) = (
+        i.checked_sub(1).map(|i| &tokens[i]),
+        i.checked_sub(2).map(|i| &tokens[i]),
+    ) {
+        path.push(name.as_str());
+        i -= 2;
+    }
+    if !path.is_empty() {
+        path.reverse();
+        return Context::Field(path);
+    }
+
+    // The definition that the word is part of, if any.
+    let Some((def, span)) = file
+        .ast
+        .defs
+        .iter()
+        .rev()
+        .find(|(_, span)| span.start < offset)
+    else {
+        return Context::TopLevel;
+    };
+    let first = tokens.partition_point(|(_, s)| s.start < span.start);
+
+    // If the definition has ended and there are no unclosed brackets then
+    // this must be a new definition. Definitions that failed to parse are
+    // probably still being typed.
+    let mut depth = 0usize;
+    for (token, _) in &tokens[first..end] {
+        match token {
+            Token::LeftBracket | Token::LeftSquareBracket | Token::LeftCurlyBracket => depth += 1,
+            Token::RightBracket | Token::RightSquareBracket | Token::RightCurlyBracket => {
+                depth = depth.saturating_sub(1)
+            }
+            _ => {}
+        }
+    }
+    if span.end < offset && depth == 0 && !matches!(def, Def::Error(_)) {
+        return Context::TopLevel;
+    }
+
+    // Look at the first token rather than the AST because the definition
+    // may not have parsed.
+    match tokens.get(first) {
+        Some((
+            Token::KwVal | Token::KwType | Token::KwStruct | Token::KwUnion | Token::KwBitfield,
+            _,
+        )) => Context::Type,
+        _ => Context::Expression,
+    }
+}
+
+/// All the definitions in the workspace, with their kind. If a name has
+/// several kinds then the first by `DefinitionKind`'s order is used, so that
+/// it is the same every time.
+fn definitions(files: &[(&Url, &File)]) -> BTreeMap<String, CompletionItemKind> {
+    let mut kinds = BTreeMap::<&str, DefinitionKind>::new();
+    for (_, file) in files {
+        for (name, sites) in &file.definitions {
+            for definition in sites {
+                kinds
+                    .entry(name)
+                    .and_modify(|kind| *kind = (*kind).min(definition.kind))
+                    .or_insert(definition.kind);
+            }
+        }
+    }
+    kinds
+        .into_iter()
+        .map(|(name, kind)| (name.to_string(), kind.lsp_kinds().1))
+        .collect()
+}
+
+/// Find the declared type of `name`, e.g. from `let name : foo` or
+/// `register name : foo`. Only simple type names are supported.
+fn declared_type(
+    files: &[(&Url, &File)],
+    file: &File,
+    offset: usize,
+    name: &str,
+) -> Option<String> {
+    // The nearest declaration before the cursor in this file. This covers
+    // let bindings, typed function parameters and registers.
+    let end = file.tokens.partition_point(|(_, span)| span.end <= offset);
+    for window in file.tokens[..end].windows(3).rev() {
+        if let [(Token::Id(id), _), (Token::Colon, _), (Token::Id(typ), _)] = window {
+            if id == name {
+                return Some(typ.clone());
+            }
+        }
+    }
+
+    // Registers in other files.
+    files.iter().find_map(|(_, file)| {
+        file.ast.defs.iter().find_map(|(def, _)| match def {
+            Def::Register(register) if register.name.0 == name => match &register.typ.0 {
+                Typ::Id(typ) => Some(typ.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+    })
+}
+
+/// Follow type synonyms, e.g. `type foo = bar`.
+fn resolve_type(files: &[(&Url, &File)], mut name: String) -> String {
+    // Limit the depth in case of cycles.
+    for _ in 0..16 {
+        let synonym = files.iter().find_map(|(_, file)| {
+            file.ast.defs.iter().find_map(|(def, _)| match def {
+                Def::Type(type_) if type_.name.0 == name => match &type_.typ {
+                    Some((Typ::Id(typ), _)) => Some(typ.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+        });
+        match synonym {
+            Some(synonym) => name = synonym,
+            None => break,
+        }
+    }
+    name
+}
+
+/// The fields of a struct or bitfield type, with their types if known.
+fn fields(files: &[(&Url, &File)], name: &str) -> Vec<(String, Option<String>)> {
+    for (_, file) in files {
+        let text = file.source.text();
+        for (def, _) in &file.ast.defs {
+            match def {
+                Def::Struct(struct_) if struct_.name.0 == name => {
+                    return struct_
+                        .fields
+                        .iter()
+                        .map(|((field, _), (_, span))| {
+                            (field.clone(), Some(text[span.start..span.end].to_string()))
+                        })
+                        .collect();
+                }
+                Def::Bitfield(bitfield) if bitfield.name.0 == name => {
+                    let (_, span) = &bitfield.typ;
+                    // Bitfields have an implicit `bits` field for the whole value.
+                    return std::iter::once((
+                        "bits".to_string(),
+                        Some(text[span.start..span.end].to_string()),
+                    ))
+                    .chain(
+                        bitfield
+                            .fields
+                            .iter()
+                            .map(|field| (field.name.0.clone(), None)),
+                    )
+                    .collect();
+                }
+                _ => {}
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// Completions for the fields of `a.b.`.
+fn field_items(
+    files: &[(&Url, &File)],
+    file: &File,
+    offset: usize,
+    path: &[&str],
+) -> Vec<CompletionItem> {
+    let Some((first, rest)) = path.split_first() else {
+        return Vec::new();
+    };
+    let Some(mut typ) = declared_type(files, file, offset, first) else {
+        return Vec::new();
+    };
+    for field in rest {
+        typ = resolve_type(files, typ);
+        let field_type = fields(files, &typ)
+            .into_iter()
+            .find(|(name, _)| name == field)
+            .and_then(|(_, typ)| typ);
+        match field_type {
+            Some(field_type) => typ = field_type,
+            None => return Vec::new(),
+        }
+    }
+
+    fields(files, &resolve_type(files, typ))
+        .into_iter()
+        .map(|(label, detail)| CompletionItem {
+            label,
+            kind: Some(CompletionItemKind::FIELD),
+            detail,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Completions at `offset` in `file`. `files` should include `file`.
+pub fn completion<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    file: &File,
+    offset: usize,
+) -> Vec<CompletionItem> {
+    let files = files.collect::<Vec<_>>();
+
+    // Find the start of the word being typed, if any.
+    let text = file.source.text();
+    let offset = text[..offset]
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+        .map_or(0, |i| i + 1);
+
+    let keyword = |label: &&str| CompletionItem {
+        label: label.to_string(),
+        kind: Some(CompletionItemKind::KEYWORD),
+        ..Default::default()
+    };
+    let definition = |(label, kind): (String, CompletionItemKind)| CompletionItem {
+        label,
+        kind: Some(kind),
+        ..Default::default()
+    };
+
+    match context(file, offset) {
+        Context::TopLevel => DEFINITION_KEYWORDS.iter().map(keyword).collect(),
+        Context::Type => TYPE_KEYWORDS
+            .iter()
+            .map(keyword)
+            .chain(
+                definitions(&files)
+                    .into_iter()
+                    .filter(|(_, kind)| {
+                        matches!(
+                            *kind,
+                            CompletionItemKind::CLASS
+                                | CompletionItemKind::STRUCT
+                                | CompletionItemKind::ENUM
+                        )
+                    })
+                    .map(definition),
+            )
+            .collect(),
+        Context::Expression => EXPRESSION_KEYWORDS
+            .iter()
+            .map(keyword)
+            .chain(definitions(&files).into_iter().map(definition))
+            .collect(),
+        Context::Field(path) => field_items(&files, file, offset, &path),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+
+    const WORKSPACE: &str = r#"
+struct inner = { value : bits(8) }
+struct outer = { inner : inner, count : int }
+type outer_alias = outer
+bitfield Mstatus : bits(64) = { MIE : 3, SIE : 1 }
+register mstatus : Mstatus
+val mstatus : unit -> Mstatus
+enum colour = { Red, Green }
+val add : (int, int) -> int
+"#;
+
+    /// Complete at `|` in `code`, with `WORKSPACE` in another file.
+    fn complete(code: &str) -> Vec<(String, CompletionItemKind)> {
+        let fixities = sail_parser::FixityTable::default();
+        let offset = code.find('|').unwrap();
+        let file = File::new(
+            code.replace('|', ""),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let workspace = File::new(
+            WORKSPACE.to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        let workspace_uri = Url::parse("file:///ws/b.sail").unwrap();
+        let files = [(&uri, &file), (&workspace_uri, &workspace)];
+        completion(files.into_iter(), &file, offset)
+            .into_iter()
+            .map(|item| (item.label, item.kind.unwrap()))
+            .collect()
+    }
+
+    fn labels_of(items: &[(String, CompletionItemKind)]) -> Vec<&str> {
+        items.iter().map(|(label, _)| label.as_str()).collect()
+    }
+
+    #[test]
+    fn test_keywords() {
+        let items = complete("val foo : int\nfun|");
+        let labels = labels_of(&items);
+        assert!(labels.contains(&"function"));
+        assert!(!labels.contains(&"if"));
+        assert!(!labels.contains(&"add"));
+
+        let items = complete("function foo() = {\n  let x = 1;\n  |\n}");
+        let labels = labels_of(&items);
+        assert!(labels.contains(&"if"));
+        assert!(!labels.contains(&"function"));
+        assert!(items.contains(&("add".to_string(), CompletionItemKind::FUNCTION)));
+        // The register comes before the val of the same name.
+        assert!(items.contains(&("mstatus".to_string(), CompletionItemKind::VARIABLE)));
+        assert!(items.contains(&("Red".to_string(), CompletionItemKind::ENUM_MEMBER)));
+
+        // Incomplete definitions.
+        let items = complete("function foo() = {\n  let x = |");
+        assert!(labels_of(&items).contains(&"if"));
+
+        let items = complete("val foo : |");
+        let labels = labels_of(&items);
+        assert!(labels.contains(&"forall"));
+        assert!(!labels.contains(&"if"));
+        assert!(items.contains(&("outer".to_string(), CompletionItemKind::STRUCT)));
+        assert!(items.contains(&("colour".to_string(), CompletionItemKind::ENUM)));
+        assert!(!labels.contains(&"add"));
+    }
+
+    #[test]
+    fn test_fields() {
+        let items = complete("function foo(o : outer_alias) -> unit = {\n  o.|\n}");
+        assert_eq!(labels_of(&items), ["inner", "count"]);
+        assert!(items
+            .iter()
+            .all(|(_, kind)| *kind == CompletionItemKind::FIELD));
+
+        let items = complete("function foo() = {\n  let o : outer = undefined;\n  o.inner.va|\n}");
+        assert_eq!(labels_of(&items), ["value"]);
+
+        let items = complete("function foo() = mstatus.|");
+        assert_eq!(labels_of(&items), ["bits", "MIE", "SIE"]);
+
+        let items = complete("function foo() = unknown.|");
+        assert!(items.is_empty());
+    }
+}
@@ -1,74 +1,222 @@
-use itertools::Itertools;
 use std::collections::HashMap;
 
-use sail_parser::{Span, Token};
+use sail_parser::{Def, Ident, Pat, ScatteredDef, SourceFile, Span, Token};
+use tower_lsp::lsp_types::{CompletionItemKind, SymbolKind};
 
-pub fn add_definitions(
-    tokens: &[(Token, Span)],
-    _text: &str,
-    definitions: &mut HashMap<String, usize>,
-) {
-    // For now we'll do something stupidly simple. Look for `KwFunction`
-    // followed by `Id(...)`. That is a function definitions.
-
-    // Then when we go-to-definition on an `Id()` we look those up.
+/// The sort of definition. Go-to-definition results are grouped in this
+/// order, so the main definitions come before clauses and declarations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DefinitionKind {
+    /// `function foo`, or `scattered function foo`.
+    Function,
+    /// `mapping foo`, or `scattered mapping foo`.
+    Mapping,
+    Register,
+    /// Top-level `let foo = ...`.
+    Let,
+    Type,
+    Struct,
+    /// `union foo`, or `scattered union foo`.
+    Union,
+    /// `enum foo`, or `scattered enum foo`.
+    Enum,
+    Bitfield,
+    /// A constructor in a `union` or `union clause`.
+    UnionVariant,
+    /// A member in an `enum` or `enum clause`.
+    EnumMember,
+    /// `function clause foo`.
+    FunctionClause,
+    /// `mapping clause foo`.
+    MappingClause,
+    /// `val foo : ...`.
+    Val,
+    /// `overload foo = {...}`, which adds members to `foo`.
+    Overload,
+}
 
-    // Go-to-definition is a bit tricky because of scattered functions.
-    for (token_0, token_1) in tokens.iter().tuple_windows() {
-        match (&token_0.0, &token_1.0) {
-            (&Token::KwFunction, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+impl DefinitionKind {
+    /// How definitions of this kind are shown in symbol lists and in
+    /// completions.
+    pub fn lsp_kinds(self) -> (SymbolKind, CompletionItemKind) {
+        match self {
+            DefinitionKind::Function
+            | DefinitionKind::Mapping
+            | DefinitionKind::FunctionClause
+            | DefinitionKind::MappingClause
+            | DefinitionKind::Val
+            | DefinitionKind::Overload => (SymbolKind::FUNCTION, CompletionItemKind::FUNCTION),
+            DefinitionKind::Register => (SymbolKind::VARIABLE, CompletionItemKind::VARIABLE),
+            DefinitionKind::Let => (SymbolKind::CONSTANT, CompletionItemKind::CONSTANT),
+            DefinitionKind::Type => (SymbolKind::CLASS, CompletionItemKind::CLASS),
+            DefinitionKind::Struct | DefinitionKind::Bitfield => {
+                (SymbolKind::STRUCT, CompletionItemKind::STRUCT)
             }
-            (&Token::KwRegister, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            DefinitionKind::Union | DefinitionKind::Enum => {
+                (SymbolKind::ENUM, CompletionItemKind::ENUM)
             }
-            (&Token::KwMapping, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            DefinitionKind::UnionVariant => {
+                (SymbolKind::CONSTRUCTOR, CompletionItemKind::CONSTRUCTOR)
             }
-            (&Token::KwUnion, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            DefinitionKind::EnumMember => {
+                (SymbolKind::ENUM_MEMBER, CompletionItemKind::ENUM_MEMBER)
             }
-            (&Token::KwStruct, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Definition {
+    pub kind: DefinitionKind,
+    // The span of the name.
+    pub span: Span,
+}
+
+pub fn add_definitions(file: &SourceFile, definitions: &mut HashMap<String, Vec<Definition>>) {
+    let mut add = |(name, span): &Ident, kind| {
+        definitions
+            .entry(name.clone())
+            .or_default()
+            .push(Definition { kind, span: *span });
+    };
+
+    for (def, _) in &file.defs {
+        match def {
+            Def::Val(val) => add(&val.name, DefinitionKind::Val),
+            Def::Function(function) => {
+                for (clause, _) in &function.clauses {
+                    add(&clause.name, DefinitionKind::Function);
+                }
             }
-            (&Token::KwType, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            Def::FunctionClause(clause) => add(&clause.name, DefinitionKind::FunctionClause),
+            Def::Mapping(mapping) => add(&mapping.name, DefinitionKind::Mapping),
+            Def::MappingClause { name, .. } => add(name, DefinitionKind::MappingClause),
+            Def::Register(register) => add(&register.name, DefinitionKind::Register),
+            Def::Let(binding) => {
+                let pat = match &binding.pat {
+                    (Pat::Typed(pat, _), _) => pat,
+                    pat => pat,
+                };
+                if let (Pat::Id(name), span) = pat {
+                    add(&(name.clone(), *span), DefinitionKind::Let);
+                }
             }
-            (&Token::KwOverload, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            Def::Type(type_) => add(&type_.name, DefinitionKind::Type),
+            Def::Struct(struct_) => add(&struct_.name, DefinitionKind::Struct),
+            Def::Union(union) => {
+                add(&union.name, DefinitionKind::Union);
+                for variant in &union.variants {
+                    add(&variant.name, DefinitionKind::UnionVariant);
+                }
             }
-            (&Token::KwBitfield, &Token::Id(ref ident)) => {
-                definitions.insert(ident.clone(), token_1.1.start);
+            Def::UnionClause { variant, .. } => add(&variant.name, DefinitionKind::UnionVariant),
+            Def::Enum(enum_) => {
+                add(&enum_.name, DefinitionKind::Enum);
+                for member in &enum_.members {
+                    add(&member.name, DefinitionKind::EnumMember);
+                }
+            }
+            Def::EnumClause { member, .. } => add(member, DefinitionKind::EnumMember),
+            Def::Overload(overload) => add(&overload.name, DefinitionKind::Overload),
+            Def::Bitfield(bitfield) => {
+                add(&bitfield.name, DefinitionKind::Bitfield);
                 // Auto-generated Mk_ functions.
-                definitions.insert(format!("Mk_{}", ident), token_1.1.start);
+                add(
+                    &(format!("Mk_{}", bitfield.name.0), bitfield.name.1),
+                    DefinitionKind::Function,
+                );
+            }
+            Def::Scattered(scattered) => match scattered {
+                ScatteredDef::Function(name) => add(name, DefinitionKind::Function),
+                ScatteredDef::Mapping { name, .. } => add(name, DefinitionKind::Mapping),
+                ScatteredDef::Union { name, .. } => add(name, DefinitionKind::Union),
+                ScatteredDef::Enum(name) => add(name, DefinitionKind::Enum),
+            },
+            Def::Error(Some((keywords, name))) => {
+                let kind = match keywords.as_slice() {
+                    [Token::KwFunction] => DefinitionKind::Function,
+                    [Token::KwFunction, Token::KwClause] => DefinitionKind::FunctionClause,
+                    [Token::KwMapping] => DefinitionKind::Mapping,
+                    [Token::KwMapping, Token::KwClause] => DefinitionKind::MappingClause,
+                    [Token::KwRegister, ..] => DefinitionKind::Register,
+                    [Token::KwType] => DefinitionKind::Type,
+                    [Token::KwStruct] => DefinitionKind::Struct,
+                    [Token::KwUnion] => DefinitionKind::Union,
+                    [Token::KwEnum] => DefinitionKind::Enum,
+                    [Token::KwBitfield] => DefinitionKind::Bitfield,
+                    [Token::KwOverload] => DefinitionKind::Overload,
+                    _ => DefinitionKind::Val,
+                };
+                add(name, kind);
             }
             _ => {}
         }
     }
+}
 
-    // "Parse" enums of the form `enum Foo = { Bar, Baz, ... }`
-    let mut token_iter = tokens.iter();
-    while let Some(next) = token_iter.next() {
-        if next.0 == Token::KwEnum {
-            add_enum_definition(&mut token_iter, definitions);
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chumsky::Parser;
+
+    #[test]
+    fn test_multiple_definitions() {
+        let code = r#"
+val foo : int -> int
+scattered function foo
+function clause foo(0) = 1
+function clause foo(x) = x
+overload bar = {foo}
+overload bar = {baz}
+let limit : int = 5
+"#;
+        let tokens = sail_parser::lexer().parse(code).into_result().unwrap();
+        let (ast, errors) = sail_parser::parse(&tokens, &sail_parser::FixityTable::default());
+        assert!(errors.is_empty());
+
+        let mut definitions = HashMap::new();
+        add_definitions(&ast, &mut definitions);
+
+        let kinds = |name: &str| {
+            definitions[name]
+                .iter()
+                .map(|definition| {
+                    (
+                        definition.kind,
+                        &code[definition.span.start..definition.span.end],
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds("foo"),
+            [
+                (DefinitionKind::Val, "foo"),
+                (DefinitionKind::Function, "foo"),
+                (DefinitionKind::FunctionClause, "foo"),
+                (DefinitionKind::FunctionClause, "foo"),
+            ]
+        );
+        assert_eq!(
+            kinds("bar"),
+            [
+                (DefinitionKind::Overload, "bar"),
+                (DefinitionKind::Overload, "bar")
+            ]
+        );
+        assert_eq!(kinds("limit"), [(DefinitionKind::Let, "limit")]);
     }
-}
 
-fn add_enum_definition(token_iter: &mut std::slice::Iter<(Token, Span)>, definitions: &mut HashMap<String, usize>) {
-    if let Some((Token::Id(ref ident), span)) = token_iter.next() {
-        definitions.insert(ident.clone(), span.start);
-        if let Some((Token::Equal, _)) = token_iter.next() {
-            if let Some((Token::LeftCurlyBracket, _)) = token_iter.next() {
-                while let Some((Token::Id(ident), span)) = token_iter.next() {
-                    definitions.insert(ident.clone(), span.start);
-                    if let Some((Token::Comma, _)) = token_iter.next() {
-                        // Ok
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
+    #[test]
+    fn test_broken_definitions() {
+        let code = "function foo(x : ) = 1\nregister r : int\n";
+        let tokens = sail_parser::lexer().parse(code).into_result().unwrap();
+        let (ast, errors) = sail_parser::parse(&tokens, &sail_parser::FixityTable::default());
+        assert_eq!(errors.len(), 1);
+
+        let mut definitions = HashMap::new();
+        add_definitions(&ast, &mut definitions);
+        assert_eq!(definitions["foo"][0].kind, DefinitionKind::Function);
+        assert_eq!(definitions["r"][0].kind, DefinitionKind::Register);
     }
 }
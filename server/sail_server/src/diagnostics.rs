@@ -1 +1,370 @@
+// Diagnostics from the Sail compiler. This runs `sail --just-check` on the
+// files in the active target and parses errors like:
+//
+//   Type error:
+//   model/prelude.sail:12.4-10:
+//   12 |    foo(x)
+//      |    ^----^
+//      | Identifier foo is unbound
+//
+// Lines are 1-based and columns are 0-based byte offsets. The end can be
+// `line.column` or just `column` if it is on the same line.
 
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::Deserialize;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+
+use crate::text_document::TextDocument;
+
+/// How to run the Sail compiler.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompilerConfig {
+    // Run the compiler when files are saved.
+    pub enabled: bool,
+    // The `sail` executable. If not set it is found on `PATH`.
+    pub executable: Option<String>,
+    // Extra arguments, e.g. `-dno_cast`.
+    pub args: Vec<String>,
+}
+
+/// (line, column), both 0-based. Columns are byte offsets.
+pub type LineColumn = (u32, u32);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompilerError {
+    pub path: PathBuf,
+    pub start: LineColumn,
+    pub end: LineColumn,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+/// Parse a location like `file.sail:12.4-10` or `file.sail:12.4-13.0`.
+fn parse_location(location: &str) -> Option<(PathBuf, LineColumn, LineColumn)> {
+    let (path, range) = location.rsplit_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    let (start_line, start_column) = start.split_once('.')?;
+    let start = (
+        start_line.parse::<u32>().ok()?.checked_sub(1)?,
+        start_column.parse().ok()?,
+    );
+    let end = match end.split_once('.') {
+        Some((end_line, end_column)) => (
+            end_line.parse::<u32>().ok()?.checked_sub(1)?,
+            end_column.parse().ok()?,
+        ),
+        None => (start.0, end.parse().ok()?),
+    };
+    if path.is_empty() {
+        return None;
+    }
+    Some((PathBuf::from(path), start, end))
+}
+
+/// Parse the compiler's output. Relative paths are relative to `dir`.
+pub fn parse_output(output: &str, dir: &Path) -> Vec<CompilerError> {
+    // Errors with the lines of text under the code. If there aren't any
+    // then the heading is used as the message.
+    let mut errors: Vec<(CompilerError, Vec<&str>)> = Vec::new();
+    // The line before the location, e.g. `Type error:`.
+    let mut heading = "";
+    // Whether the lines after the location belong to the last error.
+    let mut in_error = false;
+
+    for line in output.lines() {
+        let line = line.trim_end();
+
+        // The location is on its own line, or at the end of the heading for
+        // warnings, e.g. `Warning: Redundant case file.sail:3.4-10:`.
+        let location = line.strip_suffix(':').and_then(|rest| {
+            let (before, location) = match rest.rsplit_once(' ') {
+                Some((before, location)) => (before.trim(), location),
+                None => ("", rest),
+            };
+            parse_location(location).map(|location| (before, location))
+        });
+
+        if let Some((before, (path, start, end))) = location {
+            let heading = if before.is_empty() { heading } else { before };
+            let severity = if heading.starts_with("Warning") {
+                DiagnosticSeverity::WARNING
+            } else {
+                DiagnosticSeverity::ERROR
+            };
+            let message = heading
+                .trim_start_matches("Warning:")
+                .trim()
+                .trim_end_matches(':')
+                .to_string();
+            errors.push((
+                CompilerError {
+                    path: dir.join(path),
+                    start,
+                    end,
+                    severity,
+                    message,
+                },
+                Vec::new(),
+            ));
+            in_error = true;
+            continue;
+        }
+
+        let Some((_, detail)) = errors.last_mut().filter(|_| in_error) else {
+            heading = line;
+            continue;
+        };
+
+        match line.split_once('|') {
+            // Source code, e.g. `12 |    foo(x)`.
+            Some((number, _)) if !number.trim().is_empty() => {}
+            Some((_, text)) => {
+                let text = text.trim();
+                // Skip the markers under the code, e.g. `^----^`.
+                if !text.is_empty() && !text.chars().all(|c| "^-".contains(c)) {
+                    detail.push(text);
+                }
+            }
+            // Elided source code.
+            None if line.is_empty() || line == "..." => {}
+            // Something else, e.g. the heading of the next error.
+            None => {
+                in_error = false;
+                heading = line;
+            }
+        }
+    }
+
+    errors
+        .into_iter()
+        .map(|(mut error, detail)| {
+            if !detail.is_empty() {
+                error.message = detail.join("\n");
+            }
+            error
+        })
+        .collect()
+}
+
+/// Run the compiler on `files` in `dir` and return its errors. This blocks
+/// until it finishes.
+pub fn check(
+    config: &CompilerConfig,
+    dir: &Path,
+    files: &[PathBuf],
+) -> Result<Vec<CompilerError>, String> {
+    let executable = config.executable.as_deref().unwrap_or("sail");
+    let output = Command::new(executable)
+        .arg("--just-check")
+        .args(&config.args)
+        .args(files)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("couldn't run `{}`: {}", executable, e))?;
+
+    let mut text = String::from_utf8_lossy(&output.stderr).into_owned();
+    text.push('\n');
+    text.push_str(&String::from_utf8_lossy(&output.stdout));
+
+    let errors = parse_output(&text, dir);
+    if errors.is_empty() && !output.status.success() {
+        return Err(format!(
+            "`{}` failed ({}) without any errors we understand:\n{}",
+            executable,
+            output.status,
+            text.trim()
+        ));
+    }
+    Ok(errors)
+}
+
+impl CompilerError {
+    /// Convert to an LSP diagnostic. The compiler's columns are in bytes so
+    /// the source is needed to convert them. If it isn't available they are
+    /// used as they are. The source may not be what the compiler saw, e.g. if
+    /// it has been edited, so columns inside characters are moved back to
+    /// the start of them.
+    pub fn to_diagnostic(&self, source: Option<&TextDocument>) -> Diagnostic {
+        let position = |(line, column): LineColumn| match source {
+            Some(source) => {
+                let start = source.offset_at(&Position::new(line, 0));
+                let end = source.offset_at(&Position::new(line + 1, 0));
+                let line_text = &source.text()[start..end];
+                let mut column = (column as usize).min(line_text.len());
+                while !line_text.is_char_boundary(column) {
+                    column -= 1;
+                }
+                source.position_at(start + column)
+            }
+            None => Position::new(line, column),
+        };
+        Diagnostic::new(
+            Range::new(position(self.start), position(self.end)),
+            Some(self.severity),
+            None,
+            Some("sail".to_string()),
+            self.message.clone(),
+            None,
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OUTPUT: &str = "\
+Type error:
+model/prelude.sail:12.4-10:
+12 |    foo(x)
+   |    ^----^
+   | Identifier foo is unbound
+
+Warning: Redundant case model/regs.sail:3.2-5.1:
+3  |  case _ => {
+   |  ^---------
+...
+5  | }
+   | ^
+   | This case is never used
+
+Error:
+/abs/insts.sail:1.0-1:
+1 |}
+  |^
+  | Expected a definition
+  | because of this
+";
+
+    #[test]
+    fn test_parse_output() {
+        let errors = parse_output(OUTPUT, Path::new("/ws"));
+        assert_eq!(
+            errors,
+            [
+                CompilerError {
+                    path: PathBuf::from("/ws/model/prelude.sail"),
+                    start: (11, 4),
+                    end: (11, 10),
+                    severity: DiagnosticSeverity::ERROR,
+                    message: "Identifier foo is unbound".to_string(),
+                },
+                CompilerError {
+                    path: PathBuf::from("/ws/model/regs.sail"),
+                    start: (2, 2),
+                    end: (4, 1),
+                    severity: DiagnosticSeverity::WARNING,
+                    message: "This case is never used".to_string(),
+                },
+                CompilerError {
+                    path: PathBuf::from("/abs/insts.sail"),
+                    start: (0, 0),
+                    end: (0, 1),
+                    severity: DiagnosticSeverity::ERROR,
+                    message: "Expected a definition\nbecause of this".to_string(),
+                },
+            ]
+        );
+
+        // Without any detail the heading is used.
+        let errors = parse_output("Syntax error:\na.sail:2.0-3:\n", Path::new("/ws"));
+        assert_eq!(errors[0].message, "Syntax error");
+    }
+
+    #[test]
+    fn test_to_diagnostic() {
+        use crate::text_document::PositionEncoding;
+
+        let source = TextDocument::new(
+            "let x = 1\nlet s = \"héllo\"\n".to_string(),
+            PositionEncoding::Utf16,
+        );
+        let error = |start, end| CompilerError {
+            path: PathBuf::from("/ws/a.sail"),
+            start,
+            end,
+            severity: DiagnosticSeverity::ERROR,
+            message: "Bad thing".to_string(),
+        };
+        let range = |start, end| error(start, end).to_diagnostic(Some(&source)).range;
+
+        // `é` is two bytes but one UTF-16 unit.
+        assert_eq!(
+            range((1, 9), (1, 14)),
+            Range::new(Position::new(1, 9), Position::new(1, 13))
+        );
+        // A column inside `é` is moved back to it.
+        assert_eq!(
+            range((1, 11), (1, 12)),
+            Range::new(Position::new(1, 10), Position::new(1, 11))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_stub() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("sail_stub_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stub = dir.join("sail");
+        std::fs::write(
+            &stub,
+            "#!/bin/sh\n\
+             echo \"$@\" > args.txt\n\
+             echo 'Type error:' >&2\n\
+             echo 'a.sail:1.0-3:' >&2\n\
+             echo '  | Bad thing' >&2\n\
+             exit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = CompilerConfig {
+            enabled: true,
+            executable: Some(stub.to_string_lossy().into_owned()),
+            args: vec!["-dno_cast".to_string()],
+        };
+        let errors = check(&config, &dir, &[dir.join("a.sail"), dir.join("b.sail")]).unwrap();
+        assert_eq!(
+            errors,
+            [CompilerError {
+                path: dir.join("a.sail"),
+                start: (0, 0),
+                end: (0, 3),
+                severity: DiagnosticSeverity::ERROR,
+                message: "Bad thing".to_string(),
+            }]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args.txt")).unwrap(),
+            format!(
+                "--just-check -dno_cast {} {}\n",
+                dir.join("a.sail").display(),
+                dir.join("b.sail").display()
+            )
+        );
+
+        // Failing without output we understand is an error.
+        std::fs::write(&stub, "#!/bin/sh\necho 'Segmentation fault' >&2\nexit 2\n").unwrap();
+        assert!(check(&config, &dir, &[])
+            .unwrap_err()
+            .contains("Segmentation fault"));
+
+        let config = CompilerConfig {
+            executable: Some(dir.join("missing").to_string_lossy().into_owned()),
+            ..config
+        };
+        assert!(check(&config, &dir, &[])
+            .unwrap_err()
+            .starts_with("couldn't run"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
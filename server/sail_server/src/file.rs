@@ -2,68 +2,243 @@ use tower_lsp::lsp_types::{
     Diagnostic, DiagnosticSeverity, Position, Range, TextDocumentContentChangeEvent,
 };
 
-use crate::{definitions, text_document::TextDocument};
+use crate::{
+    definitions,
+    text_document::{PositionEncoding, TextDocument},
+};
 use chumsky::Parser;
-use std::{cmp::Ordering, collections::HashMap};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
+#[derive(Clone)]
 pub struct File {
     // The source code.
     pub source: TextDocument,
 
-    // The parse result if any. If there isn't one then that is because
-    // of a parse error.
-    pub tokens: Option<Vec<(sail_parser::Token, sail_parser::Span)>>,
+    // The lexer output. The lexer skips characters it doesn't understand so
+    // this is available even if there are errors. They are shared with
+    // edited copies so that those can be relexed incrementally.
+    pub tokens: Arc<Vec<(sail_parser::Token, sail_parser::Span)>>,
+
+    // Errors from lexing, as (span, message).
+    lex_errors: Vec<(sail_parser::Span, String)>,
+
+    // Operator fixity declarations in this file, e.g. `infixl 5 <<`. These
+    // apply to the whole workspace.
+    pub fixities: Vec<(String, sail_parser::Fixity)>,
+
+    // The parsed AST. If there are syntax errors then this contains what
+    // could be parsed.
+    pub ast: sail_parser::SourceFile,
 
-    // Go-to definition locations extracted from the file.
-    pub definitions: HashMap<String, usize>,
+    // Errors from parsing, as (span, message).
+    parse_errors: Vec<(sail_parser::Span, String)>,
+
+    // Go-to definition locations extracted from the file. There can be
+    // several for each name, e.g. `val` and `function`, or scattered clauses.
+    pub definitions: HashMap<String, Vec<definitions::Definition>>,
 
     // Diagnostic errors from parsing.
     pub diagnostics: Vec<Diagnostic>,
+
+    // The source has been edited since it was analysed, so everything else
+    // is out of date.
+    pub dirty: bool,
+
+    // The part of the source that has changed since it was lexed.
+    edit: Option<sail_parser::Edit>,
+
+    // When the file was modified on disk before it was read, so that we can
+    // tell if it has changed since. See `files::modified_time()`.
+    pub modified: Option<u64>,
+}
+
+/// The results of analysing a file, without the source. This is what is
+/// saved in the index cache.
+#[derive(Serialize, Deserialize)]
+pub struct Analysis {
+    tokens: Vec<(sail_parser::Token, sail_parser::Span)>,
+    lex_errors: Vec<(sail_parser::Span, String)>,
+    fixities: Vec<(String, sail_parser::Fixity)>,
+    ast: sail_parser::SourceFile,
+    parse_errors: Vec<(sail_parser::Span, String)>,
 }
 
 impl File {
-    pub fn new(source: String) -> Self {
+    pub fn new(
+        source: String,
+        encoding: PositionEncoding,
+        fixities: &sail_parser::FixityTable,
+    ) -> Self {
+        let mut f = Self::lexed(source, encoding);
+        f.parse(fixities);
+        f
+    }
+
+    /// Lex a file without parsing it. This is used to find the fixities
+    /// before parsing, and `parse()` must be called before it is used.
+    pub fn lexed(source: String, encoding: PositionEncoding) -> Self {
+        let mut f = Self {
+            source: TextDocument::new(source, encoding),
+            tokens: Arc::default(),
+            lex_errors: Vec::new(),
+            fixities: Vec::new(),
+            ast: sail_parser::SourceFile::default(),
+            parse_errors: Vec::new(),
+            definitions: HashMap::new(),
+            diagnostics: Vec::new(),
+            dirty: false,
+            edit: None,
+            modified: None,
+        };
+        f.lex();
+        f
+    }
+
+    /// Restore a file from an earlier analysis of the same source.
+    pub fn from_analysis(source: String, encoding: PositionEncoding, analysis: Analysis) -> Self {
         let mut f = Self {
-            source: TextDocument::new(source),
-            tokens: None,
+            source: TextDocument::new(source, encoding),
+            tokens: Arc::new(analysis.tokens),
+            lex_errors: analysis.lex_errors,
+            fixities: analysis.fixities,
+            ast: analysis.ast,
+            parse_errors: analysis.parse_errors,
             definitions: HashMap::new(),
             diagnostics: Vec::new(),
+            dirty: false,
+            edit: None,
+            modified: None,
         };
-        f.parse();
+        f.update_definitions_and_diagnostics();
         f
     }
 
-    pub fn update(&mut self, changes: Vec<TextDocumentContentChangeEvent>) {
+    pub fn analysis(&self) -> Analysis {
+        Analysis {
+            tokens: self.tokens.to_vec(),
+            lex_errors: self.lex_errors.clone(),
+            fixities: self.fixities.clone(),
+            ast: self.ast.clone(),
+            parse_errors: self.parse_errors.clone(),
+        }
+    }
+
+    /// Apply edits to the source. This is cheap because the file isn't
+    /// analysed until `analyze()` is called.
+    pub fn edit(&mut self, changes: Vec<TextDocumentContentChangeEvent>) {
         for change in &changes {
+            let edit = match change.range {
+                Some(range) => {
+                    let start = self.source.offset_at(&range.start);
+                    sail_parser::Edit {
+                        start,
+                        old_end: self.source.offset_at(&range.end),
+                        new_end: start + change.text.len(),
+                    }
+                }
+                None => sail_parser::Edit {
+                    start: 0,
+                    old_end: self.source.text().len(),
+                    new_end: change.text.len(),
+                },
+            };
+            self.edit = Some(match self.edit {
+                Some(previous) => previous.then(edit),
+                None => edit,
+            });
             self.source.update(change);
         }
+        self.dirty = true;
+    }
 
-        self.parse();
+    /// Like `edit()` but returns an edited copy. Everything that would be
+    /// out of date is left empty rather than copied, except for the
+    /// fixities which apply to other files, and the tokens which are needed
+    /// to relex it.
+    pub fn edited(&self, changes: Vec<TextDocumentContentChangeEvent>) -> Self {
+        let mut file = Self {
+            source: self.source.clone(),
+            tokens: self.tokens.clone(),
+            lex_errors: self.lex_errors.clone(),
+            fixities: self.fixities.clone(),
+            ast: sail_parser::SourceFile::default(),
+            parse_errors: Vec::new(),
+            definitions: HashMap::new(),
+            diagnostics: Vec::new(),
+            dirty: true,
+            edit: self.edit,
+            modified: self.modified,
+        };
+        file.edit(changes);
+        file
     }
 
-    pub fn parse(&mut self) {
-        let text = self.source.text();
-        let result = sail_parser::lexer().parse(text);
-        self.tokens = result.output().cloned();
+    /// Lex and parse the file after it has been edited. Only the part that
+    /// was edited is lexed again.
+    pub fn analyze(&mut self, fixities: &sail_parser::FixityTable) {
+        match self.edit.take() {
+            Some(edit) => self.relex(edit),
+            None => self.lex(),
+        }
+        self.parse(fixities);
+        self.dirty = false;
+    }
 
+    fn relex(&mut self, edit: sail_parser::Edit) {
+        let relexed = sail_parser::relex(self.source.text(), &self.tokens, edit);
+        let mut lex_errors: Vec<_> = self
+            .lex_errors
+            .iter()
+            .filter(|(span, _)| span.start < relexed.start)
+            .cloned()
+            .collect();
+        lex_errors.extend(relexed.errors);
+        if let Some(reused_from) = relexed.reused_from {
+            lex_errors.extend(
+                self.lex_errors
+                    .iter()
+                    .filter(|(span, _)| span.start >= reused_from)
+                    .map(|(span, message)| (edit.shift(*span), message.clone())),
+            );
+        }
+        self.tokens = Arc::new(relexed.tokens);
+        self.lex_errors = lex_errors;
+        self.fixities = sail_parser::fixity_declarations(&self.tokens);
+    }
+
+    fn lex(&mut self) {
+        let (tokens, errors) = sail_parser::lexer()
+            .parse(self.source.text())
+            .into_output_errors();
+        self.tokens = Arc::new(tokens.unwrap_or_default());
+        self.lex_errors = errors
+            .iter()
+            .map(|error| (*error.span(), error.to_string()))
+            .collect();
+        self.fixities = sail_parser::fixity_declarations(&self.tokens);
+    }
+
+    /// Parse the tokens. This needs to be redone if the operator fixities
+    /// in the workspace change.
+    pub fn parse(&mut self, fixities: &sail_parser::FixityTable) {
+        let (ast, parse_errors) = sail_parser::parse(&self.tokens, fixities);
+        self.ast = ast;
+        self.parse_errors = parse_errors
+            .iter()
+            .map(|error| (*error.span(), error.to_string()))
+            .collect();
+        self.update_definitions_and_diagnostics();
+    }
+
+    fn update_definitions_and_diagnostics(&mut self) {
         let mut definitions = HashMap::with_capacity(self.definitions.len());
-        let mut diagnostics = Vec::with_capacity(self.diagnostics.len());
+        definitions::add_definitions(&self.ast, &mut definitions);
 
-        if let Some(tokens) = &self.tokens {
-            definitions::add_definitions(tokens, text, &mut definitions);
-        } else {
-            diagnostics.push(Diagnostic::new(
-                Range::new(Position::new(0, 0), Position::new(0, 0)),
-                Some(DiagnosticSeverity::ERROR),
-                None,
-                Some("Sail".to_string()),
-                "Error parsing file".to_string(),
-                None,
-                None,
-            ));
-        }
-        for error in result.errors().into_iter() {
-            let span = error.span();
+        let errors = self.lex_errors.iter().chain(&self.parse_errors).cloned();
+
+        let mut diagnostics = Vec::with_capacity(self.diagnostics.len());
+        for (span, message) in errors {
             let start = self.source.position_at(span.start);
             let end = self.source.position_at(span.end);
             diagnostics.push(Diagnostic::new(
@@ -71,7 +246,7 @@ impl File {
                 Some(DiagnosticSeverity::ERROR),
                 None,
                 Some("Sail".to_string()),
-                error.to_string(),
+                message,
                 None,
                 None,
             ));
@@ -85,7 +260,7 @@ impl File {
         // Convert the line/character to an offset.
         let offset = self.source.offset_at(&position);
         // Binary search for a token that contains the offset.
-        let tokens = self.tokens.as_ref()?;
+        let tokens = &self.tokens;
         let token = tokens.binary_search_by(|(_, span)| {
             if span.start <= offset && offset <= span.end {
                 Ordering::Equal
@@ -99,3 +274,93 @@ impl File {
         token.ok().map(|i| &tokens[i])
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_syntax_errors() {
+        let file = File::new(
+            "function foo(x) = {\n  let y = x + ;\n  y\n}\n\nfunction bar() = ` 1\n".to_string(),
+            PositionEncoding::default(),
+            &sail_parser::FixityTable::default(),
+        );
+        assert!(file.definitions.contains_key("foo"));
+        assert!(file.definitions.contains_key("bar"));
+        let ranges: Vec<_> = file.diagnostics.iter().map(|d| d.range).collect();
+        assert_eq!(
+            ranges,
+            [
+                Range::new(Position::new(5, 17), Position::new(5, 18)),
+                Range::new(Position::new(1, 14), Position::new(1, 15)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edit() {
+        let fixities = sail_parser::FixityTable::default();
+        let mut file = File::new(
+            "function foo() = 1\n".to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        file.edit(vec![TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(0, 9), Position::new(0, 12))),
+            range_length: None,
+            text: "bar".to_string(),
+        }]);
+
+        // Nothing is analysed until asked.
+        assert!(file.dirty);
+        assert_eq!(file.source.text(), "function bar() = 1\n");
+        assert!(file.definitions.contains_key("foo"));
+
+        file.analyze(&fixities);
+        assert!(!file.dirty);
+        assert!(file.definitions.contains_key("bar"));
+        assert!(!file.definitions.contains_key("foo"));
+    }
+
+    #[test]
+    fn test_relex() {
+        let fixities = sail_parser::FixityTable::default();
+        let file = File::new(
+            "register a : int
+/* ` */
+let b = ` 1
+let c = 2
+"
+            .to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let change = |line, start, end, text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(line, start),
+                Position::new(line, end),
+            )),
+            range_length: None,
+            text: text.to_string(),
+        };
+        // Several edits before it is analysed, including one that opens a
+        // string to the end of the file and one that closes it again.
+        let mut file = file.edited(vec![change(0, 9, 10, "abc"), change(2, 4, 5, "\"")]);
+        file = file.edited(vec![change(3, 9, 9, "\"")]);
+        file.analyze(&fixities);
+
+        let expected = File::new(
+            file.source.text().to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        assert_eq!(
+            file.source.text(),
+            "register abc : int\n/* ` */\nlet \" = ` 1\nlet c = 2\"\n"
+        );
+        assert_eq!(file.tokens, expected.tokens);
+        assert_eq!(file.diagnostics, expected.diagnostics);
+        assert!(file.definitions.contains_key("abc"));
+    }
+}
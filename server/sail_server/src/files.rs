@@ -1,74 +1,205 @@
 // Initial implementation will just use walkdir to re-read all the files
 // every 30 seconds.
 
+use crate::cache::{self, Cache};
 use crate::file::File;
+use crate::filter::Filter;
+use crate::text_document::PositionEncoding;
 use std::{
     collections::{HashMap, HashSet},
     fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::UNIX_EPOCH,
 };
 use tower_lsp::lsp_types::Url;
-use walkdir::WalkDir;
 
-#[derive(Default)]
+// Files are shared between snapshots of the state, and copied when they
+// are changed.
+#[derive(Clone, Default)]
 pub struct Files {
     folders: HashSet<Url>,
-    files: HashMap<Url, File>,
+    files: HashMap<Url, Arc<File>>,
 }
 
-pub fn scan_folders(folders: HashSet<Url>) -> HashMap<Url, File> {
-    let mut files = HashMap::new();
+/// Find all the Sail files in the folders that `filter` allows.
+pub fn find_files(folders: &HashSet<Url>, filter: &Filter) -> Vec<(Url, PathBuf)> {
+    folders
+        .iter()
+        .filter(|folder| folder.scheme() == "file")
+        .filter_map(|folder| folder.to_file_path().ok())
+        .flat_map(|path| filter.find_files(&path))
+        .filter_map(file_url)
+        .collect()
+}
 
-    for folder in folders {
-        if folder.scheme() != "file" {
-            continue;
-        }
-        if let Ok(path) = folder.to_file_path() {
-            for entry in WalkDir::new(path) {
-                match entry {
-                    Ok(entry) => {
-                        if entry.file_type().is_file()
-                            && entry.path().extension() == Some("sail".as_ref())
-                        {
-                            let path = entry.path();
-                            match fs::read_to_string(path) {
-                                Ok(source) => {
-                                    let file = File::new(source);
-                                    match path.to_str() {
-                                        Some(path_str) => {
-                                            let mut url = folder.clone();
-                                            dbg!(&url, &path_str);
-                                            // TODO: This is a hack to get around Windows paths and
-                                            // a bug in Url::set_path. https://github.com/servo/rust-url/issues/864
-                                            let mut path_windows = path_str.replace("\\", "/");
-                                            if !path_windows.starts_with('/') {
-                                                path_windows.insert(0, '/');
-                                            }
-                                            url.set_path(&path_windows);
-                                            files.insert(url, file);
-                                        }
-                                        None => {
-                                            eprintln!(
-                                                "Error converting path to string: {}",
-                                                path.display()
-                                            );
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Error reading file {}: {:?}", path.display(), e);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error scanning folder: {:?}", e);
+/// Like `find_files()` but only in `dir`, which is in one of the folders.
+pub fn find_files_in(folders: &HashSet<Url>, filter: &Filter, dir: &Path) -> Vec<(Url, PathBuf)> {
+    filter
+        .find_files_in(folders, dir)
+        .into_iter()
+        .filter_map(file_url)
+        .collect()
+}
+
+fn file_url(path: PathBuf) -> Option<(Url, PathBuf)> {
+    let Some(path_str) = path.to_str() else {
+        eprintln!("Error converting path to string: {}", path.display());
+        return None;
+    };
+    let mut url = Url::parse("file:///").unwrap();
+    // TODO: This is a hack to get around Windows paths and
+    // a bug in Url::set_path. https://github.com/servo/rust-url/issues/864
+    let mut path_windows = path_str.replace('\\', "/");
+    if !path_windows.starts_with('/') {
+        path_windows.insert(0, '/');
+    }
+    url.set_path(&path_windows);
+    Some((url, path))
+}
+
+/// When a file was last modified, in nanoseconds since the epoch.
+pub fn modified_time(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos() as u64)
+}
+
+/// Call `f` on each item using all the available cores. `progress` is
+/// called with the number of items done so far after each one.
+fn parallel_map<T: Send, R: Send>(
+    items: Vec<T>,
+    f: impl Fn(T) -> R + Sync,
+    progress: impl Fn(usize) + Sync,
+) -> Vec<R> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(items.len().max(1));
+    let items = Mutex::new(items.into_iter());
+    let done = AtomicUsize::new(0);
+
+    std::thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let Some(item) = items.lock().unwrap().next() else {
+                            break;
+                        };
+                        results.push(f(item));
+                        progress(done.fetch_add(1, Ordering::Relaxed) + 1);
                     }
-                }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    })
+}
+
+/// A file that has been read but not parsed yet.
+struct Unparsed {
+    url: Url,
+    path: PathBuf,
+    version: cache::Version,
+    file: File,
+    // The fixities it was parsed with, if it came from the cache.
+    parsed_with: Option<sail_parser::FixityTable>,
+}
+
+/// Read and analyse files using all the available cores, using the cache
+/// for files that haven't changed. The fixities are found from the files
+/// that `include` returns true for, added to `fixities`, before parsing so
+/// that files don't need to be parsed twice. Positions in the files use
+/// `encoding`. Returns the files and the fixities used. `progress` is
+/// called with the number of steps done so far, out of twice the number of
+/// files, as each file is read and then parsed.
+pub fn read_files(
+    files: Vec<(Url, PathBuf)>,
+    include: impl Fn(&Url) -> bool,
+    mut fixities: sail_parser::FixityTable,
+    encoding: PositionEncoding,
+    cache: Option<&Cache>,
+    progress: impl Fn(usize) + Sync,
+) -> (HashMap<Url, File>, sail_parser::FixityTable) {
+    let read = |(url, path): (Url, PathBuf)| {
+        let modified = modified_time(&path);
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Error reading file {}: {:?}", path.display(), e);
+                return None;
             }
-        }
+        };
+        let version = cache::Version::new(&path, &source);
+        let (mut file, parsed_with) = match cache.and_then(|cache| cache.load(&path, version)) {
+            Some(cached) => (
+                File::from_analysis(source, encoding, cached.analysis),
+                Some(cached.parsed_with),
+            ),
+            None => (File::lexed(source, encoding), None),
+        };
+        file.modified = modified;
+        Some(Unparsed {
+            url,
+            path,
+            version,
+            file,
+            parsed_with,
+        })
+    };
+    let total = files.len();
+    let mut unparsed = parallel_map(files, read, &progress)
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    // The same as `State::update_fixities()`.
+    unparsed.sort_by(|a, b| a.url.cmp(&b.url));
+    for unparsed in unparsed.iter().filter(|unparsed| include(&unparsed.url)) {
+        fixities.extend(unparsed.file.fixities.iter().cloned());
     }
 
-    files
+    let parse = |mut unparsed: Unparsed| {
+        if unparsed.parsed_with.as_ref() != Some(&fixities) {
+            unparsed.file.parse(&fixities);
+            if let Some(cache) = cache {
+                cache.store(
+                    &unparsed.path,
+                    unparsed.version,
+                    &fixities,
+                    unparsed.file.analysis(),
+                );
+            }
+        }
+        (unparsed.url, unparsed.file)
+    };
+    let files = parallel_map(unparsed, parse, |done| progress(total + done))
+        .into_iter()
+        .collect();
+    (files, fixities)
+}
+
+/// A short name for a file to show to the user. This is the path relative to
+/// the workspace folder that contains it, or the full URL if there isn't one.
+pub fn display_path(uri: &Url, folders: &HashSet<Url>) -> String {
+    let Ok(path) = uri.to_file_path() else {
+        return uri.to_string();
+    };
+    folders
+        .iter()
+        .filter_map(|folder| folder.to_file_path().ok())
+        .find_map(|folder| path.strip_prefix(folder).ok().map(|p| p.to_path_buf()))
+        .unwrap_or(path)
+        .display()
+        .to_string()
+        .replace('\\', "/")
 }
 
 impl Files {
@@ -76,27 +207,205 @@ impl Files {
         self.folders.insert(folder);
     }
 
+    /// Remove a folder and the files in it, unless they are also in another
+    /// folder.
     pub fn remove_folder(&mut self, folder: &Url) {
         self.folders.remove(folder);
+        let Ok(dir) = folder.to_file_path() else {
+            return;
+        };
+        let others = self
+            .folders
+            .iter()
+            .filter_map(|folder| folder.to_file_path().ok())
+            .collect::<Vec<_>>();
+        self.files.retain(|url, _| {
+            url.to_file_path().map_or(true, |path| {
+                !path.starts_with(&dir) || others.iter().any(|other| path.starts_with(other))
+            })
+        });
     }
 
     pub fn add_file(&mut self, url: Url, file: File) {
-        self.files.insert(url, file);
+        self.files.insert(url, Arc::new(file));
     }
 
     pub fn remove_file(&mut self, url: &Url) {
         self.files.remove(url);
     }
 
+    /// Whether we have any files in a directory. This is false for a file,
+    /// as it isn't in itself.
+    pub fn has_files_in(&self, dir: &Path) -> bool {
+        self.files.keys().any(|url| {
+            url.to_file_path()
+                .is_ok_and(|path| path != dir && path.starts_with(dir))
+        })
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&File> {
+        self.files.get(url).map(Arc::as_ref)
+    }
+
+    pub fn get_mut(&mut self, url: &Url) -> Option<&mut File> {
+        self.files.get_mut(url).map(Arc::make_mut)
+    }
+
     pub fn all_files(&self) -> impl Iterator<Item = (&Url, &File)> {
-        self.files.iter()
+        self.files.iter().map(|(url, file)| (url, file.as_ref()))
+    }
+
+    pub fn all_files_mut(&mut self) -> impl Iterator<Item = &mut File> {
+        self.files.values_mut().map(Arc::make_mut)
     }
 
-    pub fn update(&mut self, files: HashMap<Url, File>) {
-        self.files = files;
+    /// Add files from a scan. Files that were added while it was running
+    /// are kept because they may be newer.
+    pub fn add_scanned(&mut self, files: HashMap<Url, File>) {
+        for (url, file) in files {
+            self.files.entry(url).or_insert_with(|| Arc::new(file));
+        }
     }
 
     pub fn folders(&self) -> &HashSet<Url> {
         &self.folders
     }
+
+    /// Compare the files found on disk under `dir`, or everywhere if it is
+    /// `None`, with the ones we have. Returns the files that no longer exist
+    /// and the ones that are new or have been modified since they were read.
+    pub fn changes(
+        &self,
+        found: Vec<(Url, PathBuf)>,
+        dir: Option<&Path>,
+    ) -> (Vec<Url>, Vec<(Url, PathBuf)>) {
+        let found_urls = found.iter().map(|(url, _)| url).collect::<HashSet<_>>();
+        let removed = self
+            .files
+            .keys()
+            .filter(|url| !found_urls.contains(url))
+            .filter(|url| match dir {
+                Some(dir) => url.to_file_path().is_ok_and(|path| path.starts_with(dir)),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        let changed = found
+            .into_iter()
+            .filter(|(url, path)| match self.files.get(url) {
+                Some(file) => file.modified != modified_time(path),
+                None => true,
+            })
+            .collect();
+        (removed, changed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let dir = std::env::temp_dir().join(format!("sail_scan_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for (name, source) in [
+            ("a.sail", "register a : int"),
+            ("sub/b.sail", "register b : int"),
+            ("sub/c.sail", "register c : int"),
+            ("notes.txt", "register d : int"),
+        ] {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+
+        let folder = Url::from_directory_path(&dir).unwrap();
+        let found = find_files(&HashSet::from([folder]), &Filter::new(&Default::default()));
+        assert_eq!(found.len(), 3);
+
+        let calls = std::sync::Mutex::new(Vec::new());
+        let (files, _) = read_files(
+            found,
+            |_| true,
+            Default::default(),
+            Default::default(),
+            None,
+            |done| calls.lock().unwrap().push(done),
+        );
+        let mut names = files
+            .values()
+            .flat_map(|file| file.definitions.keys().cloned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, [1, 2, 3, 4, 5, 6]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes() {
+        let dir = std::env::temp_dir().join(format!("sail_changes_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a.sail", "sub/b.sail", "sub/c.sail"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let folder = Url::from_directory_path(&dir).unwrap();
+        let folders = HashSet::from([folder.clone()]);
+        let filter = Filter::new(&Default::default());
+        let found = find_files(&folders, &filter);
+        let (read, _) = read_files(
+            found,
+            |_| true,
+            Default::default(),
+            Default::default(),
+            None,
+            |_| {},
+        );
+        let mut files = Files::default();
+        files.add_folder(folder);
+        files.add_scanned(read);
+
+        let names = |urls: Vec<Url>| {
+            let mut names = urls
+                .iter()
+                .map(|url| display_path(url, &folders))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let (removed, changed) = files.changes(find_files(&folders, &filter), None);
+        assert!(removed.is_empty());
+        assert!(changed.is_empty());
+        assert!(files.has_files_in(&dir.join("sub")));
+        assert!(!files.has_files_in(&dir.join("sub/b.sail")));
+        assert!(!files.has_files_in(&dir.join("su")));
+
+        // Rename a folder and add a file to it.
+        std::fs::rename(dir.join("sub"), dir.join("moved")).unwrap();
+        std::fs::write(dir.join("moved/d.sail"), "").unwrap();
+        let old = dir.join("sub");
+        let (removed, changed) = files.changes(find_files_in(&folders, &filter, &old), Some(&old));
+        assert_eq!(names(removed), ["sub/b.sail", "sub/c.sail"]);
+        assert!(changed.is_empty());
+        let new = dir.join("moved");
+        let (removed, changed) = files.changes(find_files_in(&folders, &filter, &new), Some(&new));
+        assert!(removed.is_empty());
+        assert_eq!(
+            names(changed.into_iter().map(|(url, _)| url).collect()),
+            ["moved/b.sail", "moved/c.sail", "moved/d.sail"]
+        );
+
+        // Modified files are found by a full rescan.
+        let (a, _) = file_url(dir.join("a.sail")).unwrap();
+        files.get_mut(&a).unwrap().modified = Some(0);
+        let (removed, changed) = files.changes(find_files(&folders, &filter), None);
+        assert_eq!(names(removed), ["sub/b.sail", "sub/c.sail"]);
+        assert_eq!(changed.len(), 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,829 @@
+// Which files in the workspace are analysed. Files must match one of the
+// include globs and none of the exclude globs, must not be too big, and
+// must not be ignored by a `.gitignore` or `.ignore` file. Like git, this
+// also uses the ignore files in the repository above the workspace folder,
+// `.git/info/exclude` and the user's `core.excludesFile`. Globs are
+// relative to the workspace folder and always use `/`.
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tower_lsp::lsp_types::Url;
+use walkdir::{DirEntry, WalkDir};
+
+/// Which files to analyse.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScanConfig {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // In bytes. Bigger files are probably generated.
+    pub max_file_size: u64,
+    // Skip files ignored by `.gitignore` and `.ignore` files.
+    pub use_ignore_files: bool,
+    // How often to look for changes that the client didn't tell us about, in
+    // seconds. 0 turns it off.
+    pub rescan_interval: u64,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            include: vec!["**/*.sail".to_string()],
+            exclude: Vec::new(),
+            max_file_size: 2 * 1024 * 1024,
+            use_ignore_files: true,
+            rescan_interval: 60,
+        }
+    }
+}
+
+/// A glob pattern. `*` and `?` match anything except `/`, `**` matches any
+/// number of directories, `[a-z]` and `[!a-z]` match one of a set of
+/// characters, and `\` escapes the next character.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    parts: Vec<GlobPart>,
+}
+
+#[derive(Clone, Debug)]
+enum GlobPart {
+    Char(char),
+    // `?`
+    Any,
+    // `[a-z]` or `[!a-z]`.
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    // `*`
+    Star,
+    // `**/`, which matches zero or more directories.
+    Dirs,
+    // `**` not followed by `/`, which matches anything.
+    AnyPath,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.chars().collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        let mut i = 0;
+        while i < pattern.len() {
+            let (part, len) = match &pattern[i..] {
+                ['*', '*', '/', ..] => (GlobPart::Dirs, 3),
+                ['*', '*', ..] => (GlobPart::AnyPath, 2),
+                ['*', ..] => (GlobPart::Star, 1),
+                ['?', ..] => (GlobPart::Any, 1),
+                ['[', rest @ ..] => match parse_class(rest) {
+                    Some((part, len)) => (part, len + 1),
+                    // Not a class, e.g. `[` on its own.
+                    None => (GlobPart::Char('['), 1),
+                },
+                ['\\', c, ..] => (GlobPart::Char(*c), 2),
+                [c, ..] => (GlobPart::Char(*c), 1),
+                [] => unreachable!(),
+            };
+            parts.push(part);
+            i += len;
+        }
+        Self { parts }
+    }
+
+    /// Match a relative path that uses `/`. This follows every way that the
+    /// pattern could match at once, so it takes time proportional to the
+    /// length of the pattern times the length of the path.
+    pub fn matches(&self, path: &str) -> bool {
+        // Which parts we could be at. The last one means the whole pattern
+        // has matched.
+        let mut states = vec![false; self.parts.len() + 1];
+        states[0] = true;
+        self.skip_empty(&mut states, true);
+        for c in path.chars() {
+            let mut next = vec![false; states.len()];
+            for (i, part) in self.parts.iter().enumerate() {
+                if !states[i] {
+                    continue;
+                }
+                match part {
+                    GlobPart::Char(expected) => next[i + 1] |= c == *expected,
+                    GlobPart::Any => next[i + 1] |= c != '/',
+                    GlobPart::Class { ranges, negated } => {
+                        let found = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                        next[i + 1] |= c != '/' && found != *negated;
+                    }
+                    GlobPart::Star => next[i] |= c != '/',
+                    GlobPart::Dirs => {
+                        next[i] = true;
+                        next[i + 1] |= c == '/';
+                    }
+                    GlobPart::AnyPath => next[i] = true,
+                }
+            }
+            if !next.contains(&true) {
+                return false;
+            }
+            states = next;
+            self.skip_empty(&mut states, c == '/');
+        }
+        states[self.parts.len()]
+    }
+
+    /// Add the states after parts that can match nothing. `**/` can only
+    /// match nothing at the start of a path component, otherwise `**/b`
+    /// would match `ab`.
+    fn skip_empty(&self, states: &mut [bool], component_start: bool) {
+        for (i, part) in self.parts.iter().enumerate() {
+            let empty = match part {
+                GlobPart::Star | GlobPart::AnyPath => true,
+                GlobPart::Dirs => component_start,
+                _ => false,
+            };
+            if states[i] && empty {
+                states[i + 1] = true;
+            }
+        }
+    }
+}
+
+/// Parse a character class like `a-z]` (after the `[`), returning it and its
+/// length, or `None` if there isn't a closing `]`.
+fn parse_class(pattern: &[char]) -> Option<(GlobPart, usize)> {
+    let (negated, class_start) = match pattern {
+        ['!' | '^', ..] => (true, 1),
+        _ => (false, 0),
+    };
+    let class = &pattern[class_start..];
+    // `]` is part of the class if it is first.
+    let end = 1 + class.get(1..)?.iter().position(|&c| c == ']')?;
+    let class = &class[..end];
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            ranges.push((class[i], class[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((class[i], class[i]));
+            i += 1;
+        }
+    }
+    Some((GlobPart::Class { ranges, negated }, class_start + end + 1))
+}
+
+struct IgnoreRule {
+    glob: Glob,
+    // `!pattern` un-ignores files.
+    negated: bool,
+    // `pattern/` only matches directories.
+    dir_only: bool,
+}
+
+/// The rules from the `.gitignore` and `.ignore` files in a directory.
+struct IgnoreFile {
+    // The directory relative to the workspace folder, e.g. `model/`.
+    prefix: String,
+    // For directories above the workspace folder, the path from them to the
+    // folder, e.g. `sail/`. Otherwise this is empty.
+    above: String,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    /// Read the ignore files in `dir`, if there are any. Rules in `.ignore`
+    /// come last so they take precedence.
+    fn load(dir: &Path, prefix: String) -> Option<Self> {
+        Self::read(&[dir.join(".gitignore"), dir.join(".ignore")], prefix)
+    }
+
+    fn read(paths: &[PathBuf], prefix: String) -> Option<Self> {
+        let rules = paths
+            .iter()
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .flat_map(|text| parse_ignore_rules(&text))
+            .collect::<Vec<_>>();
+        (!rules.is_empty()).then_some(Self {
+            prefix,
+            above: String::new(),
+            rules,
+        })
+    }
+
+    /// Whether the path (relative to the workspace folder) is ignored, or
+    /// `None` if no rule matches it.
+    fn ignored(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let path = path.strip_prefix(&self.prefix)?;
+        let path = match self.above.as_str() {
+            "" => Cow::Borrowed(path),
+            above => Cow::Owned(format!("{}{}", above, path)),
+        };
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.glob.matches(&path))
+            .map(|rule| !rule.negated)
+    }
+}
+
+fn parse_ignore_rules(text: &str) -> Vec<IgnoreRule> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = trim_unescaped_spaces(line);
+            // `\!` and `\#` start patterns with `!` and `#`. The glob
+            // treats the `\` as an escape.
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            if line.is_empty() {
+                return None;
+            }
+            // Patterns with a `/` are relative to the directory, and others
+            // match a name anywhere below it.
+            let glob = match line.strip_prefix('/') {
+                Some(line) => Glob::new(line),
+                None if line.contains('/') => Glob::new(line),
+                None => Glob::new(&format!("**/{}", line)),
+            };
+            Some(IgnoreRule {
+                glob,
+                negated,
+                dir_only,
+            })
+        })
+        .collect()
+}
+
+/// Remove trailing spaces from a line of an ignore file, unless they are
+/// escaped with `\`.
+fn trim_unescaped_spaces(line: &str) -> &str {
+    let mut line = line.trim_end_matches(['\r', '\n']);
+    while let Some(rest) = line.strip_suffix(' ') {
+        let backslashes = rest.len() - rest.trim_end_matches('\\').len();
+        if backslashes % 2 == 1 {
+            break;
+        }
+        line = rest;
+    }
+    line
+}
+
+/// The ignore files that apply to a workspace folder from outside it: the
+/// user's `core.excludesFile`, then the repository's `.git/info/exclude`,
+/// then the ignore files in the directories between the repository and the
+/// folder. These are in order of precedence, lowest first.
+fn outer_ignore_files(root: &Path) -> Vec<IgnoreFile> {
+    let Some(repo) = root.ancestors().find(|dir| dir.join(".git").exists()) else {
+        return Vec::new();
+    };
+    let git_dir = git_dir(repo);
+    let excludes = excludes_file(git_dir.as_deref())
+        .into_iter()
+        .chain(git_dir.map(|git_dir| git_dir.join("info/exclude")));
+    // Each ignore file and the directory its rules are relative to.
+    let mut ignore_files = excludes
+        .filter_map(|path| Some((repo, IgnoreFile::read(&[path], String::new())?)))
+        .collect::<Vec<_>>();
+    let dirs = root
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(repo))
+        .collect::<Vec<_>>();
+    for dir in dirs.into_iter().rev() {
+        ignore_files.extend(IgnoreFile::load(dir, String::new()).map(|file| (dir, file)));
+    }
+    ignore_files
+        .into_iter()
+        .filter_map(|(dir, mut ignore_file)| {
+            let above = relative_path(dir, root)?;
+            if !above.is_empty() {
+                ignore_file.above = above + "/";
+            }
+            Some(ignore_file)
+        })
+        .collect()
+}
+
+/// The git directory of a repository. This is usually `.git`, but in
+/// worktrees and submodules `.git` is a file that says where it is.
+fn git_dir(repo: &Path) -> Option<PathBuf> {
+    let dot_git = repo.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let text = fs::read_to_string(&dot_git).ok()?;
+    let path = text.trim().strip_prefix("gitdir:")?.trim();
+    Some(repo.join(path))
+}
+
+/// The user's own ignore file. This is `core.excludesFile` from the git
+/// config, or `~/.config/git/ignore` if that isn't set.
+fn excludes_file(git_dir: Option<&Path>) -> Option<PathBuf> {
+    let env = |name| std::env::var_os(name).map(PathBuf::from);
+    let home = env("HOME").or_else(|| env("USERPROFILE"));
+    let config_home =
+        env("XDG_CONFIG_HOME").or_else(|| home.as_ref().map(|home| home.join(".config")));
+    // Later config files take precedence, so look at them first.
+    let configs = [
+        config_home.as_ref().map(|dir| dir.join("git/config")),
+        home.as_ref().map(|home| home.join(".gitconfig")),
+        git_dir.map(|git_dir| git_dir.join("config")),
+    ];
+    let configured = configs
+        .into_iter()
+        .rev()
+        .flatten()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|text| config_excludes_file(&text));
+    match configured {
+        Some(path) => match path.strip_prefix("~/") {
+            Some(path) => Some(home?.join(path)),
+            None => Some(PathBuf::from(path)),
+        },
+        None => Some(config_home?.join("git/ignore")),
+    }
+}
+
+/// Find `core.excludesFile` in a git config file.
+fn config_excludes_file(text: &str) -> Option<String> {
+    let mut in_core = false;
+    let mut found = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[') {
+            let name = section.split([']', ' ', '\t']).next().unwrap_or("");
+            in_core = name.eq_ignore_ascii_case("core");
+        } else if let Some((key, value)) = line.split_once('=').filter(|_| in_core) {
+            if key.trim().eq_ignore_ascii_case("excludesFile") {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                found = Some(value.to_string());
+            }
+        }
+    }
+    found
+}
+
+/// A path relative to `root` using `/`, or `None` if it isn't inside it.
+fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let names = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(names.join("/"))
+}
+
+/// Decides which files are analysed. See `ScanConfig`.
+pub struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    max_file_size: u64,
+    use_ignore_files: bool,
+}
+
+impl Filter {
+    pub fn new(config: &ScanConfig) -> Self {
+        Self {
+            include: config.include.iter().map(|glob| Glob::new(glob)).collect(),
+            exclude: config.exclude.iter().map(|glob| Glob::new(glob)).collect(),
+            max_file_size: config.max_file_size,
+            use_ignore_files: config.use_ignore_files,
+        }
+    }
+
+    /// Whether a file or directory is excluded by the globs or the ignore
+    /// files in the directories above it. Later ignore files are deeper so
+    /// they take precedence.
+    fn excluded(&self, path: &str, is_dir: bool, ignore_files: &[IgnoreFile]) -> bool {
+        self.excluded_by_name(path)
+            || ignore_files
+                .iter()
+                .rev()
+                .find_map(|ignore_file| ignore_file.ignored(path, is_dir))
+                .unwrap_or(false)
+    }
+
+    /// Whether a path is excluded by the globs or is a `.git` directory,
+    /// which can be decided without reading anything.
+    fn excluded_by_name(&self, path: &str) -> bool {
+        path.rsplit('/').next() == Some(".git")
+            || self.exclude.iter().any(|glob| glob.matches(path))
+    }
+
+    fn included_file(&self, path: &str, size: u64) -> bool {
+        size <= self.max_file_size && self.include.iter().any(|glob| glob.matches(path))
+    }
+
+    /// The ignore files from outside a workspace folder, if they are used.
+    fn outer_ignore_files(&self, root: &Path) -> Vec<IgnoreFile> {
+        if self.use_ignore_files {
+            outer_ignore_files(root)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Check that a path and the directories above it aren't excluded, and
+    /// return the ignore files in those directories.
+    fn check_parents(&self, root: &Path, path: &str, is_dir: bool) -> Option<Vec<IgnoreFile>> {
+        let mut ignore_files = self.outer_ignore_files(root);
+        if path.is_empty() {
+            return Some(ignore_files);
+        }
+        let mut prefix = String::new();
+        let names = path.split('/').collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            if self.use_ignore_files {
+                ignore_files.extend(IgnoreFile::load(&root.join(&prefix), prefix.clone()));
+            }
+            prefix.push_str(name);
+            if self.excluded(&prefix, is_dir || i + 1 < names.len(), &ignore_files) {
+                return None;
+            }
+            prefix.push('/');
+        }
+        Some(ignore_files)
+    }
+
+    /// Find the files to analyse in a workspace folder.
+    pub fn find_files(&self, root: &Path) -> Vec<PathBuf> {
+        let entries = self.walk(root, root, self.outer_ignore_files(root));
+        self.files(root, entries)
+    }
+
+    /// Find the files to analyse in a directory in one of the workspace
+    /// folders. This is for directories that the client says have changed.
+    pub fn find_files_in(&self, folders: &HashSet<Url>, dir: &Path) -> Vec<PathBuf> {
+        match self.walk_in(folders, dir) {
+            Some((root, entries)) => self.files(&root, entries),
+            None => Vec::new(),
+        }
+    }
+
+    /// Find the directories in a workspace folder that aren't excluded,
+    /// including the folder itself. These are the ones to watch.
+    pub fn find_dirs(&self, root: &Path) -> Vec<PathBuf> {
+        dirs(self.walk(root, root, self.outer_ignore_files(root)))
+    }
+
+    /// Like `find_dirs()` but only in `dir`, which is in one of the
+    /// workspace folders.
+    pub fn find_dirs_in(&self, folders: &HashSet<Url>, dir: &Path) -> Vec<PathBuf> {
+        match self.walk_in(folders, dir) {
+            Some((_, entries)) => dirs(entries),
+            None => Vec::new(),
+        }
+    }
+
+    /// Walk a directory in one of the workspace folders, returning the
+    /// folder and the entries, or `None` if the directory is excluded.
+    fn walk_in(&self, folders: &HashSet<Url>, dir: &Path) -> Option<(PathBuf, Vec<DirEntry>)> {
+        let (root, relative) = in_folder(folders, dir).filter(|_| dir.is_dir())?;
+        let ignore_files = self.check_parents(&root, &relative, true)?;
+        let entries = self.walk(&root, dir, ignore_files);
+        Some((root, entries))
+    }
+
+    /// Find the files and directories in `dir`, which is in the workspace
+    /// folder `root` and isn't excluded. `ignore_files` are the ones above
+    /// it.
+    fn walk(&self, root: &Path, dir: &Path, mut ignore_files: Vec<IgnoreFile>) -> Vec<DirEntry> {
+        let entries = WalkDir::new(dir).into_iter().filter_entry(|entry| {
+            let Some(path) = relative_path(root, entry.path()) else {
+                return false;
+            };
+            ignore_files.retain(|ignore_file| path.starts_with(&ignore_file.prefix));
+            let is_dir = entry.file_type().is_dir();
+            if entry.depth() > 0 && self.excluded(&path, is_dir, &ignore_files) {
+                return false;
+            }
+            if is_dir && self.use_ignore_files {
+                let prefix = if path.is_empty() { path } else { path + "/" };
+                ignore_files.extend(IgnoreFile::load(entry.path(), prefix));
+            }
+            true
+        });
+        entries
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    eprintln!("Error scanning folder: {:?}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The files to analyse from the entries in the workspace folder `root`.
+    fn files(&self, root: &Path, entries: Vec<DirEntry>) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in entries {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let size = entry.metadata().map_or(0, |metadata| metadata.len());
+            if relative_path(root, entry.path()).is_some_and(|path| self.included_file(&path, size))
+            {
+                files.push(entry.into_path());
+            }
+        }
+        files
+    }
+
+    /// Whether a path in one of the workspace folders could be included,
+    /// going by its name and the names of the directories above it. This
+    /// doesn't touch the file system, so it is used to drop changes in
+    /// excluded directories quickly.
+    pub fn may_include(&self, folders: &HashSet<Url>, path: &Path) -> bool {
+        let Some((_, relative)) = in_folder(folders, path) else {
+            return false;
+        };
+        let mut end = 0;
+        for name in relative.split('/') {
+            end += name.len();
+            if self.excluded_by_name(&relative[..end]) {
+                return false;
+            }
+            end += 1;
+        }
+        true
+    }
+
+    /// Whether a single file in one of the workspace folders should be
+    /// analysed. This is for files that the client says have changed.
+    pub fn includes(&self, folders: &HashSet<Url>, path: &Path) -> bool {
+        let Some((root, relative)) = in_folder(folders, path) else {
+            return false;
+        };
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        self.included_file(&relative, metadata.len())
+            && self.check_parents(&root, &relative, false).is_some()
+    }
+}
+
+fn dirs(entries: Vec<DirEntry>) -> Vec<PathBuf> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.file_type().is_dir())
+        .map(DirEntry::into_path)
+        .collect()
+}
+
+/// The workspace folder that contains a path, and the path relative to it.
+fn in_folder(folders: &HashSet<Url>, path: &Path) -> Option<(PathBuf, String)> {
+    folders
+        .iter()
+        .filter_map(|folder| folder.to_file_path().ok())
+        .filter_map(|root| relative_path(&root, path).map(|relative| (root, relative)))
+        .max_by_key(|(root, _)| root.as_os_str().len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let cases = [
+            ("*.sail", "a.sail", true),
+            ("*.sail", "model/a.sail", false),
+            ("**/*.sail", "a.sail", true),
+            ("**/*.sail", "model/sub/a.sail", true),
+            ("**/*.sail", "a.sail.bak", false),
+            ("build/**", "build/a/b.sail", true),
+            ("build/**", "build", false),
+            ("a/**/b", "a/b", true),
+            ("a/**/b", "a/x/y/b", true),
+            ("a/**/b", "ab", false),
+            ("a/**/b", "a/xb", false),
+            ("**/tmp.sail", "mytmp.sail", false),
+            ("**/tmp.sail", "model/mytmp.sail", false),
+            ("**/tmp.sail", "model/tmp.sail", true),
+            ("**/build", "rebuild", false),
+            ("**/build", "a/build", true),
+            ("**/**/b", "a/b", true),
+            ("a?c", "abc", true),
+            ("a?c", "a/c", false),
+            ("[a-c]x", "bx", true),
+            ("[!a-c]x", "bx", false),
+            ("[]]", "]", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("[", "[", true),
+            ("[!]", "[!]", true),
+            ("a\\", "a\\", true),
+            (
+                "*a*a*a*b",
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                false,
+            ),
+            (
+                "**/**/**/**/x",
+                "a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/y",
+                false,
+            ),
+            ("*a*b", "xaxxb", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                Glob::new(pattern).matches(path),
+                expected,
+                "{} {}",
+                pattern,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_files() {
+        let dir = std::env::temp_dir().join(format!("sail_filter_{}", std::process::id()));
+        for name in [
+            "a.sail",
+            "big.sail",
+            "notes.txt",
+            "build/gen.sail",
+            "model/b.sail",
+            "model/tmp.sail",
+            "model/keep/tmp.sail",
+            "vendor/c.sail",
+            "vendor/lib/d.sail",
+        ] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(
+                path,
+                if name == "big.sail" {
+                    "x".repeat(100)
+                } else {
+                    "".to_string()
+                },
+            )
+            .unwrap();
+        }
+        fs::write(dir.join(".gitignore"), "# Build output\nbuild/\n").unwrap();
+        fs::write(dir.join("model/.gitignore"), "tmp.sail\n").unwrap();
+        fs::write(dir.join("model/.ignore"), "!keep/tmp.sail\n").unwrap();
+
+        let config = ScanConfig {
+            exclude: vec!["vendor/*.sail".to_string()],
+            max_file_size: 50,
+            ..Default::default()
+        };
+        let filter = Filter::new(&config);
+        let mut found = filter
+            .find_files(&dir)
+            .iter()
+            .map(|path| relative_path(&dir, path).unwrap())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "a.sail",
+                "model/b.sail",
+                "model/keep/tmp.sail",
+                "vendor/lib/d.sail"
+            ]
+        );
+
+        // Single files are filtered the same way.
+        let folders = HashSet::from([Url::from_directory_path(&dir).unwrap()]);
+        for name in ["a.sail", "model/keep/tmp.sail"] {
+            assert!(filter.includes(&folders, &dir.join(name)), "{}", name);
+        }
+        for name in [
+            "big.sail",
+            "notes.txt",
+            "build/gen.sail",
+            "model/tmp.sail",
+            "vendor/c.sail",
+            "missing.sail",
+        ] {
+            assert!(!filter.includes(&folders, &dir.join(name)), "{}", name);
+        }
+        assert!(!filter.includes(&folders, Path::new("/elsewhere/a.sail")));
+
+        // Paths can be ruled out by name alone.
+        assert!(filter.may_include(&folders, &dir.join("model/tmp.sail")));
+        assert!(filter.may_include(&folders, &dir.join("vendor")));
+        assert!(!filter.may_include(&folders, &dir.join("vendor/c.sail")));
+        assert!(!filter.may_include(&folders, &dir.join(".git/objects/ab")));
+        assert!(!filter.may_include(&folders, Path::new("/elsewhere/a.sail")));
+
+        // And directories.
+        let mut found = filter.find_files_in(&folders, &dir.join("model"));
+        found.sort();
+        assert_eq!(
+            found,
+            [dir.join("model/b.sail"), dir.join("model/keep/tmp.sail")]
+        );
+        assert!(filter
+            .find_files_in(&folders, &dir.join("build"))
+            .is_empty());
+
+        // The directories to watch skip excluded ones.
+        let mut found = filter
+            .find_dirs(&dir)
+            .iter()
+            .map(|path| relative_path(&dir, path).unwrap())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["", "model", "model/keep", "vendor", "vendor/lib"]);
+        assert_eq!(
+            filter.find_dirs_in(&folders, &dir.join("model")),
+            [dir.join("model"), dir.join("model/keep")]
+        );
+        assert!(filter.find_dirs_in(&folders, &dir.join("build")).is_empty());
+
+        // Ignore files can be turned off.
+        let config = ScanConfig {
+            use_ignore_files: false,
+            ..config
+        };
+        assert_eq!(Filter::new(&config).find_files(&dir).len(), 6);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let ignore_file = IgnoreFile {
+            prefix: String::new(),
+            above: String::new(),
+            rules: parse_ignore_rules(
+                "# comment\n\\#hash\n\\!bang\nspace\\ \ntrimmed  \n!trimmed\r\n",
+            ),
+        };
+        assert_eq!(ignore_file.ignored("#hash", false), Some(true));
+        assert_eq!(ignore_file.ignored("!bang", false), Some(true));
+        assert_eq!(ignore_file.ignored("space ", false), Some(true));
+        assert_eq!(ignore_file.ignored("space", false), None);
+        assert_eq!(ignore_file.ignored("trimmed", false), Some(false));
+        assert_eq!(ignore_file.ignored("# comment", false), None);
+    }
+
+    #[test]
+    fn test_outer_ignore_files() {
+        let dir = std::env::temp_dir().join(format!("sail_filter_outer_{}", std::process::id()));
+        let root = dir.join("models/ws");
+        for name in [
+            "a.sail",
+            "excluded.sail",
+            "ignored.sail",
+            "sub/ignored.sail",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::create_dir_all(dir.join(".git/info")).unwrap();
+        fs::write(dir.join(".git/info/exclude"), "/models/ws/excluded.sail\n").unwrap();
+        fs::write(dir.join("models/.gitignore"), "ws/ignored.sail\n").unwrap();
+
+        let filter = Filter::new(&ScanConfig::default());
+        let mut found = filter
+            .find_files(&root)
+            .iter()
+            .map(|path| relative_path(&root, path).unwrap())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, ["a.sail", "sub/ignored.sail"]);
+
+        let folders = HashSet::from([Url::from_directory_path(&root).unwrap()]);
+        assert!(filter.includes(&folders, &root.join("a.sail")));
+        assert!(!filter.includes(&folders, &root.join("excluded.sail")));
+        assert!(!filter.includes(&folders, &root.join("ignored.sail")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_excludes_file() {
+        let config = "[user]\n\texcludesFile = no\n[core]\n\tpager = less\n\tExcludesFile = \"~/.gitignore global\"\n";
+        assert_eq!(
+            config_excludes_file(config).as_deref(),
+            Some("~/.gitignore global")
+        );
+        assert_eq!(config_excludes_file("[core]\n\tpager = less\n"), None);
+    }
+}
@@ -0,0 +1,132 @@
+/// Score for each matched character.
+const MATCH: i32 = 16;
+/// Bonus for matching the start of a word, e.g. `x` in `read_xreg` or `R`
+/// in `readReg`.
+const BOUNDARY: i32 = 24;
+/// Bonus for matching the character after the previous match.
+const CONSECUTIVE: i32 = 28;
+/// Bonus for matching the case exactly.
+const CASE: i32 = 1;
+/// Penalty for each skipped character.
+const GAP: i32 = 1;
+/// Bonus if the query matches the whole name, ignoring case.
+const EXACT: i32 = 1000;
+
+fn is_boundary(candidate: &[char], j: usize) -> bool {
+    if j == 0 {
+        return true;
+    }
+    let (prev, c) = (candidate[j - 1], candidate[j]);
+    (!prev.is_alphanumeric() && c.is_alphanumeric())
+        || (prev.is_lowercase() && c.is_uppercase())
+        || (!prev.is_ascii_digit() && c.is_ascii_digit())
+}
+
+/// Fuzzy match `query` against `candidate`. The query characters must
+/// appear in order (ignoring case) but not necessarily together. Returns a
+/// score where higher is better, or `None` if it doesn't match.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let query = query.chars().collect::<Vec<_>>();
+    let candidate = candidate.chars().collect::<Vec<_>>();
+    if query.is_empty() {
+        return Some(0);
+    }
+    if query.len() > candidate.len() {
+        return None;
+    }
+
+    // `prev[j]` is the best score for the query so far with its last
+    // character matched at `candidate[j]`.
+    let mut prev: Vec<Option<i32>> = vec![None; candidate.len()];
+    for (i, &q) in query.iter().enumerate() {
+        let mut current = vec![None; candidate.len()];
+        // The best score from an earlier match that isn't adjacent,
+        // including the gap penalty.
+        let mut gapped: Option<i32> = None;
+        for (j, &c) in candidate.iter().enumerate() {
+            if j >= 2 {
+                gapped = gapped.max(prev[j - 2]).map(|score| score - GAP);
+            }
+            if !q.to_lowercase().eq(c.to_lowercase()) {
+                continue;
+            }
+            let mut score = MATCH;
+            if is_boundary(&candidate, j) {
+                score += BOUNDARY;
+            }
+            if q == c {
+                score += CASE;
+            }
+            let before = if i == 0 {
+                // Penalise skipping characters at the start, but not much
+                // because matching a later word is common.
+                Some(-(j.min(8) as i32) * GAP)
+            } else {
+                let consecutive = j
+                    .checked_sub(1)
+                    .and_then(|k| prev[k])
+                    .map(|score| score + CONSECUTIVE);
+                consecutive.max(gapped)
+            };
+            current[j] = before.map(|before| score + before);
+        }
+        prev = current;
+    }
+
+    let best = prev.into_iter().flatten().max()?;
+    // Prefer shorter names, because there is less that doesn't match. This
+    // is only a small penalty so that e.g. long prefix matches still rank
+    // highly.
+    let mut score = best - (candidate.len() - query.len()) as i32 / 4;
+    if query.len() == candidate.len() {
+        score += EXACT;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rank<'a>(query: &str, candidates: &[&'a str]) -> Vec<&'a str> {
+        let mut matches = candidates
+            .iter()
+            .filter_map(|c| fuzzy_score(query, c).map(|score| (score, *c)))
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        matches.into_iter().map(|(_, c)| c).collect()
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("abc", "xaxbxc").is_some());
+        assert_eq!(fuzzy_score("abc", "acb"), None);
+        assert_eq!(fuzzy_score("abcd", "abc"), None);
+        assert_eq!(fuzzy_score("", "abc"), Some(0));
+
+        assert_eq!(
+            rank(
+                "xreg",
+                &[
+                    "rX_bits",
+                    "read_xreg",
+                    "xreg_write_callback",
+                    "xregs",
+                    "xReg",
+                    "x_r_e_g"
+                ]
+            ),
+            [
+                "xReg",
+                "xregs",
+                "xreg_write_callback",
+                "read_xreg",
+                "x_r_e_g"
+            ]
+        );
+        assert_eq!(
+            rank("wxr", &["wX_bits", "write_xreg", "wxr", "wide_x_reg"]),
+            ["wxr", "write_xreg", "wide_x_reg"]
+        );
+    }
+}
@@ -1 +1,259 @@
+use std::collections::HashSet;
 
+use sail_parser::Def;
+use tower_lsp::lsp_types::Url;
+
+use crate::definitions::DefinitionKind;
+use crate::file::File;
+use crate::files::display_path;
+
+/// Find the comment directly above `offset`, which should be the start of a
+/// definition. This is either a series of `//` lines or a `/* */` block. The
+/// comment markers are removed.
+pub fn doc_comment(text: &str, offset: usize) -> Option<String> {
+    let before = &text[..offset];
+    // The text before the definition on the same line should be blank.
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    if !before[line_start..].trim().is_empty() {
+        return None;
+    }
+    // Remove the line break before the definition. The line before that
+    // must not be blank.
+    let before = &before[..line_start];
+    let before = before.strip_suffix('\n').unwrap_or(before);
+    let before = before.strip_suffix('\r').unwrap_or(before);
+    if before.rsplit('\n').next()?.trim().is_empty() {
+        return None;
+    }
+    let before = before.trim_end();
+
+    if before.ends_with("*/") {
+        let start = before.rfind("/*")?;
+        let body = &before[start + 2..];
+        let body = body.strip_suffix("*/")?;
+        let lines = body.lines().map(|line| {
+            // Remove the ` * ` that often starts each line.
+            let line = line.trim();
+            let line = line.strip_prefix('*').unwrap_or(line);
+            line.strip_prefix(' ').unwrap_or(line).trim_end()
+        });
+        return non_empty(lines);
+    }
+
+    let lines = before
+        .lines()
+        .rev()
+        .map(str::trim)
+        .take_while(|line| line.starts_with("//"))
+        .collect::<Vec<_>>();
+    non_empty(lines.into_iter().rev().map(|line| {
+        let line = line.trim_start_matches('/');
+        line.strip_prefix(' ').unwrap_or(line).trim_end()
+    }))
+}
+
+/// Join lines, ignoring blank lines at the start and end.
+fn non_empty<'a>(lines: impl Iterator<Item = &'a str>) -> Option<String> {
+    let lines = lines.collect::<Vec<_>>();
+    let start = lines.iter().position(|line| !line.is_empty())?;
+    let end = lines.iter().rposition(|line| !line.is_empty())?;
+    Some(lines[start..=end].join("\n"))
+}
+
+/// Hover text for `name`. This shows each `val` with that name, along with
+/// its doc comment and the file it is in. If there aren't any vals then it
+/// shows the other definitions instead, e.g. types and registers. For an
+/// overload it shows the `overload` followed by the vals of its members.
+pub fn hover<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    folders: &HashSet<Url>,
+    name: &str,
+) -> Option<String> {
+    let files = files.collect::<Vec<_>>();
+
+    let mut names = vec![name];
+    for (_, file) in &files {
+        for (def, _) in &file.ast.defs {
+            if let Def::Overload(overload) = def {
+                if overload.name.0 == name {
+                    for (member, _) in &overload.members {
+                        if !names.contains(&member.as_str()) {
+                            names.push(member);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Each candidate is (rank, uri, code, offset, file). Vals are ranked by
+    // the position of their name in `names`, after any overloads.
+    let mut vals = Vec::new();
+    let mut others = Vec::new();
+
+    for &(uri, file) in &files {
+        let text = file.source.text();
+        for (def, span) in &file.ast.defs {
+            if let Def::Val(val) = def {
+                if let Some(i) = names.iter().position(|name| *name == val.name.0) {
+                    vals.push((
+                        i + 1,
+                        uri,
+                        text[span.start..span.end].to_string(),
+                        span.start,
+                        file,
+                    ));
+                }
+            }
+        }
+        for definition in file.definitions.get(name).into_iter().flatten() {
+            if definition.kind == DefinitionKind::Val {
+                continue;
+            }
+            // Show the whole line that the definition is on.
+            let offset = definition.span.start;
+            let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+            let end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+            others.push((
+                definition.kind,
+                (0, uri, text[start..end].trim().to_string(), start, file),
+            ));
+        }
+    }
+
+    // Only show the main definitions, e.g. `scattered function execute`
+    // rather than every `function clause execute`.
+    let main_kind = others.iter().map(|(kind, _)| *kind).min();
+    let others = others
+        .into_iter()
+        .filter(|(kind, _)| Some(*kind) == main_kind)
+        .map(|(_, other)| other)
+        .collect::<Vec<_>>();
+
+    let mut candidates = if vals.is_empty() {
+        others
+    } else if main_kind == Some(DefinitionKind::Overload) {
+        others.into_iter().chain(vals).collect()
+    } else {
+        vals
+    };
+    if candidates.is_empty() {
+        return None;
+    }
+    candidates.sort_by(|a, b| (a.0, a.1, a.3).cmp(&(b.0, b.1, b.3)));
+
+    let sections = candidates
+        .into_iter()
+        .map(|(_, uri, code, offset, file)| {
+            let mut section = format!("```sail\n{}\n```\n", code);
+            if let Some(comment) = doc_comment(file.source.text(), offset) {
+                section.push('\n');
+                section.push_str(&comment);
+                section.push('\n');
+            }
+            section.push_str(&format!("\n*{}*\n", display_path(uri, folders)));
+            section
+        })
+        .collect::<Vec<_>>();
+
+    Some(sections.join("\n---\n\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+
+    #[test]
+    fn test_doc_comment() {
+        let text = "// Not this.\n\n// Add two numbers.\n// Really.\nval add : int\n";
+        let offset = text.find("val").unwrap();
+        assert_eq!(
+            doc_comment(text, offset).as_deref(),
+            Some("Add two numbers.\nReally.")
+        );
+
+        let text = "/*\n * Add two\n * numbers.\n */\nval add : int\n";
+        let offset = text.find("val").unwrap();
+        assert_eq!(
+            doc_comment(text, offset).as_deref(),
+            Some("Add two\nnumbers.")
+        );
+
+        let text = "/* Add. */\r\n  val add : int\n";
+        let offset = text.find("val").unwrap();
+        assert_eq!(doc_comment(text, offset).as_deref(), Some("Add."));
+
+        // Blank lines separate comments from definitions.
+        let text = "// Something else.\n\nval add : int\n";
+        let offset = text.find("val").unwrap();
+        assert_eq!(doc_comment(text, offset), None);
+
+        assert_eq!(doc_comment("val add : int", 0), None);
+    }
+
+    #[test]
+    fn test_hover() {
+        let fixities = sail_parser::FixityTable::default();
+        let rv32 = File::new(
+            "// Read a register.\nval rX : regidx -> bits(32)\nfunction rX(r) = 0x00000000\n"
+                .to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let rv64 = File::new(
+            "val rX : regidx -> bits(64)\nregister PC : bits(64)\n".to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let folder = Url::parse("file:///ws/").unwrap();
+        let rv32_uri = Url::parse("file:///ws/rv32/regs.sail").unwrap();
+        let rv64_uri = Url::parse("file:///ws/rv64/regs.sail").unwrap();
+        let files = [(&rv64_uri, &rv64), (&rv32_uri, &rv32)];
+        let folders = HashSet::from([folder]);
+
+        assert_eq!(
+            hover(files.into_iter(), &folders, "rX").unwrap(),
+            "```sail\nval rX : regidx -> bits(32)\n```\n\nRead a register.\n\n*rv32/regs.sail*\n\
+             \n---\n\n\
+             ```sail\nval rX : regidx -> bits(64)\n```\n\n*rv64/regs.sail*\n"
+        );
+
+        assert_eq!(
+            hover(files.into_iter(), &folders, "PC").unwrap(),
+            "```sail\nregister PC : bits(64)\n```\n\n*rv64/regs.sail*\n"
+        );
+
+        assert_eq!(hover(files.into_iter(), &folders, "nothing"), None);
+    }
+
+    #[test]
+    fn test_hover_overload() {
+        let fixities = sail_parser::FixityTable::default();
+        let overloads = File::new(
+            "overload to_str = {to_str_int, to_str_bits}\n".to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let vals = File::new(
+            "val to_str_bits : bits(8) -> string\n// An integer.\nval to_str_int : int -> string\n"
+                .to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let folder = Url::parse("file:///ws/").unwrap();
+        let overloads_uri = Url::parse("file:///ws/overloads.sail").unwrap();
+        let vals_uri = Url::parse("file:///ws/vals.sail").unwrap();
+        let files = [(&vals_uri, &vals), (&overloads_uri, &overloads)];
+        let folders = HashSet::from([folder]);
+
+        assert_eq!(
+            hover(files.into_iter(), &folders, "to_str").unwrap(),
+            "```sail\noverload to_str = {to_str_int, to_str_bits}\n```\n\n*overloads.sail*\n\
+             \n---\n\n\
+             ```sail\nval to_str_int : int -> string\n```\n\nAn integer.\n\n*vals.sail*\n\
+             \n---\n\n\
+             ```sail\nval to_str_bits : bits(8) -> string\n```\n\n*vals.sail*\n"
+        );
+    }
+}
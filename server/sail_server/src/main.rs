@@ -1,59 +1,556 @@
 use file::File;
+use serde::Deserialize;
+use serde_json::Value;
+use state::{SharedState, State};
 use std::cmp::Reverse;
-use std::collections::hash_map::HashMap;
-use tokio::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use text_document::PositionEncoding;
+use tokio::task::JoinHandle;
 use tower_lsp::jsonrpc::Result;
+use tower_lsp::lsp_types::notification::Progress;
+use tower_lsp::lsp_types::request::WorkDoneProgressCreate;
 use tower_lsp::lsp_types::{
-    CompletionOptions, CompletionParams, CompletionResponse, DidChangeConfigurationParams,
-    DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
+    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic,
+    DidChangeConfigurationParams, DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
     DidChangeWatchedFilesRegistrationOptions, DidChangeWorkspaceFoldersParams,
     DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
+    DocumentSymbolParams, DocumentSymbolResponse, ExecuteCommandOptions, ExecuteCommandParams,
     FileSystemWatcher, GlobPattern, GotoDefinitionParams, GotoDefinitionResponse, Hover,
-    HoverParams, HoverProviderCapability, InitializeParams, InitializeResult, InitializedParams,
-    Location, MessageType, OneOf, Range, Registration, ServerCapabilities, SignatureHelp,
-    SignatureHelpOptions, SignatureHelpParams, TextDocumentSyncCapability, TextDocumentSyncKind,
-    Url, WatchKind, WorkDoneProgressOptions, WorkspaceFoldersServerCapabilities,
-    WorkspaceServerCapabilities,
+    HoverContents, HoverParams, HoverProviderCapability, InitializeParams, InitializeResult,
+    InitializedParams, Location, MarkupContent, MarkupKind, MessageActionItem, MessageType, OneOf,
+    PrepareRenameResponse, Range, ReferenceParams, Registration, RenameOptions, RenameParams,
+    ServerCapabilities, SignatureHelp, SignatureHelpOptions, SignatureHelpParams,
+    SymbolInformation, TextDocumentPositionParams, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url, WatchKind, WorkDoneProgressOptions, WorkspaceEdit,
+    WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities, WorkspaceSymbolParams,
+};
+use tower_lsp::lsp_types::{
+    NumberOrString, ProgressParams, ProgressParamsValue, WorkDoneProgress, WorkDoneProgressBegin,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressReport,
 };
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
 mod text_document;
 
+mod cache;
 mod completion;
 mod definitions;
 mod diagnostics;
 mod file;
 mod files;
+mod filter;
+mod fuzzy;
 mod hover;
+mod project;
+mod references;
+mod rename;
+mod rope;
 mod signature;
+mod state;
+mod symbols;
+mod watcher;
 
-#[derive(Default)]
-struct State {
-    disk_files: files::Files,
-    open_files: HashMap<Url, File>,
+/// The client's initialization options.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct InitializationOptions {
+    #[serde(flatten)]
+    project: project::ProjectConfig,
+    compiler: diagnostics::CompilerConfig,
+    cache: cache::CacheConfig,
+    scan: filter::ScanConfig,
 }
 
-impl State {
-    /// Get all the files, ignoring files on disk that are also open.
-    fn all_files(&self) -> impl Iterator<Item = (&Url, &File)> {
-        self.open_files.iter().chain(
-            self.disk_files
-                .all_files()
-                .filter(|(uri, _)| !self.open_files.contains_key(uri)),
-        )
-    }
+/// The command to switch the active target. With no arguments the user is
+/// asked to pick one.
+const SET_CONFIGURATION_COMMAND: &str = "sail.setConfiguration";
+
+/// Parameters for the `sail/setConfiguration` request.
+#[derive(Deserialize)]
+struct SetConfigurationParams {
+    name: String,
 }
 
+/// How long to wait after a document is changed before analysing it, so
+/// that we don't analyse on every keystroke.
+const ANALYSIS_DELAY: Duration = Duration::from_millis(200);
+
+/// How long to collect file changes from our own watcher before handling
+/// them, because there are often lots at once, e.g. when switching branches.
+const WATCH_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
 struct Backend {
-    state: Mutex<State>,
+    state: Arc<SharedState>,
     client: Client,
+    // The pending analysis for each open document. This is replaced (and
+    // the old one cancelled) when the document changes.
+    analysis: Arc<std::sync::Mutex<HashMap<Url, JoinHandle<()>>>>,
+    // Our own file watcher, if the client can't watch files.
+    watcher: Arc<std::sync::Mutex<Option<watcher::Watcher>>>,
 }
 
 impl Backend {
     pub fn new_with_client(client: Client) -> Self {
         Self {
-            state: Mutex::new(State::default()),
+            state: Arc::default(),
             client,
+            analysis: Arc::default(),
+            watcher: Arc::default(),
+        }
+    }
+
+    /// Get a snapshot of the state for a request. Edited files are analysed
+    /// first, so that the request sees the latest text. This never waits for
+    /// other changes to the state.
+    async fn state_for_request(&self) -> Arc<State> {
+        let snapshot = self.state.snapshot();
+        if !snapshot.open_files.values().any(|file| file.dirty) {
+            return snapshot;
+        }
+        // Share the results unless something else is changing the state, in
+        // which case they are only used for this request.
+        match self.state.try_write() {
+            Some(mut state) => {
+                if state.analyze_edited_files() {
+                    self.publish_open_diagnostics(&state).await;
+                }
+                state.commit()
+            }
+            None => {
+                let mut state = State::clone(&snapshot);
+                state.analyze_edited_files();
+                Arc::new(state)
+            }
+        }
+    }
+
+    /// Publish the diagnostics for a file, unless it has been edited since
+    /// they were found.
+    async fn publish_diagnostics(&self, state: &State, uri: &Url) {
+        if state.open_files.get(uri).is_some_and(|file| file.dirty) {
+            return;
+        }
+        self.client
+            .publish_diagnostics(
+                uri.clone(),
+                state.diagnostics(uri),
+                state.versions.get(uri).copied(),
+            )
+            .await;
+    }
+
+    async fn publish_open_diagnostics(&self, state: &State) {
+        for uri in state.open_files.keys() {
+            self.publish_diagnostics(state, uri).await;
+        }
+    }
+
+    /// Analyse a document in the background after a short delay. This
+    /// cancels any pending analysis of an older version.
+    fn schedule_analysis(&self, uri: Url, version: i32) {
+        let backend = self.clone();
+        let task_uri = uri.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(ANALYSIS_DELAY).await;
+            backend.analyze(&task_uri, version).await;
+        });
+        if let Some(old) = self.analysis.lock().unwrap().insert(uri, task) {
+            old.abort();
+        }
+    }
+
+    fn cancel_analysis(&self, uri: &Url) {
+        if let Some(task) = self.analysis.lock().unwrap().remove(uri) {
+            task.abort();
+        }
+    }
+
+    /// Analyse a version of a document without blocking anything else, then
+    /// publish the results if it is still the latest version.
+    async fn analyze(&self, uri: &Url, version: i32) {
+        // The copy shares the tokens so only the edited part is relexed.
+        let (mut file, fixities) = {
+            let state = self.state.snapshot();
+            if state.versions.get(uri) != Some(&version) {
+                return;
+            }
+            match state.open_files.get(uri) {
+                Some(file) if file.dirty => (File::clone(file), state.fixities.clone()),
+                // It was analysed for a request already.
+                Some(_) => {
+                    self.publish_diagnostics(&state, uri).await;
+                    return;
+                }
+                None => return,
+            }
+        };
+
+        let analysed = {
+            let fixities = fixities.clone();
+            tokio::task::spawn_blocking(move || {
+                file.analyze(&fixities);
+                file
+            })
+            .await
+        };
+        let Ok(mut file) = analysed else {
+            return;
+        };
+
+        let mut state = self.state.write().await;
+        if state.versions.get(uri) != Some(&version)
+            || !state.open_files.get(uri).is_some_and(|file| file.dirty)
+        {
+            return;
+        }
+        if state.fixities != fixities {
+            file.parse(&state.fixities);
+        }
+        state.open_files.insert(uri.clone(), Arc::new(file));
+
+        if state.update_fixities_of([uri]) {
+            self.publish_open_diagnostics(&state).await;
+        } else {
+            self.publish_diagnostics(&state, uri).await;
+        }
+    }
+
+    /// Handle `sail/setConfiguration`, which switches the active target.
+    async fn set_configuration(&self, params: SetConfigurationParams) -> Result<()> {
+        self.set_active_target(&params.name)
+            .await
+            .map_err(request_error)
+    }
+
+    /// Switch the active target, which changes the files that names are
+    /// resolved against.
+    async fn set_active_target(&self, name: &str) -> std::result::Result<(), String> {
+        let mut state = self.state.write().await;
+        Arc::make_mut(&mut state.project).set_active(name)?;
+        // Keep it if the project is reloaded.
+        state.project_config.active_target = Some(name.to_string());
+
+        self.client
+            .log_message(MessageType::INFO, format!("active target: {}", name))
+            .await;
+
+        // The fixities may be different so this can change the diagnostics.
+        state.update_fixities();
+        self.publish_open_diagnostics(&state).await;
+        Ok(())
+    }
+
+    /// Run the Sail compiler on the active target, if it is enabled, and
+    /// publish its errors.
+    async fn run_compiler(&self) {
+        let (config, dir, files) = {
+            let state = self.state.snapshot();
+            if !state.compiler_config.enabled {
+                return;
+            }
+            let Some(target) = state.project.active_target() else {
+                self.client
+                    .log_message(
+                        MessageType::WARNING,
+                        "not running the Sail compiler because there is no target",
+                    )
+                    .await;
+                return;
+            };
+            let mut folders = state.disk_files.folders().iter().collect::<Vec<_>>();
+            folders.sort();
+            let Some(dir) = folders
+                .first()
+                .and_then(|folder| folder.to_file_path().ok())
+            else {
+                return;
+            };
+            (state.compiler_config.clone(), dir, target.files.clone())
+        };
+
+        // Don't block changes to the state while the compiler runs.
+        let result =
+            tokio::task::spawn_blocking(move || diagnostics::check(&config, &dir, &files)).await;
+        let errors = match result {
+            Ok(Ok(errors)) => errors,
+            Ok(Err(e)) => {
+                self.client.log_message(MessageType::ERROR, e).await;
+                return;
+            }
+            Err(e) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("compiler task failed: {}", e))
+                    .await;
+                return;
+            }
+        };
+
+        let mut state = self.state.write().await;
+        let mut compiler_diagnostics: HashMap<Url, Vec<Diagnostic>> = HashMap::new();
+        for error in errors {
+            let Ok(uri) = Url::from_file_path(&error.path) else {
+                continue;
+            };
+            let source = state
+                .open_files
+                .get(&uri)
+                .map(Arc::as_ref)
+                .or_else(|| state.disk_files.get(&uri))
+                .map(|file| &file.source);
+            let diagnostic = error.to_diagnostic(source);
+            compiler_diagnostics
+                .entry(uri)
+                .or_default()
+                .push(diagnostic);
+        }
+
+        // Clear errors that have been fixed too.
+        let old = std::mem::replace(
+            &mut state.compiler_diagnostics,
+            Arc::new(compiler_diagnostics),
+        );
+        let mut uris = old
+            .keys()
+            .cloned()
+            .chain(state.compiler_diagnostics.keys().cloned())
+            .collect::<Vec<_>>();
+        uris.sort();
+        uris.dedup();
+        for uri in uris {
+            self.publish_diagnostics(&state, &uri).await;
+        }
+    }
+
+    /// Read all the files in the workspace, reporting progress to the client.
+    /// Requests made before this finishes only see the open files.
+    async fn scan_workspace(&self) {
+        let snapshot = self.state.snapshot();
+        let filter = filter::Filter::new(&snapshot.scan_config);
+        let files = files::find_files(snapshot.disk_files.folders(), &filter);
+        let total = files.len();
+        let progress = self.begin_progress(snapshot.work_done_progress).await;
+
+        // Report progress each time another percent is done.
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let scan_snapshot = snapshot.clone();
+        let scan = tokio::task::spawn_blocking(move || {
+            let cache = cache::Cache::from_config(&scan_snapshot.cache_config);
+            // Only do this once, as rescans use the cache too.
+            if let Some(cache) = &cache {
+                cache.remove_old_versions();
+            }
+            let include = |uri: &Url| scan_snapshot.project.contains(uri);
+            let fixities = sail_parser::FixityTable::default();
+            let encoding = scan_snapshot.position_encoding;
+            // Each file is read and then parsed.
+            let steps = 2 * total;
+            files::read_files(files, include, fixities, encoding, cache.as_ref(), |done| {
+                if done * 100 / steps != (done - 1) * 100 / steps {
+                    let _ = sender.send(done);
+                }
+            })
+        });
+        while let Some(done) = receiver.recv().await {
+            if let Some(token) = &progress {
+                self.report_progress(
+                    token,
+                    WorkDoneProgress::Report(WorkDoneProgressReport {
+                        cancellable: Some(false),
+                        message: Some(format!("Indexing {}/{} files", done / 2, total)),
+                        percentage: Some((done * 50 / total) as u32),
+                    }),
+                )
+                .await;
+            }
+        }
+        let (files, fixities) = match scan.await {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("scan failed: {}", e))
+                    .await;
+                (HashMap::new(), snapshot.fixities.clone())
+            }
+        };
+
+        let mut state = self.state.write().await;
+        let read = files.len();
+        if state.add_scanned(files, fixities) {
+            self.publish_open_diagnostics(&state).await;
+        }
+        drop(state);
+
+        if let Some(token) = &progress {
+            self.report_progress(
+                token,
+                WorkDoneProgress::End(WorkDoneProgressEnd {
+                    message: Some(format!("Indexed {} files", read)),
+                }),
+            )
+            .await;
+        }
+        self.client
+            .log_message(MessageType::INFO, format!("indexed {} files", read))
+            .await;
+    }
+
+    /// Find files that have been added, changed or removed under `dir`, or
+    /// anywhere in the workspace, and update them. This catches changes that
+    /// the client doesn't tell us about, e.g. files in a renamed folder.
+    async fn rescan(&self, dir: Option<PathBuf>) {
+        let snapshot = self.state.snapshot();
+        let rescan = tokio::task::spawn_blocking(move || {
+            let filter = filter::Filter::new(&snapshot.scan_config);
+            let folders = snapshot.disk_files.folders();
+            let found = match &dir {
+                Some(dir) => files::find_files_in(folders, &filter, dir),
+                None => files::find_files(folders, &filter),
+            };
+            let (removed, changed) = snapshot.disk_files.changes(found, dir.as_deref());
+            let cache = cache::Cache::from_config(&snapshot.cache_config);
+            let include = |uri: &Url| snapshot.project.contains(uri);
+            let fixities = snapshot.fixities.clone();
+            let encoding = snapshot.position_encoding;
+            let (files, fixities) =
+                files::read_files(changed, include, fixities, encoding, cache.as_ref(), |_| {});
+            (removed, files, fixities)
+        });
+        let (removed, files, fixities) = match rescan.await {
+            Ok(rescanned) => rescanned,
+            Err(e) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("rescan failed: {}", e))
+                    .await;
+                return;
+            }
+        };
+        if removed.is_empty() && files.is_empty() {
+            return;
+        }
+
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!(
+                    "rescan: {} files changed, {} removed",
+                    files.len(),
+                    removed.len()
+                ),
+            )
+            .await;
+        let mut state = self.state.write().await;
+        if state.update_disk_files(files, fixities, &removed) {
+            self.publish_open_diagnostics(&state).await;
+        }
+    }
+
+    /// Watch the workspace folders ourselves, for clients that can't. The
+    /// changes are handled like ones from the client.
+    async fn start_watcher(&self) {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = match watcher::Watcher::new(move |events| {
+            let _ = sender.send(events);
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                self.client
+                    .log_message(
+                        MessageType::ERROR,
+                        format!("couldn't start file watcher: {}", e),
+                    )
+                    .await;
+                return;
+            }
+        };
+        let snapshot = self.state.snapshot();
+        let filter = filter::Filter::new(&snapshot.scan_config);
+        let errors = watcher.set_folders(snapshot.disk_files.folders(), &filter);
+        *self.watcher.lock().unwrap() = Some(watcher);
+        self.log_messages(
+            errors
+                .into_iter()
+                .map(|e| (MessageType::ERROR, e))
+                .collect(),
+        )
+        .await;
+        self.client
+            .log_message(MessageType::INFO, "watching files")
+            .await;
+
+        let backend = self.clone();
+        tokio::spawn(async move {
+            while let Some(mut changes) = receiver.recv().await {
+                tokio::time::sleep(WATCH_DELAY).await;
+                while let Ok(more) = receiver.try_recv() {
+                    changes.extend(more);
+                }
+                // Only the last change to each file matters.
+                let mut seen = HashSet::new();
+                changes.reverse();
+                changes.retain(|change| seen.insert(change.uri.clone()));
+                changes.reverse();
+                backend
+                    .did_change_watched_files(DidChangeWatchedFilesParams { changes })
+                    .await;
+            }
+        });
+    }
+
+    /// Rescan the whole workspace every so often, in case the client doesn't
+    /// tell us about some changes.
+    async fn rescan_periodically(&self) {
+        loop {
+            let interval = self.state.snapshot().scan_config.rescan_interval;
+            if interval == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            self.rescan(None).await;
+        }
+    }
+
+    /// Start reporting progress for the workspace scan, if the client
+    /// supports it.
+    async fn begin_progress(&self, supported: bool) -> Option<NumberOrString> {
+        if !supported {
+            return None;
+        }
+        let token = NumberOrString::String("sail/indexing".to_string());
+        self.client
+            .send_request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                token: token.clone(),
+            })
+            .await
+            .ok()?;
+        self.report_progress(
+            &token,
+            WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: "Sail".to_string(),
+                cancellable: Some(false),
+                message: Some("Indexing files".to_string()),
+                percentage: Some(0),
+            }),
+        )
+        .await;
+        Some(token)
+    }
+
+    async fn report_progress(&self, token: &NumberOrString, progress: WorkDoneProgress) {
+        self.client
+            .send_notification::<Progress>(ProgressParams {
+                token: token.clone(),
+                value: ProgressParamsValue::WorkDone(progress),
+            })
+            .await;
+    }
+
+    async fn log_messages(&self, messages: Vec<(MessageType, String)>) {
+        for (typ, message) in messages {
+            self.client.log_message(typ, message).await;
         }
     }
 }
@@ -65,27 +562,75 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "server initialized")
             .await;
 
-        let mut state = self.state.lock().await;
+        let mut state = self.state.write().await;
         if let Some(workspace_folders) = params.workspace_folders {
             for folder in workspace_folders {
-                state.disk_files.add_folder(folder.uri);
+                Arc::make_mut(&mut state.disk_files).add_folder(folder.uri);
             }
         }
 
-        let folders = state.disk_files.folders().clone();
-        state.disk_files.update(files::scan_folders(folders));
+        if let Some(options) = params.initialization_options {
+            match serde_json::from_value::<InitializationOptions>(options) {
+                Ok(options) => {
+                    state.project_config = options.project;
+                    state.compiler_config = options.compiler;
+                    state.cache_config = options.cache;
+                    state.scan_config = options.scan;
+                }
+                Err(e) => {
+                    self.client
+                        .log_message(
+                            MessageType::ERROR,
+                            format!("invalid initialization options: {}", e),
+                        )
+                        .await;
+                }
+            }
+        }
+        let messages = state.load_project();
+        self.log_messages(messages).await;
+
+        // The workspace is scanned after initialization.
+        state.work_done_progress = params
+            .capabilities
+            .window
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        state.client_watches_files = params
+            .capabilities
+            .workspace
+            .and_then(|workspace| workspace.did_change_watched_files)
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
+        state.position_encoding = PositionEncoding::negotiate(
+            params
+                .capabilities
+                .general
+                .and_then(|general| general.position_encodings)
+                .as_deref(),
+        );
 
         Ok(InitializeResult {
             server_info: None,
             capabilities: ServerCapabilities {
+                position_encoding: Some(state.position_encoding.kind()),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::INCREMENTAL,
                 )),
                 definition_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(false),
+                    },
+                })),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
                 completion_provider: Some(CompletionOptions {
                     resolve_provider: Some(false),
-                    trigger_characters: Some(vec![" ".to_string()]),
+                    trigger_characters: Some(vec![".".to_string()]),
                     work_done_progress_options: WorkDoneProgressOptions {
                         work_done_progress: Some(false),
                     },
@@ -93,8 +638,14 @@ impl LanguageServer for Backend {
                     completion_item: None,
                 }),
                 signature_help_provider: Some(SignatureHelpOptions {
-                    trigger_characters: Some(vec![" ,".to_string()]),
-                    retrigger_characters: Some(vec![" ,".to_string()]),
+                    trigger_characters: Some(vec!["(".to_string(), ",".to_string()]),
+                    retrigger_characters: Some(vec![",".to_string()]),
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(false),
+                    },
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![SET_CONFIGURATION_COMMAND.to_string()],
                     work_done_progress_options: WorkDoneProgressOptions {
                         work_done_progress: Some(false),
                     },
@@ -116,26 +667,46 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "server initialized")
             .await;
 
-        // Technically we should check if the client capabilities support this
-        // but I can't be bothered.
+        let backend = self.clone();
+        tokio::spawn(async move {
+            backend.scan_workspace().await;
+            backend.rescan_periodically().await;
+        });
 
-        // TODO: This is kind of broken. If you rename a folder that contains some of
-        // these files then you won't get a notification for them. The easiest
-        // solution is to watch all files.
+        if !self.state.snapshot().client_watches_files {
+            self.start_watcher().await;
+            return;
+        }
 
+        // Watch the files that can be included. Excluded ones are filtered
+        // out when they change.
+        let mut watchers = self
+            .state
+            .snapshot()
+            .scan_config
+            .include
+            .iter()
+            .cloned()
+            .chain(["**/*sail_project".to_string()])
+            .map(|glob| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(glob),
+                kind: Some(WatchKind::all()),
+            })
+            .collect::<Vec<_>>();
+        // Clients don't send events for the files in a folder when it is
+        // renamed or deleted, so watch folders too.
+        watchers.push(FileSystemWatcher {
+            glob_pattern: GlobPattern::String("**/*".to_string()),
+            kind: Some(WatchKind::Create | WatchKind::Delete),
+        });
         let result = self
             .client
             .register_capability(vec![Registration {
                 id: "sail_watch_files_id".to_string(),
                 method: "workspace/didChangeWatchedFiles".to_string(),
                 register_options: Some(
-                    serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
-                        watchers: vec![FileSystemWatcher {
-                            glob_pattern: GlobPattern::String("**/*.sail".to_string()),
-                            kind: Some(WatchKind::all()),
-                        }],
-                    })
-                    .unwrap(),
+                    serde_json::to_value(DidChangeWatchedFilesRegistrationOptions { watchers })
+                        .unwrap(),
                 ),
             }])
             .await;
@@ -153,6 +724,7 @@ impl LanguageServer for Backend {
                         format!("error registering file watcher: {:?}", e),
                     )
                     .await;
+                self.start_watcher().await;
             }
         }
     }
@@ -166,13 +738,43 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "workspace folders changed")
             .await;
 
-        let mut state = self.state.lock().await;
+        let mut state = self.state.write().await;
 
+        for folder in params.event.removed.iter() {
+            Arc::make_mut(&mut state.disk_files).remove_folder(&folder.uri);
+        }
         for folder in params.event.added.iter() {
-            state.disk_files.add_folder(folder.uri.clone());
+            Arc::make_mut(&mut state.disk_files).add_folder(folder.uri.clone());
         }
-        for folder in params.event.removed.iter() {
-            state.disk_files.remove_folder(&folder.uri);
+        let messages = state.load_project();
+        self.log_messages(messages).await;
+        if state.update_fixities() {
+            self.publish_open_diagnostics(&state).await;
+        }
+        let errors = self
+            .watcher
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|watcher| {
+                let filter = filter::Filter::new(&state.scan_config);
+                watcher.set_folders(state.disk_files.folders(), &filter)
+            })
+            .unwrap_or_default();
+        drop(state);
+        self.log_messages(
+            errors
+                .into_iter()
+                .map(|e| (MessageType::ERROR, e))
+                .collect(),
+        )
+        .await;
+
+        // Only the new folders need to be scanned.
+        for folder in params.event.added {
+            if let Ok(path) = folder.uri.to_file_path() {
+                self.rescan(Some(path)).await;
+            }
         }
     }
 
@@ -182,9 +784,80 @@ impl LanguageServer for Backend {
             .await;
     }
 
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
+        if params.command != SET_CONFIGURATION_COMMAND {
+            return Err(request_error(format!(
+                "Unknown command `{}`.",
+                params.command
+            )));
+        }
+
+        let name = match params.arguments.first() {
+            Some(Value::String(name)) => name.clone(),
+            Some(_) => {
+                return Err(request_error(
+                    "The configuration name must be a string.".to_string(),
+                ))
+            }
+            None => {
+                let actions = self
+                    .state
+                    .snapshot()
+                    .project
+                    .target_names()
+                    .into_iter()
+                    .map(|name| MessageActionItem {
+                        title: name.to_string(),
+                        properties: HashMap::new(),
+                    })
+                    .collect::<Vec<_>>();
+                if actions.is_empty() {
+                    return Err(request_error(
+                        "There are no configurations. Add `sail.targets` to the settings or a `.sail_project` file to the workspace.".to_string(),
+                    ));
+                }
+                let action = self
+                    .client
+                    .show_message_request(
+                        MessageType::INFO,
+                        "Select the Sail configuration",
+                        Some(actions),
+                    )
+                    .await?;
+                match action {
+                    Some(action) => action.title,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        self.set_active_target(&name).await.map_err(request_error)?;
+        Ok(None)
+    }
+
     async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let (projects, changes): (Vec<_>, Vec<_>) = params
+            .changes
+            .iter()
+            .partition(|change| change.uri.path().ends_with("sail_project"));
+        // We watch everything to see folders change, so drop changes in
+        // excluded directories like `.git` before doing anything else.
+        let snapshot = self.state.snapshot();
+        let filter = filter::Filter::new(&snapshot.scan_config);
+        let folders = snapshot.disk_files.folders();
+        let changes = changes
+            .into_iter()
+            .filter(|change| match change.uri.to_file_path() {
+                Ok(path) => filter.may_include(folders, &path),
+                Err(()) => false,
+            })
+            .collect::<Vec<_>>();
+        if projects.is_empty() && changes.is_empty() {
+            return;
+        }
+
         let mut files = String::new();
-        for change in &params.changes {
+        for change in projects.iter().chain(&changes) {
             files.push_str(&format!(" {}", change.uri));
         }
         self.client
@@ -194,27 +867,89 @@ impl LanguageServer for Backend {
             )
             .await;
 
-        let mut state = self.state.lock().await;
-        for change in &params.changes {
+        let mut state = self.state.write().await;
+        if !projects.is_empty() {
+            let messages = state.load_project();
+            self.log_messages(messages).await;
+        }
+        // Directories that have been created or deleted, e.g. by renaming.
+        let mut dirs = Vec::new();
+        for change in &changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            // Files that we shouldn't analyse are treated as deleted, in case
+            // they were analysed before, e.g. if they have got too big.
+            let included = filter.includes(state.disk_files.folders(), &path);
             match change.typ {
-                tower_lsp::lsp_types::FileChangeType::DELETED => {
-                    state.disk_files.remove_file(&change.uri);
-                }
                 tower_lsp::lsp_types::FileChangeType::CREATED
-                | tower_lsp::lsp_types::FileChangeType::CHANGED => {
+                | tower_lsp::lsp_types::FileChangeType::CHANGED
+                    if included =>
+                {
                     // Parse the file.
-                    if change.uri.scheme() == "file" {
-                        if let Ok(path) = change.uri.to_file_path() {
-                            if let Ok(source) = std::fs::read_to_string(path) {
-                                let file = File::new(source);
-                                state.disk_files.add_file(change.uri.clone(), file);
-                            }
-                        }
+                    let modified = files::modified_time(&path);
+                    if let Ok(source) = std::fs::read_to_string(&path) {
+                        let mut file = File::new(source, state.position_encoding, &state.fixities);
+                        file.modified = modified;
+                        Arc::make_mut(&mut state.disk_files).add_file(change.uri.clone(), file);
                     }
                 }
-                _ => {}
+                tower_lsp::lsp_types::FileChangeType::DELETED
+                    if state.disk_files.has_files_in(&path) =>
+                {
+                    dirs.push(path);
+                }
+                _ => {
+                    Arc::make_mut(&mut state.disk_files).remove_file(&change.uri);
+                }
             }
         }
+        // A different project can include different files.
+        let updated = if projects.is_empty() {
+            state.update_fixities_of(changes.iter().map(|change| &change.uri))
+        } else {
+            state.update_fixities()
+        };
+        if updated {
+            self.publish_open_diagnostics(&state).await;
+        }
+
+        // Our own watcher only watches the directories that existed when it
+        // started, so add new ones and forget deleted ones.
+        let mut errors = Vec::new();
+        if let Some(watcher) = self.watcher.lock().unwrap().as_mut() {
+            for path in changes
+                .iter()
+                .filter_map(|change| change.uri.to_file_path().ok())
+            {
+                if path.is_dir() {
+                    errors.extend(watcher.add_dir(state.disk_files.folders(), &filter, &path));
+                } else {
+                    watcher.remove_dir(&path);
+                }
+            }
+        }
+        drop(state);
+        self.log_messages(
+            errors
+                .into_iter()
+                .map(|e| (MessageType::ERROR, e))
+                .collect(),
+        )
+        .await;
+
+        if !dirs.is_empty() {
+            let backend = self.clone();
+            tokio::spawn(async move {
+                for dir in dirs {
+                    backend.rescan(Some(dir)).await;
+                }
+            });
+        }
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
@@ -227,15 +962,23 @@ impl LanguageServer for Backend {
 
         let uri = &params.text_document.uri;
 
-        let mut state = self.state.lock().await;
+        let mut state = self.state.write().await;
 
-        let file = File::new(params.text_document.text /*, true*/);
+        let file = File::new(
+            params.text_document.text,
+            state.position_encoding,
+            &state.fixities,
+        );
+        state.open_files.insert(uri.clone(), Arc::new(file));
+        state
+            .versions
+            .insert(uri.clone(), params.text_document.version);
 
-        self.client
-            .publish_diagnostics(uri.clone(), file.diagnostics.clone(), None)
-            .await;
+        self.publish_diagnostics(&state, uri).await;
 
-        state.open_files.insert(uri.clone(), file);
+        if state.update_fixities_of([uri]) {
+            self.publish_open_diagnostics(&state).await;
+        }
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
@@ -248,17 +991,20 @@ impl LanguageServer for Backend {
 
         let uri = &params.text_document.uri;
 
-        let mut state = self.state.lock().await;
-
+        // Only apply the edits here. Analysis is done in the background.
+        let mut state = self.state.write().await;
         let file = state
             .open_files
-            .get_mut(uri)
-            .expect("document changed that isn't open");
-        file.update(params.content_changes);
+            .get(uri)
+            .expect("document changed that isn't open")
+            .edited(params.content_changes);
+        state.open_files.insert(uri.clone(), Arc::new(file));
+        state
+            .versions
+            .insert(uri.clone(), params.text_document.version);
+        drop(state);
 
-        self.client
-            .publish_diagnostics(uri.clone(), file.diagnostics.clone(), None)
-            .await;
+        self.schedule_analysis(uri.clone(), params.text_document.version);
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
@@ -268,6 +1014,8 @@ impl LanguageServer for Backend {
                 format!("file saved: {}", params.text_document.uri),
             )
             .await;
+
+        self.run_compiler().await;
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
@@ -279,8 +1027,16 @@ impl LanguageServer for Backend {
             .await;
         let uri = &params.text_document.uri;
 
-        let mut state = self.state.lock().await;
+        self.cancel_analysis(uri);
+
+        let mut state = self.state.write().await;
         state.open_files.remove(uri);
+        state.versions.remove(uri);
+
+        // The file on disk may have different declarations.
+        if state.update_fixities_of([uri]) {
+            self.publish_open_diagnostics(&state).await;
+        }
     }
 
     async fn goto_definition(
@@ -292,7 +1048,7 @@ impl LanguageServer for Backend {
             .await;
 
         let uri = &params.text_document_position_params.text_document.uri;
-        let state = self.state.lock().await;
+        let state = self.state_for_request().await;
         let file = state
             .open_files
             .get(uri)
@@ -300,92 +1056,199 @@ impl LanguageServer for Backend {
 
         let position = params.text_document_position_params.position;
 
-        if let Some(token) = file.token_at(position) {
-            if let (sail_parser::Token::Id(ident), _) = token {
-                // TODO: This is currently limited to one definition per file
-                // even though you can actually have more (e.g. for `overload`).
-                let mut definitions = state.all_files()
-                    .filter_map(|(uri, file)| {
-                        if let Some(offset) = file.definitions.get(ident) {
-                            let position = file.source.position_at(*offset);
-                            Some(Location::new(uri.clone(), Range::new(position, position)))
-                        } else {
-                            None
-                        }
-                    }).collect::<Vec<_>>();
-
-                // Sort by "distance" to the file from the currently open one,
-                // as measured by the number of shared path components.
-                // TODO: For some reason this doesn't quite work on Windows
-                // because `uri.path_segments()` starts with `c%3A` sometimes
-                // instead of `c:`. Also we should do case insensitive comparison
-                // on Windows. Let's just give up on Windows for now.
-                definitions.sort_by_key(|location| Reverse(
-                    match (uri.path_segments(), location.uri.path_segments()) {
-                        (Some(p0), Some(p1)) =>
-                            p0.zip(p1).take_while(|(a, b)| a == b).count(),
-                        _ => 0,
-                    }
-                ));
+        if let Some((sail_parser::Token::Id(ident), _)) = file.token_at(position) {
+            let mut definitions = state
+                .all_files()
+                .flat_map(|(uri, file)| {
+                    file.definitions
+                        .get(ident)
+                        .into_iter()
+                        .flatten()
+                        .map(move |definition| {
+                            let range = Range::new(
+                                file.source.position_at(definition.span.start),
+                                file.source.position_at(definition.span.end),
+                            );
+                            (definition.kind, Location::new(uri.clone(), range))
+                        })
+                })
+                .collect::<Vec<_>>();
 
-                if !definitions.is_empty() {
-                    eprintln!("First definition URI: {}", definitions[0].uri);
-                    return Ok(Some(GotoDefinitionResponse::Array(definitions)));
-                }
+            // Group by kind, so e.g. functions come before their vals, then
+            // sort by "distance" to the file from the currently open one,
+            // as measured by the number of shared path components.
+            // TODO: For some reason this doesn't quite work on Windows
+            // because `uri.path_segments()` starts with `c%3A` sometimes
+            // instead of `c:`. Also we should do case insensitive comparison
+            // on Windows. Let's just give up on Windows for now.
+            definitions.sort_by_key(|(kind, location)| {
+                let distance = match (uri.path_segments(), location.uri.path_segments()) {
+                    (Some(p0), Some(p1)) => p0.zip(p1).take_while(|(a, b)| a == b).count(),
+                    _ => 0,
+                };
+                (
+                    *kind,
+                    Reverse(distance),
+                    location.uri.clone(),
+                    location.range.start,
+                )
+            });
+            let definitions = definitions
+                .into_iter()
+                .map(|(_, location)| location)
+                .collect::<Vec<_>>();
+
+            if !definitions.is_empty() {
+                eprintln!("First definition URI: {}", definitions[0].uri);
+                return Ok(Some(GotoDefinitionResponse::Array(definitions)));
             }
         }
         Ok(None)
     }
 
-    async fn completion(&self, _params: CompletionParams) -> Result<Option<CompletionResponse>> {
-        // self.client
-        //     .log_message(MessageType::INFO, format!("completion: {:?}", params))
-        //     .await;
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
+
+        let offset = file
+            .source
+            .offset_at(&params.text_document_position.position);
+        Ok(Some(references::references(
+            state.all_files(),
+            uri,
+            offset,
+            params.context.include_declaration,
+        )))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let uri = &params.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
 
-        // let uri = &params.text_document_position.text_document.uri;
-        // let state = self.state.lock().await;
-        // let _file = state
-        //     .open_files
-        //     .get(uri)
-        //     .expect("completion for file that isn't open");
+        let offset = file.source.offset_at(&params.position);
+        rename::prepare_rename(state.all_files(), uri, offset)
+            .map(|range| Some(PrepareRenameResponse::Range(range)))
+            .map_err(request_error)
+    }
 
-        // TODO: Completion
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
 
-        Ok(None)
+        let offset = file
+            .source
+            .offset_at(&params.text_document_position.position);
+        rename::rename(state.all_files(), uri, offset, &params.new_name)
+            .map(Some)
+            .map_err(request_error)
     }
 
-    async fn hover(&self, _params: HoverParams) -> Result<Option<Hover>> {
-        // self.client
-        //     .log_message(MessageType::INFO, format!("hover: {:?}", params))
-        //     .await;
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        Ok(Some(DocumentSymbolResponse::Nested(
+            symbols::document_symbols(file),
+        )))
+    }
 
-        // let uri = &params.text_document_position_params.text_document.uri;
-        // let state = self.state.lock().await;
-        // let _file = state
-        //     .open_files
-        //     .get(uri)
-        //     .expect("hover for file that isn't open");
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        let state = self.state_for_request().await;
+        Ok(Some(symbols::workspace_symbols(
+            state.all_files(),
+            &params.query,
+        )))
+    }
 
-        // TODO: Hover
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
 
-        Ok(None)
+        let offset = file
+            .source
+            .offset_at(&params.text_document_position.position);
+        let items = completion::completion(state.all_files(), file, offset);
+        Ok(Some(CompletionResponse::Array(items)))
     }
 
-    async fn signature_help(&self, _params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
-        // self.client
-        //     .log_message(MessageType::INFO, format!("signature: {:?}", params))
-        //     .await;
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
 
-        // let uri = &params.text_document_position_params.text_document.uri;
-        // let state = self.state.lock().await;
-        // let _file = state
-        //     .open_files
-        //     .get(uri)
-        //     .expect("signature for file that isn't open");
+        let position = params.text_document_position_params.position;
 
-        // TODO: Signature help.
+        let Some((sail_parser::Token::Id(ident), span)) = file.token_at(position) else {
+            return Ok(None);
+        };
+        let range = Range::new(
+            file.source.position_at(span.start),
+            file.source.position_at(span.end),
+        );
 
-        Ok(None)
+        Ok(
+            hover::hover(state.all_files(), state.disk_files.folders(), ident).map(|value| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            }),
+        )
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let state = self.state_for_request().await;
+        let Some(file) = state.open_files.get(uri) else {
+            return Ok(None);
+        };
+
+        let offset = file
+            .source
+            .offset_at(&params.text_document_position_params.position);
+        let Some((name, active_parameter)) = signature::call_at(&file.tokens, offset) else {
+            return Ok(None);
+        };
+
+        Ok(signature::signature_help(
+            state.all_files(),
+            name,
+            active_parameter,
+        ))
+    }
+}
+
+/// An error response with a message that is shown to the user.
+fn request_error(message: String) -> tower_lsp::jsonrpc::Error {
+    tower_lsp::jsonrpc::Error {
+        code: tower_lsp::jsonrpc::ErrorCode::InvalidRequest,
+        message: message.into(),
+        data: None,
     }
 }
 
@@ -394,6 +1257,178 @@ async fn main() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(Backend::new_with_client);
+    let (service, socket) = LspService::build(Backend::new_with_client)
+        .custom_method("sail/setConfiguration", Backend::set_configuration)
+        .finish();
     Server::new(stdin, stdout, socket).serve(service).await;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tower_lsp::lsp_types::{
+        ClientCapabilities, GeneralClientCapabilities, Position, PositionEncodingKind,
+        TextDocumentContentChangeEvent, TextDocumentItem, VersionedTextDocumentIdentifier,
+        WorkspaceFolder, WorkspaceFoldersChangeEvent,
+    };
+
+    async fn symbol_names(backend: &Backend) -> Vec<String> {
+        let params = WorkspaceSymbolParams {
+            query: String::new(),
+            ..Default::default()
+        };
+        let symbols = backend.symbol(params).await.unwrap().unwrap_or_default();
+        let mut names = symbols
+            .into_iter()
+            .map(|symbol| symbol.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_workspace_folders() {
+        let dir = std::env::temp_dir().join(format!("sail_folders_{}", std::process::id()));
+        let folder = |name: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("defs.sail"), format!("register {} : int\n", name)).unwrap();
+            WorkspaceFolder {
+                uri: Url::from_directory_path(&path).unwrap(),
+                name: name.to_string(),
+            }
+        };
+        let (first, second) = (folder("first"), folder("second"));
+
+        // Calling the backend directly means that the client is never
+        // initialised, so messages to it are dropped.
+        let (service, _) = LspService::new(Backend::new_with_client);
+        let backend = service.inner();
+        let params = InitializeParams {
+            initialization_options: Some(serde_json::json!({ "cache": { "enabled": false } })),
+            ..Default::default()
+        };
+        backend.initialize(params).await.unwrap();
+        assert!(symbol_names(backend).await.is_empty());
+
+        let change = |added, removed| DidChangeWorkspaceFoldersParams {
+            event: WorkspaceFoldersChangeEvent { added, removed },
+        };
+        backend
+            .did_change_workspace_folders(change(vec![first.clone()], vec![]))
+            .await;
+        assert_eq!(symbol_names(backend).await, ["first"]);
+
+        backend
+            .did_change_workspace_folders(change(vec![second.clone()], vec![first]))
+            .await;
+        assert_eq!(symbol_names(backend).await, ["second"]);
+
+        backend
+            .did_change_workspace_folders(change(vec![], vec![second]))
+            .await;
+        assert!(symbol_names(backend).await.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_analyze_reuses_tokens() {
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        let (service, _) = LspService::new(Backend::new_with_client);
+        let backend = service.inner();
+        backend
+            .initialize(InitializeParams::default())
+            .await
+            .unwrap();
+        backend
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    uri.clone(),
+                    "sail".to_string(),
+                    1,
+                    "register a : int\nregister b : int\n".to_string(),
+                ),
+            })
+            .await;
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(Range::new(Position::new(0, 9), Position::new(0, 10))),
+                    range_length: None,
+                    text: "abc".to_string(),
+                }],
+            })
+            .await;
+
+        // Rename `b` behind the lexer's back. Lexing from scratch would
+        // find `b` again, but reused tokens keep the new name.
+        {
+            let mut state = backend.state.write().await;
+            let file = Arc::make_mut(state.open_files.get_mut(&uri).unwrap());
+            let mut tokens = file.tokens.to_vec();
+            tokens[5].0 = sail_parser::Token::Id("c".to_string());
+            file.tokens = Arc::new(tokens);
+        }
+        backend.analyze(&uri, 2).await;
+
+        let state = backend.state.snapshot();
+        let file = &state.open_files[&uri];
+        assert!(!file.dirty);
+        assert_eq!(
+            file.tokens[5],
+            (
+                sail_parser::Token::Id("c".to_string()),
+                sail_parser::Span::new(28, 29)
+            )
+        );
+        assert!(file.definitions.contains_key("abc"));
+        assert!(file.definitions.contains_key("c"));
+    }
+
+    #[tokio::test]
+    async fn test_position_encoding() {
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        for (encodings, expected, column) in [
+            (None, PositionEncodingKind::UTF16, 17),
+            (
+                Some(vec![PositionEncodingKind::UTF8]),
+                PositionEncodingKind::UTF8,
+                18,
+            ),
+        ] {
+            let (service, _) = LspService::new(Backend::new_with_client);
+            let backend = service.inner();
+            let params = InitializeParams {
+                capabilities: ClientCapabilities {
+                    general: Some(GeneralClientCapabilities {
+                        position_encodings: encodings,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let result = backend.initialize(params).await.unwrap();
+            assert_eq!(result.capabilities.position_encoding, Some(expected));
+
+            backend
+                .did_open(DidOpenTextDocumentParams {
+                    text_document: TextDocumentItem::new(
+                        uri.clone(),
+                        "sail".to_string(),
+                        1,
+                        "/* \u{e9} */ register x : int\n".to_string(),
+                    ),
+                })
+                .await;
+            let params = WorkspaceSymbolParams {
+                query: "x".to_string(),
+                ..Default::default()
+            };
+            let symbols = backend.symbol(params).await.unwrap().unwrap();
+            assert_eq!(symbols[0].location.range.start, Position::new(0, column));
+        }
+    }
+}
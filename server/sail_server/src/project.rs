@@ -0,0 +1,721 @@
+// Sail has no module system. Which files are compiled together, and in
+// which order, is decided by a Makefile or by a Sail project file. This
+// reads project files, or an explicit list of files per target from the
+// configuration, so that names are only resolved against the files in the
+// active target.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tower_lsp::lsp_types::Url;
+use walkdir::WalkDir;
+
+/// Project configuration, from the client's initialization options.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProjectConfig {
+    // If this is empty then project files in the workspace are used.
+    pub targets: Vec<TargetConfig>,
+    // The name of the active target. If not set the first target is used.
+    pub active_target: Option<String>,
+}
+
+/// A target, e.g. `RV32` or `RV64`. The files are either listed explicitly
+/// or come from a Sail project file. Relative paths are relative to the
+/// workspace folders.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TargetConfig {
+    pub name: String,
+    // Files in the order they are compiled.
+    pub files: Vec<String>,
+    // A Sail project file, e.g. `model/riscv.sail_project`.
+    pub project: Option<String>,
+    // Modules to use from the project file. If empty all modules are used.
+    pub modules: Vec<String>,
+    // Values for `variable`s in the project file, e.g. `ARCH = RV32`.
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+pub struct Target {
+    pub name: String,
+    // Files in compilation order.
+    pub files: Vec<PathBuf>,
+    set: HashSet<PathBuf>,
+}
+
+impl Target {
+    fn new(name: String, files: Vec<PathBuf>) -> Self {
+        let set = files.iter().cloned().collect();
+        Self { name, files, set }
+    }
+}
+
+/// The targets in the workspace and which one is active.
+#[derive(Clone, Default)]
+pub struct Project {
+    pub targets: Vec<Target>,
+    active: Option<usize>,
+}
+
+impl Project {
+    /// Load the targets from the configuration, or find project files in
+    /// the workspace folders if there aren't any configured. Returns any
+    /// errors, which don't stop the other targets from loading.
+    pub fn load(config: &ProjectConfig, folders: &HashSet<Url>) -> (Self, Vec<String>) {
+        let mut folders = folders
+            .iter()
+            .filter_map(|folder| folder.to_file_path().ok())
+            .collect::<Vec<_>>();
+        folders.sort();
+
+        let mut targets = Vec::new();
+        let mut errors = Vec::new();
+
+        if config.targets.is_empty() {
+            for path in find_project_files(&folders) {
+                let name = path
+                    .file_stem()
+                    .map_or("project".into(), |stem| stem.to_string_lossy())
+                    .trim_start_matches('.')
+                    .to_string();
+                match project_files(&path, &[], &HashMap::new()) {
+                    Ok(files) => targets.push(Target::new(name, files)),
+                    Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+                }
+            }
+        }
+
+        for target in &config.targets {
+            let mut files = Vec::new();
+            for file in &target.files {
+                files.extend(resolve(&folders, file));
+            }
+            if let Some(project) = &target.project {
+                for path in resolve(&folders, project) {
+                    if !path.is_file() {
+                        continue;
+                    }
+                    match project_files(&path, &target.modules, &target.variables) {
+                        Ok(project_files) => files.extend(project_files),
+                        Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+                    }
+                }
+            }
+            targets.push(Target::new(target.name.clone(), files));
+        }
+
+        let active = match &config.active_target {
+            Some(name) => {
+                let active = targets.iter().position(|target| &target.name == name);
+                if active.is_none() {
+                    errors.push(format!("unknown target `{}`", name));
+                }
+                active
+            }
+            None => (!targets.is_empty()).then_some(0),
+        };
+
+        (Self { targets, active }, errors)
+    }
+
+    pub fn active_target(&self) -> Option<&Target> {
+        self.active.map(|i| &self.targets[i])
+    }
+
+    /// Switch to another target. The file sets of all targets are kept so
+    /// this doesn't need to read anything from disk.
+    pub fn set_active(&mut self, name: &str) -> Result<(), String> {
+        match self.targets.iter().position(|target| target.name == name) {
+            Some(i) => {
+                self.active = Some(i);
+                Ok(())
+            }
+            None => Err(format!(
+                "Unknown configuration `{}`. Available configurations: {}.",
+                name,
+                self.target_names().join(", ")
+            )),
+        }
+    }
+
+    pub fn target_names(&self) -> Vec<&str> {
+        self.targets
+            .iter()
+            .map(|target| target.name.as_str())
+            .collect()
+    }
+
+    /// Is the file part of the active target? If there isn't one then all
+    /// files are.
+    pub fn contains(&self, uri: &Url) -> bool {
+        let Some(target) = self.active_target() else {
+            return true;
+        };
+        uri.to_file_path()
+            .is_ok_and(|path| target.set.contains(&normalize(&path)))
+    }
+}
+
+/// Remove `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Resolve a path from the configuration against each workspace folder.
+fn resolve(folders: &[PathBuf], path: &str) -> Vec<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return vec![normalize(path)];
+    }
+    folders
+        .iter()
+        .map(|folder| normalize(&folder.join(path)))
+        .collect()
+}
+
+/// Find `*.sail_project` files near the top of the workspace folders.
+fn find_project_files(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for folder in folders {
+        for entry in WalkDir::new(folder).max_depth(3).sort_by_file_name() {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let is_project = entry.file_name() == "sail_project"
+                || entry.path().extension() == Some("sail_project".as_ref());
+            if entry.file_type().is_file() && is_project {
+                paths.push(entry.into_path());
+            }
+        }
+    }
+    paths
+}
+
+/// Read a project file and get the files for some of its modules (or all
+/// of them), in order.
+fn project_files(
+    path: &Path,
+    modules: &[String],
+    variables: &HashMap<String, String>,
+) -> Result<Vec<PathBuf>, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let project = parse_project(&source)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(project
+        .files(modules, variables)?
+        .into_iter()
+        .map(|file| normalize(&dir.join(file)))
+        .collect())
+}
+
+/// A parsed Sail project file. This supports:
+///
+/// ```text
+/// variable ARCH = RV64
+///
+/// core {
+///   files prelude.sail, "types.sail"
+/// }
+///
+/// arch {
+///   requires core
+///   files
+///     if $ARCH == RV32 then xlen32.sail else xlen64.sail,
+///     regs.sail
+/// }
+/// ```
+///
+/// Modules can be nested, in which case their files are included with their
+/// parent's. `after`, `before`, `default` and `optional` are accepted but
+/// only affect the order and selection of modules via `requires`.
+#[derive(Debug, Default, PartialEq)]
+pub struct SailProject {
+    variables: Vec<(String, String)>,
+    modules: Vec<Module>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Module {
+    name: String,
+    requires: Vec<String>,
+    optional: bool,
+    files: Vec<FileItem>,
+}
+
+#[derive(Debug, PartialEq)]
+enum FileItem {
+    Path(String),
+    /// `if $A == B then x else y`. The condition is (variable, value, equal).
+    If {
+        variable: String,
+        value: String,
+        equal: bool,
+        then: Vec<FileItem>,
+        else_: Vec<FileItem>,
+    },
+}
+
+impl SailProject {
+    /// The files for `modules` and the modules they require, in order. If
+    /// `modules` is empty then all non-optional modules are used.
+    pub fn files(
+        &self,
+        modules: &[String],
+        overrides: &HashMap<String, String>,
+    ) -> Result<Vec<String>, String> {
+        let mut variables = self.variables.iter().cloned().collect::<HashMap<_, _>>();
+        variables.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let roots = if modules.is_empty() {
+            self.modules
+                .iter()
+                .filter(|module| !module.optional)
+                .map(|module| module.name.clone())
+                .collect()
+        } else {
+            modules.to_vec()
+        };
+
+        // Depth first so that required modules come first.
+        let mut order = Vec::new();
+        let mut visiting = HashSet::new();
+        for root in &roots {
+            self.visit(root, &mut visiting, &mut order)?;
+        }
+
+        let mut files = Vec::new();
+        for module in order {
+            for item in &module.files {
+                item.eval(&variables, &mut files)?;
+            }
+        }
+        Ok(files)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        name: &str,
+        visiting: &mut HashSet<String>,
+        order: &mut Vec<&'a Module>,
+    ) -> Result<(), String> {
+        if order.iter().any(|module| module.name == name) {
+            return Ok(());
+        }
+        if !visiting.insert(name.to_string()) {
+            return Err(format!("module `{}` requires itself", name));
+        }
+        let module = self
+            .modules
+            .iter()
+            .find(|module| module.name == name)
+            .ok_or_else(|| format!("unknown module `{}`", name))?;
+        for required in &module.requires {
+            self.visit(required, visiting, order)?;
+        }
+        order.push(module);
+        Ok(())
+    }
+}
+
+impl FileItem {
+    fn eval(
+        &self,
+        variables: &HashMap<String, String>,
+        files: &mut Vec<String>,
+    ) -> Result<(), String> {
+        match self {
+            FileItem::Path(path) => files.push(path.clone()),
+            FileItem::If {
+                variable,
+                value,
+                equal,
+                then,
+                else_,
+            } => {
+                let actual = variables
+                    .get(variable)
+                    .ok_or_else(|| format!("unknown variable `${}`", variable))?;
+                let branch = if (actual == value) == *equal {
+                    then
+                } else {
+                    else_
+                };
+                for item in branch {
+                    item.eval(variables, files)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a project file into tokens, with their line numbers for errors.
+/// Paths are single tokens, e.g. `../model/prelude.sail`.
+fn tokenize(source: &str) -> Result<Vec<(String, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let line = |offset: usize| source[..offset].matches('\n').count() + 1;
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if source[start..].starts_with("//") {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if source[start..].starts_with("/*") {
+            let end = source[start..]
+                .find("*/")
+                .ok_or_else(|| format!("line {}: unterminated comment", line(start)))?;
+            while chars.next_if(|&(i, _)| i < start + end + 2).is_some() {}
+        } else if c == '"' {
+            chars.next();
+            let mut end = None;
+            for (i, c) in chars.by_ref() {
+                if c == '"' {
+                    end = Some(i);
+                    break;
+                }
+            }
+            let end = end.ok_or_else(|| format!("line {}: unterminated string", line(start)))?;
+            // Keep the quote so strings can be distinguished from keywords.
+            tokens.push((source[start..end].to_string(), line(start)));
+        } else if source[start..].starts_with("==") || source[start..].starts_with("!=") {
+            chars.next();
+            chars.next();
+            tokens.push((source[start..start + 2].to_string(), line(start)));
+        } else if "{}(),=$".contains(c) {
+            chars.next();
+            tokens.push((c.to_string(), line(start)));
+        } else {
+            let mut end = start;
+            while let Some((i, c)) =
+                chars.next_if(|&(_, c)| !c.is_whitespace() && !"{}(),=$\"".contains(c))
+            {
+                end = i + c.len_utf8();
+            }
+            tokens.push((source[start..end].to_string(), line(start)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(String, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|(token, _)| token.as_str())
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self.tokens.get(self.pos) {
+            Some((token, line)) => format!("line {}: {}, found `{}`", line, message, token),
+            None => format!("{} at end of file", message),
+        }
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", token)))
+        }
+    }
+
+    /// A name or path. Strings have their quote removed.
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(token) if !"{}(),=$".contains(token) && token != "==" && token != "!=" => {
+                let token = self.next().unwrap();
+                Ok(token.strip_prefix('"').unwrap_or(&token).to_string())
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn module(&mut self, name: String, modules: &mut Vec<Module>) -> Result<(), String> {
+        self.expect("{")?;
+        let mut module = Module {
+            name,
+            ..Module::default()
+        };
+        let mut children = Vec::new();
+        while !self.eat("}") {
+            match self.peek() {
+                Some("requires") | Some("after") | Some("before") => {
+                    let is_requires = self.next().as_deref() == Some("requires");
+                    loop {
+                        let name = self.name()?;
+                        if is_requires {
+                            module.requires.push(name);
+                        }
+                        if !self.eat(",") {
+                            break;
+                        }
+                    }
+                }
+                Some("files") => {
+                    self.next();
+                    module.files.extend(self.file_list()?);
+                }
+                Some("optional") => {
+                    self.next();
+                    module.optional = true;
+                }
+                Some("default") => {
+                    self.next();
+                }
+                Some(_) => {
+                    let name = self.name()?;
+                    self.module(name, &mut children)?;
+                }
+                None => return Err(self.error("expected `}`")),
+            }
+        }
+        // Nested modules are included with their parent.
+        for child in children {
+            module.requires.extend(child.requires);
+            module.files.extend(child.files);
+        }
+        modules.push(module);
+        Ok(())
+    }
+
+    fn file_list(&mut self) -> Result<Vec<FileItem>, String> {
+        let mut items = Vec::new();
+        loop {
+            items.extend(self.file_item()?);
+            if !self.eat(",") {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn file_item(&mut self) -> Result<Vec<FileItem>, String> {
+        match self.peek() {
+            Some("if") => {
+                self.next();
+                self.expect("$")?;
+                let variable = self.name()?;
+                let equal = match self.next().as_deref() {
+                    Some("==") => true,
+                    Some("!=") => false,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.error("expected `==` or `!=`"));
+                    }
+                };
+                let value = self.name()?;
+                self.expect("then")?;
+                let then = self.file_item()?;
+                let else_ = if self.eat("else") {
+                    self.file_item()?
+                } else {
+                    Vec::new()
+                };
+                Ok(vec![FileItem::If {
+                    variable,
+                    value,
+                    equal,
+                    then,
+                    else_,
+                }])
+            }
+            Some("error") => {
+                // `error("message")` is only reached when the project is
+                // misconfigured. Ignore it.
+                self.next();
+                self.expect("(")?;
+                self.name()?;
+                self.expect(")")?;
+                Ok(Vec::new())
+            }
+            Some("{") => {
+                self.next();
+                if self.eat("}") {
+                    return Ok(Vec::new());
+                }
+                let items = self.file_list()?;
+                self.expect("}")?;
+                Ok(items)
+            }
+            _ => Ok(vec![FileItem::Path(self.name()?)]),
+        }
+    }
+}
+
+/// Parse a Sail project file.
+pub fn parse_project(source: &str) -> Result<SailProject, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut project = SailProject::default();
+    while parser.peek().is_some() {
+        if parser.eat("variable") {
+            let name = parser.name()?;
+            parser.expect("=")?;
+            let value = parser.name()?;
+            project.variables.push((name, value));
+        } else {
+            let name = parser.name()?;
+            parser.module(name, &mut project.modules)?;
+        }
+    }
+    Ok(project)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROJECT: &str = r#"
+variable ARCH = RV64
+
+// The core.
+arch {
+  requires prelude
+  files
+    if $ARCH == RV32 then riscv_xlen32.sail
+    else if $ARCH == RV64 then riscv_xlen64.sail
+    else error("ARCH must be RV32 or RV64"),
+    riscv_regs.sail
+}
+
+prelude {
+  files "prelude.sail", /* inline */ ../lib/vector.sail
+}
+
+cheri {
+  optional
+  requires arch
+  files cheri_regs.sail
+  extra {
+    files cheri_insts.sail
+  }
+}
+"#;
+
+    #[test]
+    fn test_project_files() {
+        let project = parse_project(PROJECT).unwrap();
+        let files = |modules: &[&str], variables: &[(&str, &str)]| {
+            let modules = modules.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+            let variables = variables
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            project.files(&modules, &variables)
+        };
+
+        assert_eq!(
+            files(&[], &[]).unwrap(),
+            [
+                "prelude.sail",
+                "../lib/vector.sail",
+                "riscv_xlen64.sail",
+                "riscv_regs.sail"
+            ]
+        );
+        assert_eq!(
+            files(&["cheri"], &[("ARCH", "RV32")]).unwrap(),
+            [
+                "prelude.sail",
+                "../lib/vector.sail",
+                "riscv_xlen32.sail",
+                "riscv_regs.sail",
+                "cheri_regs.sail",
+                "cheri_insts.sail"
+            ]
+        );
+        assert_eq!(
+            files(&["missing"], &[]),
+            Err("unknown module `missing`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_project_errors() {
+        assert_eq!(
+            parse_project("core {\n  files a.sail,\n}\n"),
+            Err("line 3: expected a name, found `}`".to_string())
+        );
+        assert_eq!(
+            parse_project("a { requires b }\nb { requires a }")
+                .unwrap()
+                .files(&[], &HashMap::new()),
+            Err("module `a` requires itself".to_string())
+        );
+    }
+
+    #[test]
+    fn test_targets() {
+        let config: ProjectConfig = serde_json::from_value(serde_json::json!({
+            "targets": [
+                { "name": "RV32", "files": ["prelude.sail", "rv32/xlen.sail"] },
+                { "name": "RV64", "files": ["prelude.sail", "rv64/xlen.sail"] },
+            ],
+            "activeTarget": "RV64",
+        }))
+        .unwrap();
+        let folder = if cfg!(windows) {
+            "file:///C:/ws/"
+        } else {
+            "file:///ws/"
+        };
+        let folders = HashSet::from([Url::parse(folder).unwrap()]);
+        let (project, errors) = Project::load(&config, &folders);
+        assert!(errors.is_empty());
+        assert_eq!(project.active_target().unwrap().name, "RV64");
+
+        let uri = |path: &str| Url::parse(folder).unwrap().join(path).unwrap();
+        assert!(project.contains(&uri("prelude.sail")));
+        assert!(project.contains(&uri("rv64/xlen.sail")));
+        assert!(!project.contains(&uri("rv32/xlen.sail")));
+
+        let mut project = project;
+        project.set_active("RV32").unwrap();
+        assert!(project.contains(&uri("rv32/xlen.sail")));
+        assert!(!project.contains(&uri("rv64/xlen.sail")));
+        assert_eq!(
+            project.set_active("CHERI"),
+            Err("Unknown configuration `CHERI`. Available configurations: RV32, RV64.".to_string())
+        );
+        assert_eq!(project.active_target().unwrap().name, "RV32");
+
+        // Without any targets everything is included.
+        let (project, _) = Project::load(&ProjectConfig::default(), &HashSet::new());
+        assert!(project.contains(&uri("rv32/xlen.sail")));
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{HashMap, HashSet};
+
+use sail_parser::{
+    Def, Exp, Ident, LetBinding, MappingClause, MappingPat, MatchArm, Pat, ScatteredDef,
+    SourceFile, Span, Spanned, Typ,
+};
+use tower_lsp::lsp_types::{Location, Range, Url};
+
+use crate::definitions::DefinitionKind;
+use crate::file::File;
+
+/// What an identifier refers to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Symbol {
+    /// A top-level definition, e.g. a function, register or type.
+    Global(String),
+    /// A struct or bitfield field. We don't know the type of expressions
+    /// so all fields with the same name are treated as the same symbol.
+    Field(String),
+    /// A local variable, identified by the span of its binding in the file.
+    Local(Span),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Occurrence {
+    pub symbol: Symbol,
+    pub span: Span,
+    // True for the definition site rather than a use.
+    pub declaration: bool,
+}
+
+/// The names defined in the workspace. This is needed to resolve
+/// identifiers in patterns, which may be either enum members (or union
+/// constructors) or new variables.
+#[derive(Default)]
+pub struct Globals {
+    names: HashSet<String>,
+    constructors: HashSet<String>,
+}
+
+impl Globals {
+    pub fn new<'a>(files: impl Iterator<Item = (&'a Url, &'a File)>) -> Self {
+        let mut globals = Self::default();
+        for (_, file) in files {
+            for (name, definitions) in &file.definitions {
+                globals.names.insert(name.clone());
+                if definitions.iter().any(|definition| {
+                    matches!(
+                        definition.kind,
+                        DefinitionKind::EnumMember | DefinitionKind::UnionVariant
+                    )
+                }) {
+                    globals.constructors.insert(name.clone());
+                }
+            }
+        }
+        globals
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+}
+
+/// Find every identifier in a file and resolve what it refers to.
+pub fn occurrences(file: &SourceFile, globals: &Globals) -> Vec<Occurrence> {
+    let mut walker = Walker {
+        globals,
+        scopes: Vec::new(),
+        occurrences: Vec::new(),
+    };
+    for def in &file.defs {
+        walker.def(def);
+    }
+    walker.occurrences
+}
+
+struct Walker<'a> {
+    globals: &'a Globals,
+    // Local variables in scope, mapping to their binding spans.
+    scopes: Vec<HashMap<String, Span>>,
+    occurrences: Vec<Occurrence>,
+}
+
+impl Walker<'_> {
+    fn push(&mut self, symbol: Symbol, span: Span, declaration: bool) {
+        self.occurrences.push(Occurrence {
+            symbol,
+            span,
+            declaration,
+        });
+    }
+
+    fn global(&mut self, (name, span): &Ident, declaration: bool) {
+        self.push(Symbol::Global(name.clone()), *span, declaration);
+    }
+
+    fn field(&mut self, (name, span): &Ident, declaration: bool) {
+        self.push(Symbol::Field(name.clone()), *span, declaration);
+    }
+
+    fn lookup(&self, name: &str) -> Option<Span> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    /// A use of a name that may be local.
+    fn name(&mut self, name: &str, span: Span) {
+        match self.lookup(name) {
+            Some(binding) => self.push(Symbol::Local(binding), span, false),
+            None => self.push(Symbol::Global(name.to_string()), span, false),
+        }
+    }
+
+    /// Bind a local variable in the innermost scope. If it is already bound
+    /// there (e.g. on both sides of a mapping clause) then it's a use.
+    fn bind(&mut self, name: &str, span: Span) {
+        let scope = self.scopes.last_mut().expect("binding outside a scope");
+        match scope.get(name) {
+            Some(&binding) => self.push(Symbol::Local(binding), span, false),
+            None => {
+                scope.insert(name.to_string(), span);
+                self.push(Symbol::Local(span), span, true);
+            }
+        }
+    }
+
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn def(&mut self, (def, _): &Spanned<Def>) {
+        match def {
+            Def::Val(val) => {
+                self.global(&val.name, true);
+                self.typ(&val.typschm.0.typ);
+                if let Some(constraint) = &val.typschm.0.constraint {
+                    self.typ(constraint);
+                }
+            }
+            Def::Function(function) => {
+                for (clause, _) in &function.clauses {
+                    self.global(&clause.name, true);
+                    self.scoped(|w| {
+                        w.pat(&clause.params);
+                        if let Some(typ) = &clause.return_type {
+                            w.typ(typ);
+                        }
+                        w.exp(&clause.body);
+                    });
+                }
+            }
+            Def::FunctionClause(clause) => {
+                self.global(&clause.name, true);
+                self.scoped(|w| {
+                    w.pat(&clause.params);
+                    if let Some(typ) = &clause.return_type {
+                        w.typ(typ);
+                    }
+                    w.exp(&clause.body);
+                });
+            }
+            Def::Mapping(mapping) => {
+                self.global(&mapping.name, true);
+                if let Some((typschm, _)) = &mapping.typschm {
+                    self.typ(&typschm.typ);
+                }
+                for clause in &mapping.clauses {
+                    self.mapping_clause(clause);
+                }
+            }
+            Def::MappingClause { name, clause } => {
+                self.global(name, true);
+                self.mapping_clause(clause);
+            }
+            Def::Type(type_) => {
+                self.global(&type_.name, true);
+                if let Some(typ) = &type_.typ {
+                    self.typ(typ);
+                }
+            }
+            Def::Struct(struct_) => {
+                self.global(&struct_.name, true);
+                for (name, typ) in &struct_.fields {
+                    self.field(name, true);
+                    self.typ(typ);
+                }
+            }
+            Def::Union(union) => {
+                self.global(&union.name, true);
+                for variant in &union.variants {
+                    self.global(&variant.name, true);
+                    self.typ(&variant.typ);
+                }
+            }
+            Def::UnionClause { name, variant } => {
+                self.global(name, false);
+                self.global(&variant.name, true);
+                self.typ(&variant.typ);
+            }
+            Def::Enum(enum_) => {
+                self.global(&enum_.name, true);
+                for (name, typ) in &enum_.functions {
+                    self.global(name, true);
+                    self.typ(typ);
+                }
+                for member in &enum_.members {
+                    self.global(&member.name, true);
+                    if let Some(value) = &member.value {
+                        self.scoped(|w| w.exp(value));
+                    }
+                }
+            }
+            Def::EnumClause { name, member } => {
+                self.global(name, false);
+                self.global(member, true);
+            }
+            Def::Bitfield(bitfield) => {
+                self.global(&bitfield.name, true);
+                self.typ(&bitfield.typ);
+                for field in &bitfield.fields {
+                    self.field(&field.name, true);
+                }
+            }
+            Def::Register(register) => {
+                self.global(&register.name, true);
+                self.typ(&register.typ);
+                if let Some(init) = &register.init {
+                    self.scoped(|w| w.exp(init));
+                }
+            }
+            Def::Overload(overload) => {
+                self.global(&overload.name, true);
+                for member in &overload.members {
+                    self.global(member, false);
+                }
+            }
+            Def::Scattered(scattered) => match scattered {
+                ScatteredDef::Function(name) | ScatteredDef::Enum(name) => self.global(name, true),
+                ScatteredDef::Mapping { name, typschm } => {
+                    self.global(name, true);
+                    if let Some((typschm, _)) = typschm {
+                        self.typ(&typschm.typ);
+                    }
+                }
+                ScatteredDef::Union { name, .. } => self.global(name, true),
+            },
+            Def::End(name) => self.global(name, false),
+            Def::Let(binding) => {
+                self.scoped(|w| w.exp(&binding.exp));
+                // Top-level lets are global.
+                let pat = match &binding.pat {
+                    (Pat::Typed(pat, typ), _) => {
+                        self.typ(typ);
+                        pat
+                    }
+                    pat => pat,
+                };
+                if let (Pat::Id(name), span) = pat {
+                    self.push(Symbol::Global(name.clone()), *span, true);
+                }
+            }
+            Def::TerminationMeasure { name, pat, exp } => {
+                self.global(name, false);
+                self.scoped(|w| {
+                    w.pat(pat);
+                    w.exp(exp);
+                });
+            }
+            Def::DefaultOrder(_)
+            | Def::Fixity(_)
+            | Def::Directive(_)
+            | Def::Attribute
+            | Def::Error(_) => {}
+        }
+    }
+
+    fn mapping_clause(&mut self, (clause, _): &Spanned<MappingClause>) {
+        self.scoped(|w| match clause {
+            MappingClause::Bidirectional(left, right) => {
+                w.mapping_pat(left);
+                w.mapping_pat(right);
+            }
+            MappingClause::Forwards(pat, exp) | MappingClause::Backwards(pat, exp) => {
+                w.mapping_pat(pat);
+                w.exp(exp);
+            }
+        });
+    }
+
+    fn mapping_pat(&mut self, (pat, _): &Spanned<MappingPat>) {
+        self.pat(&pat.pat);
+        if let Some(guard) = &pat.guard {
+            self.exp(guard);
+        }
+    }
+
+    /// Walk a pattern, binding any variables in the innermost scope.
+    fn pat(&mut self, (pat, span): &Spanned<Pat>) {
+        match pat {
+            Pat::Id(name) => {
+                if self.globals.constructors.contains(name) {
+                    self.push(Symbol::Global(name.clone()), *span, false);
+                } else {
+                    self.bind(name, *span);
+                }
+            }
+            Pat::App(name, args) => {
+                self.global(name, false);
+                for arg in args {
+                    self.pat(arg);
+                }
+            }
+            Pat::Tuple(pats) | Pat::Vector(pats) | Pat::List(pats) => {
+                for pat in pats {
+                    self.pat(pat);
+                }
+            }
+            Pat::Infix(left, _, right) => {
+                self.pat(left);
+                self.pat(right);
+            }
+            Pat::Typed(pat, typ) => {
+                self.pat(pat);
+                self.typ(typ);
+            }
+            Pat::As(pat, (name, span)) => {
+                self.pat(pat);
+                self.bind(name, *span);
+            }
+            Pat::Subrange((name, span), high, low) => {
+                self.bind(name, *span);
+                self.typ(high);
+                if let Some(low) = low {
+                    self.typ(low);
+                }
+            }
+            Pat::Struct(fields) => {
+                for (name, pat) in fields {
+                    self.field(name, false);
+                    self.pat(pat);
+                }
+            }
+            Pat::Wild | Pat::TyVar(_) | Pat::Lit(_) => {}
+        }
+    }
+
+    fn let_binding(&mut self, binding: &LetBinding) {
+        // The expression is evaluated before the pattern is bound.
+        self.exp(&binding.exp);
+        self.pat(&binding.pat);
+    }
+
+    fn match_arm(&mut self, (arm, _): &Spanned<MatchArm>) {
+        self.scoped(|w| {
+            w.pat(&arm.pat);
+            if let Some(guard) = &arm.guard {
+                w.exp(guard);
+            }
+            w.exp(&arm.body);
+        });
+    }
+
+    fn exp(&mut self, (exp, span): &Spanned<Exp>) {
+        match exp {
+            Exp::Id(name) => self.name(name, *span),
+            Exp::Block(statements) => self.scoped(|w| {
+                for statement in statements {
+                    match &statement.0 {
+                        // These bind over the rest of the block.
+                        Exp::Let(binding, None) | Exp::Var(binding, None) => w.let_binding(binding),
+                        _ => w.exp(statement),
+                    }
+                }
+            }),
+            Exp::Let(binding, body) | Exp::Var(binding, body) => self.scoped(|w| {
+                w.let_binding(binding);
+                if let Some(body) = body {
+                    w.exp(body);
+                }
+            }),
+            Exp::Assign(left, right) => {
+                self.exp(right);
+                match &left.as_ref() {
+                    // Assigning to an unknown name declares a new variable.
+                    (Exp::Id(name), span)
+                        if self.lookup(name).is_none()
+                            && !self.globals.names.contains(name)
+                            && !self.scopes.is_empty() =>
+                    {
+                        self.bind(name, *span)
+                    }
+                    _ => self.exp(left),
+                }
+            }
+            Exp::If(cond, then, else_) => {
+                self.exp(cond);
+                self.exp(then);
+                if let Some(else_) = else_ {
+                    self.exp(else_);
+                }
+            }
+            Exp::Match(exp, arms) | Exp::Try(exp, arms) => {
+                self.exp(exp);
+                for arm in arms {
+                    self.match_arm(arm);
+                }
+            }
+            Exp::Foreach {
+                var,
+                from,
+                to,
+                step,
+                body,
+                ..
+            } => {
+                self.exp(from);
+                self.exp(to);
+                if let Some(step) = step {
+                    self.exp(step);
+                }
+                self.scoped(|w| {
+                    w.bind(&var.0, var.1);
+                    w.exp(body);
+                });
+            }
+            Exp::While(a, b) | Exp::Repeat(a, b) | Exp::Index(a, b) => {
+                self.exp(a);
+                self.exp(b);
+            }
+            Exp::Call(name, args) => {
+                self.name(&name.0, name.1);
+                for arg in args {
+                    self.exp(arg);
+                }
+            }
+            Exp::Tuple(exps) | Exp::Vector(exps) | Exp::List(exps) => {
+                for exp in exps {
+                    self.exp(exp);
+                }
+            }
+            Exp::Field(exp, name) => {
+                self.exp(exp);
+                self.field(name, false);
+            }
+            Exp::Method(exp, name, args) => {
+                self.exp(exp);
+                self.field(name, false);
+                for arg in args {
+                    self.exp(arg);
+                }
+            }
+            Exp::Slice(exp, high, low) => {
+                self.exp(exp);
+                self.exp(high);
+                self.exp(low);
+            }
+            Exp::VectorUpdate(exp, updates) => {
+                self.exp(exp);
+                for update in updates {
+                    self.exp(&update.high);
+                    if let Some(low) = &update.low {
+                        self.exp(low);
+                    }
+                    self.exp(&update.value);
+                }
+            }
+            Exp::Struct(fields) => {
+                for (name, exp) in fields {
+                    self.field(name, false);
+                    self.exp(exp);
+                }
+            }
+            Exp::StructUpdate(exp, fields) => {
+                self.exp(exp);
+                for (name, exp) in fields {
+                    self.field(name, false);
+                    self.exp(exp);
+                }
+            }
+            Exp::Typed(exp, typ) => {
+                self.exp(exp);
+                self.typ(typ);
+            }
+            Exp::Infix(left, _, right) => {
+                self.exp(left);
+                self.exp(right);
+            }
+            Exp::Neg(exp) | Exp::Return(exp) | Exp::Throw(exp) => self.exp(exp),
+            Exp::Exit(exp) => {
+                if let Some(exp) = exp {
+                    self.exp(exp);
+                }
+            }
+            Exp::Assert(exp, message) => {
+                self.exp(exp);
+                if let Some(message) = message {
+                    self.exp(message);
+                }
+            }
+            Exp::Ref(name) => self.global(name, false),
+            Exp::Sizeof(typ) | Exp::Constraint(typ) => self.typ(typ),
+            Exp::TyVar(_) | Exp::Lit(_) | Exp::Error => {}
+        }
+    }
+
+    fn typ(&mut self, (typ, span): &Spanned<Typ>) {
+        match typ {
+            Typ::Id(name) => self.push(Symbol::Global(name.clone()), *span, false),
+            Typ::App(name, args) => {
+                self.global(name, false);
+                for arg in args {
+                    self.typ(arg);
+                }
+            }
+            Typ::Tuple(typs) | Typ::Set(typs) => {
+                for typ in typs {
+                    self.typ(typ);
+                }
+            }
+            Typ::Infix(left, _, right)
+            | Typ::Function(left, right)
+            | Typ::Bidirectional(left, right) => {
+                self.typ(left);
+                self.typ(right);
+            }
+            Typ::Neg(typ) => self.typ(typ),
+            Typ::Exist {
+                constraint, typ, ..
+            } => {
+                if let Some(constraint) = constraint {
+                    self.typ(constraint);
+                }
+                self.typ(typ);
+            }
+            Typ::Record(fields) => {
+                for (name, typ) in fields {
+                    self.field(name, true);
+                    self.typ(typ);
+                }
+            }
+            Typ::Wild | Typ::TyVar(_) | Typ::Lit(_) | Typ::Order(_) => {}
+        }
+    }
+}
+
+/// Find the identifier at `offset` in a file.
+pub fn symbol_at(file: &File, globals: &Globals, offset: usize) -> Option<Occurrence> {
+    occurrences(&file.ast, globals)
+        .into_iter()
+        .find(|occurrence| occurrence.span.start <= offset && offset <= occurrence.span.end)
+}
+
+/// Find all references to the symbol at `offset` in the file `uri`. Local
+/// variables are only searched for in that file.
+pub fn references<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    uri: &Url,
+    offset: usize,
+    include_declaration: bool,
+) -> Vec<Location> {
+    let files = files.collect::<Vec<_>>();
+    let globals = Globals::new(files.iter().copied());
+
+    let Some((_, file)) = files.iter().find(|(file_uri, _)| *file_uri == uri) else {
+        return Vec::new();
+    };
+    let Some(target) = symbol_at(file, &globals, offset) else {
+        return Vec::new();
+    };
+
+    let mut locations = Vec::new();
+    for (file_uri, file) in &files {
+        if matches!(target.symbol, Symbol::Local(_)) && *file_uri != uri {
+            continue;
+        }
+        for occurrence in occurrences(&file.ast, &globals) {
+            if occurrence.symbol == target.symbol
+                && (include_declaration || !occurrence.declaration)
+            {
+                let range = Range::new(
+                    file.source.position_at(occurrence.span.start),
+                    file.source.position_at(occurrence.span.end),
+                );
+                locations.push(Location::new((*file_uri).clone(), range));
+            }
+        }
+    }
+    locations.sort_by(|a, b| (&a.uri, a.range.start).cmp(&(&b.uri, b.range.start)));
+    locations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+
+    /// Find references to the first `|`-marked identifier in `a.sail`, and
+    /// return them as (file, text) pairs.
+    fn find(a: &str, b: &str, include_declaration: bool) -> Vec<(String, usize)> {
+        let fixities = sail_parser::FixityTable::default();
+        let offset = a.find('|').unwrap();
+        let a = File::new(a.replace('|', ""), PositionEncoding::default(), &fixities);
+        let b = File::new(b.to_string(), PositionEncoding::default(), &fixities);
+        let a_uri = Url::parse("file:///ws/a.sail").unwrap();
+        let b_uri = Url::parse("file:///ws/b.sail").unwrap();
+        let files = [(&a_uri, &a), (&b_uri, &b)];
+        references(files.into_iter(), &a_uri, offset, include_declaration)
+            .into_iter()
+            .map(|location| {
+                let file = if location.uri == a_uri { &a } else { &b };
+                let line = location.range.start.line as usize;
+                let name = file.source.text().lines().nth(line).unwrap().to_string();
+                (name, location.range.start.character as usize)
+            })
+            .collect()
+    }
+
+    const B: &str =
+        "register count : int\nenum mode = { User, Machine }\nfunction reset() = count = 0\n";
+
+    #[test]
+    fn test_globals() {
+        let a = "function f(m : |mode) -> int = {\n  count = count + 1;\n  match m { User => 0, _ => count }\n}\n";
+        assert_eq!(
+            find(a, B, true),
+            [
+                ("function f(m : mode) -> int = {".to_string(), 15),
+                ("enum mode = { User, Machine }".to_string(), 5),
+            ]
+        );
+
+        let a = "function f() -> int = {\n  |count = count + 1;\n  count\n}\n";
+        assert_eq!(
+            find(a, B, false),
+            [
+                ("  count = count + 1;".to_string(), 2),
+                ("  count = count + 1;".to_string(), 10),
+                ("  count".to_string(), 2),
+                ("function reset() = count = 0".to_string(), 19),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locals() {
+        // `count` here is a local variable that shadows the register.
+        let a = "function f(|count) -> int = {\n  let x = count;\n  x\n}\nfunction g() -> int = count\n";
+        assert_eq!(
+            find(a, B, true),
+            [
+                ("function f(count) -> int = {".to_string(), 11),
+                ("  let x = count;".to_string(), 10),
+            ]
+        );
+
+        // Enum members in patterns aren't variables.
+        let a = "function f(m) -> int = match m { |User => 0, x => { let y = x; y } }\n";
+        assert_eq!(
+            find(a, B, true),
+            [
+                (
+                    "function f(m) -> int = match m { User => 0, x => { let y = x; y } }"
+                        .to_string(),
+                    33
+                ),
+                ("enum mode = { User, Machine }".to_string(), 14),
+            ]
+        );
+
+        // Implicitly declared variables.
+        let a = "function f() -> int = {\n  |y = 1;\n  y\n}\n";
+        assert_eq!(
+            find(a, B, true),
+            [("  y = 1;".to_string(), 2), ("  y".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        let a = "struct point = { |x : int, y : int }\nfunction f(p : point) -> int = {\n  let x = p.x;\n  x\n}\n";
+        assert_eq!(
+            find(a, B, true),
+            [
+                ("struct point = { x : int, y : int }".to_string(), 17),
+                ("  let x = p.x;".to_string(), 12),
+            ]
+        );
+    }
+}
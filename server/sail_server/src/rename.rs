@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use chumsky::Parser;
+use sail_parser::{Span, Token};
+use tower_lsp::lsp_types::{Range, TextEdit, Url, WorkspaceEdit};
+
+use crate::definitions::DefinitionKind;
+use crate::file::File;
+use crate::references::{occurrences, symbol_at, Globals, Occurrence, Symbol};
+
+/// The span of the name itself. Vals can be named with strings, in which
+/// case the quotes are excluded.
+fn name_span(text: &str, span: Span) -> Span {
+    let name = &text[span.start..span.end];
+    if name.len() >= 2 && name.starts_with('"') && name.ends_with('"') {
+        Span::new(span.start + 1, span.end - 1)
+    } else {
+        span
+    }
+}
+
+/// Is `name` defined as `kind` anywhere?
+fn is_defined_as(files: &[(&Url, &File)], name: &str, kind: DefinitionKind) -> bool {
+    files.iter().any(|(_, file)| {
+        file.definitions
+            .get(name)
+            .is_some_and(|definitions| definitions.iter().any(|d| d.kind == kind))
+    })
+}
+
+/// Find the symbol to rename and check that it can be renamed. Returns the
+/// symbol and its current name.
+fn target(
+    files: &[(&Url, &File)],
+    globals: &Globals,
+    uri: &Url,
+    offset: usize,
+) -> Result<(Occurrence, String), String> {
+    let Some((_, file)) = files.iter().find(|(file_uri, _)| *file_uri == uri) else {
+        return Err("The file isn't open.".to_string());
+    };
+    let Some(target) = symbol_at(file, globals, offset) else {
+        return Err("There is no symbol here to rename.".to_string());
+    };
+    let span = name_span(file.source.text(), target.span);
+    let name = file.source.text()[span.start..span.end].to_string();
+
+    match &target.symbol {
+        Symbol::Global(global) => {
+            if !files
+                .iter()
+                .any(|(_, file)| file.definitions.contains_key(global))
+            {
+                return Err(format!("`{}` isn't defined in the workspace.", global));
+            }
+            if let Some(bitfield) = global.strip_prefix("Mk_") {
+                if is_defined_as(files, bitfield, DefinitionKind::Bitfield) {
+                    return Err(format!(
+                        "`{}` is generated from the bitfield `{}`. Rename that instead.",
+                        global, bitfield
+                    ));
+                }
+            }
+        }
+        Symbol::Field(field) => {
+            let declared = files.iter().any(|(_, file)| {
+                occurrences(&file.ast, globals)
+                    .iter()
+                    .any(|o| o.declaration && o.symbol == target.symbol)
+            });
+            if !declared {
+                return Err(format!(
+                    "The field `{}` isn't defined in the workspace.",
+                    field
+                ));
+            }
+        }
+        Symbol::Local(_) => {}
+    }
+    Ok((target, name))
+}
+
+/// Check that the symbol at `offset` can be renamed, and return its range.
+pub fn prepare_rename<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    uri: &Url,
+    offset: usize,
+) -> Result<Range, String> {
+    let files = files.collect::<Vec<_>>();
+    let globals = Globals::new(files.iter().copied());
+    let (target, _) = target(&files, &globals, uri, offset)?;
+
+    let (_, file) = files.iter().find(|(file_uri, _)| *file_uri == uri).unwrap();
+    let span = name_span(file.source.text(), target.span);
+    Ok(Range::new(
+        file.source.position_at(span.start),
+        file.source.position_at(span.end),
+    ))
+}
+
+/// Rename the symbol at `offset` everywhere in the workspace. This fails if
+/// `new_name` isn't a valid identifier or it would clash with something.
+pub fn rename<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    uri: &Url,
+    offset: usize,
+    new_name: &str,
+) -> Result<WorkspaceEdit, String> {
+    let files = files.collect::<Vec<_>>();
+    let globals = Globals::new(files.iter().copied());
+    let (target, old_name) = target(&files, &globals, uri, offset)?;
+
+    if sail_parser::keyword(new_name).is_some() {
+        return Err(format!("`{}` is a keyword.", new_name));
+    }
+    let tokens = sail_parser::lexer().parse(new_name).into_result();
+    if !matches!(tokens.as_deref(), Ok([(Token::Id(id), _)]) if id == new_name) {
+        return Err(format!("`{}` isn't a valid identifier.", new_name));
+    }
+    if new_name == old_name {
+        return Ok(WorkspaceEdit::default());
+    }
+
+    // Bitfields also rename the generated `Mk_` function.
+    let constructor = match &target.symbol {
+        Symbol::Global(name) if is_defined_as(&files, name, DefinitionKind::Bitfield) => {
+            Some(Symbol::Global(format!("Mk_{}", name)))
+        }
+        _ => None,
+    };
+
+    let mut new_names = vec![new_name.to_string()];
+    if constructor.is_some() {
+        new_names.push(format!("Mk_{}", new_name));
+    }
+
+    // The new name can't already be defined.
+    if let Symbol::Global(_) = &target.symbol {
+        if let Some(name) = new_names.iter().find(|name| globals.contains(name)) {
+            return Err(format!("`{}` is already defined.", name));
+        }
+    }
+
+    let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+    for (file_uri, file) in &files {
+        if matches!(target.symbol, Symbol::Local(_)) && file_uri != &uri {
+            continue;
+        }
+        let text = file.source.text();
+        let occurrences = occurrences(&file.ast, &globals);
+
+        if let Symbol::Field(_) = &target.symbol {
+            if occurrences
+                .iter()
+                .any(|o| o.declaration && o.symbol == Symbol::Field(new_name.to_string()))
+            {
+                return Err(format!("There is already a field called `{}`.", new_name));
+            }
+        }
+
+        for occurrence in &occurrences {
+            let replacement = if occurrence.symbol == target.symbol {
+                new_name.to_string()
+            } else if Some(&occurrence.symbol) == constructor.as_ref() {
+                format!("Mk_{}", new_name)
+            } else {
+                continue;
+            };
+
+            // Check that the new name wouldn't clash with anything else used
+            // in the same definition, e.g. a local variable.
+            if let Some((_, def_span)) = file.ast.defs.iter().find(|(_, span)| {
+                span.start <= occurrence.span.start && occurrence.span.end <= span.end
+            }) {
+                let clash = occurrences.iter().any(|other| {
+                    def_span.start <= other.span.start
+                        && other.span.end <= def_span.end
+                        && other.symbol != occurrence.symbol
+                        && text[other.span.start..other.span.end] == replacement
+                });
+                if clash {
+                    return Err(format!(
+                        "`{}` is already used where `{}` is used.",
+                        replacement, old_name
+                    ));
+                }
+            }
+
+            let span = name_span(text, occurrence.span);
+            changes
+                .entry((*file_uri).clone())
+                .or_default()
+                .push(TextEdit::new(
+                    Range::new(
+                        file.source.position_at(span.start),
+                        file.source.position_at(span.end),
+                    ),
+                    replacement,
+                ));
+        }
+    }
+
+    Ok(WorkspaceEdit::new(changes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+
+    const CODE: &str = r#"bitfield Status : bits(8) = { A : 0 }
+register status : Status = Mk_Status(0x00)
+val execute : int -> unit
+scattered function execute
+function clause execute(x) = {
+  let y = x;
+  ()
+}
+function clause execute(_) = ()
+val to_str_int : int -> string
+overload to_str = {to_str_int}
+"#;
+
+    /// Rename the symbol at the first `|` and return the edits as
+    /// (line, character, new text), or the error.
+    fn do_rename(marked: &str, new_name: &str) -> Result<Vec<(u32, u32, String)>, String> {
+        let fixities = sail_parser::FixityTable::default();
+        let offset = marked.find('|').unwrap();
+        let file = File::new(
+            marked.replace('|', ""),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        let edit = rename([(&uri, &file)].into_iter(), &uri, offset, new_name)?;
+        let mut edits = edit
+            .changes
+            .unwrap_or_default()
+            .remove(&uri)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|edit| {
+                (
+                    edit.range.start.line,
+                    edit.range.start.character,
+                    edit.new_text,
+                )
+            })
+            .collect::<Vec<_>>();
+        edits.sort();
+        Ok(edits)
+    }
+
+    fn mark(pattern: &str) -> String {
+        CODE.replacen(pattern, &format!("|{}", pattern), 1)
+    }
+
+    #[test]
+    fn test_rename() {
+        // Scattered clauses are renamed too.
+        assert_eq!(
+            do_rename(&mark("execute :"), "run").unwrap(),
+            [
+                (2, 4, "run".to_string()),
+                (3, 19, "run".to_string()),
+                (4, 16, "run".to_string()),
+                (8, 16, "run".to_string()),
+            ]
+        );
+
+        // Bitfields rename their `Mk_` constructor.
+        assert_eq!(
+            do_rename(&mark("Status :"), "Flags").unwrap(),
+            [
+                (0, 9, "Flags".to_string()),
+                (1, 18, "Flags".to_string()),
+                (1, 27, "Mk_Flags".to_string()),
+            ]
+        );
+
+        // Overload members.
+        assert_eq!(
+            do_rename(&mark("to_str_int :"), "int_to_str").unwrap(),
+            [
+                (9, 4, "int_to_str".to_string()),
+                (10, 19, "int_to_str".to_string())
+            ]
+        );
+
+        // Local variables.
+        assert_eq!(
+            do_rename(&mark("y = x"), "z").unwrap(),
+            [(5, 6, "z".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_rename_errors() {
+        assert_eq!(
+            do_rename(&mark("execute :"), "match"),
+            Err("`match` is a keyword.".to_string())
+        );
+        assert_eq!(
+            do_rename(&mark("execute :"), "1abc"),
+            Err("`1abc` isn't a valid identifier.".to_string())
+        );
+        assert_eq!(
+            do_rename(&mark("execute :"), "status"),
+            Err("`status` is already defined.".to_string())
+        );
+        assert_eq!(
+            do_rename(&mark("Mk_Status"), "Mk_Flags"),
+            Err(
+                "`Mk_Status` is generated from the bitfield `Status`. Rename that instead."
+                    .to_string()
+            )
+        );
+        // Renaming `x` to `y` would change what `y = x` means.
+        assert_eq!(
+            do_rename(&mark("x) = {"), "y"),
+            Err("`y` is already used where `x` is used.".to_string())
+        );
+        assert_eq!(
+            do_rename(&mark("int ->"), "integer"),
+            Err("`int` isn't defined in the workspace.".to_string())
+        );
+    }
+}
@@ -0,0 +1,306 @@
+// A rope of lines, so that editing text and finding lines take logarithmic
+// time however long it is. It is an implicit treap: a binary tree of the
+// lines in order, which stays balanced because each node has a random
+// priority and nodes with higher priorities are kept above lower ones.
+// Nodes are shared between copies, so cloning is cheap and an edit only
+// copies the nodes it changes.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+type Tree = Option<Arc<Node>>;
+
+#[derive(Clone)]
+struct Node {
+    // One line, including its line ending.
+    line: String,
+    priority: u64,
+    // The number of lines and bytes in this subtree.
+    lines: usize,
+    bytes: usize,
+    left: Tree,
+    right: Tree,
+}
+
+impl Node {
+    fn new(line: &str) -> Self {
+        Self {
+            line: line.to_string(),
+            priority: priority(),
+            lines: 1,
+            bytes: line.len(),
+            left: None,
+            right: None,
+        }
+    }
+
+    fn update(&mut self) {
+        self.lines = lines(&self.left) + 1 + lines(&self.right);
+        self.bytes = bytes(&self.left) + self.line.len() + bytes(&self.right);
+    }
+}
+
+fn lines(tree: &Tree) -> usize {
+    tree.as_ref().map_or(0, |node| node.lines)
+}
+
+fn bytes(tree: &Tree) -> usize {
+    tree.as_ref().map_or(0, |node| node.bytes)
+}
+
+/// A priority for a new node. These only need to be spread out, not
+/// unpredictable, so this is SplitMix64 on a counter.
+fn priority() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let mut z = STATE.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Join two trees, with all the lines of `left` before those of `right`.
+fn merge(left: Tree, right: Tree) -> Tree {
+    match (left, right) {
+        (None, tree) | (tree, None) => tree,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority > right.priority {
+                let node = Arc::make_mut(&mut left);
+                node.right = merge(node.right.take(), Some(right));
+                node.update();
+                Some(left)
+            } else {
+                let node = Arc::make_mut(&mut right);
+                node.left = merge(Some(left), node.left.take());
+                node.update();
+                Some(right)
+            }
+        }
+    }
+}
+
+/// Split a tree into the first `n` lines and the rest.
+fn split(tree: Tree, n: usize) -> (Tree, Tree) {
+    let Some(mut root) = tree else {
+        return (None, None);
+    };
+    let node = Arc::make_mut(&mut root);
+    let left_lines = lines(&node.left);
+    if n <= left_lines {
+        let (first, rest) = split(node.left.take(), n);
+        node.left = rest;
+        node.update();
+        (first, Some(root))
+    } else {
+        let (first, rest) = split(node.right.take(), n - left_lines - 1);
+        node.right = first;
+        node.update();
+        (Some(root), rest)
+    }
+}
+
+fn build(lines: &[&str]) -> Tree {
+    lines.iter().fold(None, |tree, line| {
+        merge(tree, Some(Arc::new(Node::new(line))))
+    })
+}
+
+fn push_text(tree: &Tree, text: &mut String) {
+    if let Some(node) = tree {
+        push_text(&node.left, text);
+        text.push_str(&node.line);
+        push_text(&node.right, text);
+    }
+}
+
+/// Split text into lines, each with its line ending. Like VSCode, `\n`,
+/// `\r\n` and `\r` all end lines. The last line has no ending, so it can be
+/// empty.
+fn split_lines(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for i in 0..bytes.len() {
+        let end_of_line = match bytes[i] {
+            b'\n' => true,
+            b'\r' => bytes.get(i + 1) != Some(&b'\n'),
+            _ => false,
+        };
+        if end_of_line {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    lines.push(&text[start..]);
+    lines
+}
+
+#[derive(Clone)]
+pub struct Rope {
+    // This always has at least one line.
+    root: Tree,
+}
+
+impl Rope {
+    pub fn new(text: &str) -> Self {
+        Self {
+            root: build(&split_lines(text)),
+        }
+    }
+
+    /// The length in bytes.
+    pub fn len(&self) -> usize {
+        bytes(&self.root)
+    }
+
+    #[cfg(test)]
+    pub fn line_count(&self) -> usize {
+        lines(&self.root)
+    }
+
+    /// The start of line `index` in bytes, and the line including its line
+    /// ending, or `None` if there aren't that many lines.
+    pub fn line(&self, mut index: usize) -> Option<(usize, &str)> {
+        let mut node = self.root.as_deref()?;
+        let mut start = 0;
+        loop {
+            let left_lines = lines(&node.left);
+            if index < left_lines {
+                node = node.left.as_deref()?;
+            } else if index == left_lines {
+                return Some((start + bytes(&node.left), &node.line));
+            } else {
+                index -= left_lines + 1;
+                start += bytes(&node.left) + node.line.len();
+                node = node.right.as_deref()?;
+            }
+        }
+    }
+
+    /// The line containing the byte at `offset` as (line index, start of
+    /// the line, line). Offsets past the end are in the last line.
+    pub fn line_at(&self, offset: usize) -> (usize, usize, &str) {
+        let mut node = self.root.as_deref().expect("ropes have a line");
+        let (mut index, mut start) = (0, 0);
+        loop {
+            let left_bytes = bytes(&node.left);
+            if offset < start + left_bytes {
+                node = node.left.as_deref().unwrap();
+                continue;
+            }
+            let line_start = start + left_bytes;
+            let line_index = index + lines(&node.left);
+            match &node.right {
+                Some(right) if offset >= line_start + node.line.len() => {
+                    index = line_index + 1;
+                    start = line_start + node.line.len();
+                    node = right;
+                }
+                _ => return (line_index, line_start, &node.line),
+            }
+        }
+    }
+
+    /// Replace the bytes in `range` with `text`. Only the lines that the
+    /// range touches are changed.
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        let (mut first, mut start, _) = self.line_at(range.start);
+        // If the previous line ends with `\r` then it is joined to the new
+        // text if that starts with `\n`.
+        if let Some((previous_start, previous)) = first.checked_sub(1).and_then(|i| self.line(i)) {
+            if previous.ends_with('\r') {
+                first -= 1;
+                start = previous_start;
+            }
+        }
+        let (last, _, _) = self.line_at(range.end);
+
+        let (before, rest) = split(self.root.take(), first);
+        let (middle, after) = split(rest, last + 1 - first);
+        let mut old = String::new();
+        push_text(&middle, &mut old);
+
+        let mut new = String::with_capacity(old.len() + text.len());
+        new.push_str(&old[..range.start - start]);
+        new.push_str(text);
+        new.push_str(&old[range.end - start..]);
+        let mut lines = split_lines(&new);
+        // The old lines ended with a line ending unless they were the last
+        // ones, so the new text does too and its empty last line is
+        // really the start of `after`.
+        if after.is_some() {
+            debug_assert_eq!(lines.last(), Some(&""));
+            lines.pop();
+        }
+        self.root = merge(merge(before, build(&lines)), after);
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.len());
+        push_text(&self.root, &mut text);
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(rope: &Rope, text: &str) {
+        assert_eq!(rope.text(), text);
+        assert_eq!(rope.len(), text.len());
+        let expected = split_lines(text);
+        assert_eq!(rope.line_count(), expected.len());
+        let mut start = 0;
+        for (index, line) in expected.iter().enumerate() {
+            assert_eq!(rope.line(index), Some((start, *line)));
+            for offset in start..start + line.len() {
+                assert_eq!(rope.line_at(offset), (index, start, *line));
+            }
+            start += line.len();
+        }
+        assert_eq!(rope.line(expected.len()), None);
+        let last = expected.len() - 1;
+        assert_eq!(rope.line_at(text.len()).0, last);
+    }
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(split_lines(""), [""]);
+        assert_eq!(split_lines("a\nb\r\nc\rd"), ["a\n", "b\r\n", "c\r", "d"]);
+        assert_eq!(split_lines("a\n\r"), ["a\n", "\r", ""]);
+    }
+
+    #[test]
+    fn test_rope() {
+        let mut text = "ab\ncd\r\nef\rgh\n\nij".to_string();
+        let mut rope = Rope::new(&text);
+        check(&rope, &text);
+
+        // Random edits, including ones that join or split `\r\n`.
+        let mut seed = 1u64;
+        let mut random = |n: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        let inserts = ["", "x", "\n", "\r", "\r\n", "yz\nw", "\n\n"];
+        for _ in 0..2000 {
+            let start = random(text.len() + 1);
+            let end = (start + random(4)).min(text.len());
+            let insert = inserts[random(inserts.len())];
+
+            let (copy, old) = (rope.clone(), text.clone());
+            rope.replace(start..end, insert);
+            text.replace_range(start..end, insert);
+            check(&rope, &text);
+            // Copies aren't changed.
+            assert_eq!(copy.text(), old);
+        }
+    }
+}
@@ -1 +1,269 @@
+use sail_parser::{Def, Span, Spanned, Token, Typ, ValSpec};
+use tower_lsp::lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel, SignatureHelp,
+    SignatureInformation, Url,
+};
 
+use crate::file::File;
+use crate::hover::doc_comment;
+
+/// Find the function call that `offset` is in. This returns the name of the
+/// function and the index of the argument that `offset` is in.
+///
+/// This works on tokens rather than the AST because the call is usually
+/// incomplete while you are typing it.
+pub fn call_at(tokens: &[(Token, Span)], offset: usize) -> Option<(&str, u32)> {
+    // Tokens that end before the cursor.
+    let end = tokens.partition_point(|(_, span)| span.end <= offset);
+
+    // `foo()` is a single token so we have to handle it specially.
+    if let Some((Token::Unit, span)) = tokens.get(end) {
+        if span.start < offset {
+            return match end.checked_sub(1).map(|i| &tokens[i].0) {
+                Some(Token::Id(name)) => Some((name, 0)),
+                _ => None,
+            };
+        }
+    }
+
+    let mut depth = 0;
+    let mut commas = 0;
+    for i in (0..end).rev() {
+        match &tokens[i].0 {
+            Token::RightBracket | Token::RightSquareBracket | Token::RightCurlyBracket => {
+                depth += 1
+            }
+            Token::LeftBracket | Token::LeftSquareBracket if depth > 0 => depth -= 1,
+            Token::LeftCurlyBracket if depth > 0 => depth -= 1,
+            Token::Comma if depth == 0 => commas += 1,
+            Token::LeftBracket => {
+                if let Some((Token::Id(name), _)) = i.checked_sub(1).map(|i| &tokens[i]) {
+                    return Some((name, commas));
+                }
+                // A tuple or parenthesised expression inside the call.
+                commas = 0;
+            }
+            // A vector inside the call.
+            Token::LeftSquareBracket => commas = 0,
+            // We're in a block so not in a call.
+            Token::LeftCurlyBracket | Token::Semicolon if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Get the text for a span, with whitespace collapsed.
+fn source_text(text: &str, span: Span) -> String {
+    text[span.start..span.end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Build the signature for a val, e.g. `foo(bits('n), int) -> unit`. Any
+/// `forall` is shown in the documentation rather than the label, along with
+/// the val's doc comment.
+fn signature(val: &ValSpec, val_span: Span, text: &str) -> SignatureInformation {
+    let (typschm, typschm_span) = &val.typschm;
+
+    let (params, ret): (Vec<&Spanned<Typ>>, Option<&Spanned<Typ>>) = match &typschm.typ.0 {
+        Typ::Function(arg, ret) => match &arg.0 {
+            Typ::Tuple(args) => (args.iter().collect(), Some(ret)),
+            Typ::Id(name) if name == "unit" => (Vec::new(), Some(ret)),
+            _ => (vec![arg], Some(ret)),
+        },
+        _ => (Vec::new(), None),
+    };
+
+    let mut label = format!("{}(", val.name.0);
+    let mut parameters = Vec::new();
+    for (i, (_, span)) in params.into_iter().enumerate() {
+        if i > 0 {
+            label.push_str(", ");
+        }
+        let start = label.encode_utf16().count() as u32;
+        label.push_str(&source_text(text, *span));
+        let end = label.encode_utf16().count() as u32;
+        parameters.push(ParameterInformation {
+            label: ParameterLabel::LabelOffsets([start, end]),
+            documentation: None,
+        });
+    }
+    label.push(')');
+    if let Some((_, span)) = ret {
+        label.push_str(" -> ");
+        label.push_str(&source_text(text, *span));
+    }
+
+    let mut documentation = Vec::new();
+    if !typschm.quantifiers.is_empty() {
+        let quantifier = source_text(text, Span::new(typschm_span.start, typschm.typ.1.start));
+        documentation.push(format!("```sail\n{}\n```", quantifier));
+    }
+    if let Some(comment) = doc_comment(text, val_span.start) {
+        documentation.push(comment);
+    }
+
+    SignatureInformation {
+        label,
+        documentation: (!documentation.is_empty()).then(|| {
+            Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: documentation.join("\n\n"),
+            })
+        }),
+        parameters: Some(parameters),
+        active_parameter: None,
+    }
+}
+
+/// Signature help for a call to `name`. If `name` is an overload then every
+/// overloaded val is shown as an alternative signature.
+pub fn signature_help<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    name: &str,
+    active_parameter: u32,
+) -> Option<SignatureHelp> {
+    let mut files = files.collect::<Vec<_>>();
+    files.sort_by_key(|(uri, _)| *uri);
+
+    let mut names = vec![name];
+    for (_, file) in &files {
+        for (def, _) in &file.ast.defs {
+            if let Def::Overload(overload) = def {
+                if overload.name.0 == name {
+                    for (member, _) in &overload.members {
+                        if !names.contains(&member.as_str()) {
+                            names.push(member);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut signatures = Vec::new();
+    for name in names {
+        for (_, file) in &files {
+            for (def, span) in &file.ast.defs {
+                if let Def::Val(val) = def {
+                    if val.name.0 == name {
+                        signatures.push(signature(val, *span, file.source.text()));
+                    }
+                }
+            }
+        }
+    }
+
+    if signatures.is_empty() {
+        return None;
+    }
+
+    // Pick the first signature that has enough parameters.
+    let active_signature = signatures
+        .iter()
+        .position(|signature| {
+            signature
+                .parameters
+                .as_ref()
+                .is_some_and(|p| p.len() > active_parameter as usize)
+        })
+        .unwrap_or(0);
+
+    Some(SignatureHelp {
+        signatures,
+        active_signature: Some(active_signature as u32),
+        active_parameter: Some(active_parameter),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+    use chumsky::Parser;
+
+    fn call_at_marker(code: &str) -> Option<(String, u32)> {
+        let offset = code.find('|').unwrap();
+        let code = code.replace('|', "");
+        let tokens = sail_parser::lexer().parse(&code).into_result().unwrap();
+        call_at(&tokens, offset).map(|(name, arg)| (name.to_string(), arg))
+    }
+
+    #[test]
+    fn test_call_at() {
+        let call = |name: &str, arg| Some((name.to_string(), arg));
+        assert_eq!(call_at_marker("foo(|"), call("foo", 0));
+        assert_eq!(call_at_marker("foo(a, |b)"), call("foo", 1));
+        assert_eq!(call_at_marker("foo(a, bar(b, c), |"), call("foo", 2));
+        assert_eq!(call_at_marker("foo(a, bar(b, |c))"), call("bar", 1));
+        assert_eq!(call_at_marker("foo(a, (b, |c))"), call("foo", 1));
+        assert_eq!(call_at_marker("foo(a, [b, |c])"), call("foo", 1));
+        assert_eq!(call_at_marker("foo(|)"), call("foo", 0));
+        assert_eq!(call_at_marker("foo(a)|"), None);
+        assert_eq!(call_at_marker("foo(a); |"), None);
+        assert_eq!(call_at_marker("foo(a, { |"), None);
+    }
+
+    #[test]
+    fn test_signature_help() {
+        let fixities = sail_parser::FixityTable::default();
+        let file = File::new(
+            r#"
+// Sign extend.
+val sign_extend : forall 'n 'm, 'm >= 'n. (implicit('m), bits('n)) -> bits('m)
+val zeros : unit -> bits(8)
+val to_str_int : int -> string
+val to_str_bits : forall 'n. bits('n) -> string
+overload to_str = {to_str_int, to_str_bits}
+overload to_str = {to_str_bits}
+"#
+            .to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        let files = || [(&uri, &file)].into_iter();
+
+        let help = signature_help(files(), "sign_extend", 1).unwrap();
+        assert_eq!(help.signatures.len(), 1);
+        let signature = &help.signatures[0];
+        assert_eq!(
+            signature.label,
+            "sign_extend(implicit('m), bits('n)) -> bits('m)"
+        );
+        assert_eq!(
+            signature.parameters.as_ref().unwrap()[1].label,
+            ParameterLabel::LabelOffsets([26, 34])
+        );
+        assert_eq!(
+            signature.documentation,
+            Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "```sail\nforall 'n 'm, 'm >= 'n.\n```\n\nSign extend.".to_string(),
+            }))
+        );
+        assert_eq!(help.active_parameter, Some(1));
+
+        let help = signature_help(files(), "zeros", 0).unwrap();
+        assert_eq!(help.signatures[0].label, "zeros() -> bits(8)");
+        assert_eq!(help.signatures[0].documentation, None);
+
+        let help = signature_help(files(), "to_str", 0).unwrap();
+        let labels = help
+            .signatures
+            .iter()
+            .map(|s| s.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                "to_str_int(int) -> string",
+                "to_str_bits(bits('n)) -> string"
+            ]
+        );
+
+        assert_eq!(signature_help(files(), "nothing", 0), None);
+    }
+}
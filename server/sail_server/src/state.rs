@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock},
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+use tower_lsp::lsp_types::{Diagnostic, MessageType, Url};
+
+use crate::{
+    cache, diagnostics, file::File, files, filter, project, text_document::PositionEncoding,
+};
+
+// The workspace. Requests use an immutable snapshot of this so they never
+// wait for changes. Files and the larger members are shared between
+// snapshots, and copied when they are changed, so a change only copies what
+// it touches.
+#[derive(Clone, Default)]
+pub struct State {
+    pub disk_files: Arc<files::Files>,
+    pub open_files: HashMap<Url, Arc<File>>,
+    // Operator fixities from the `infix` declarations in all files.
+    pub fixities: sail_parser::FixityTable,
+    // The declarations that `fixities` was built from, for each file that
+    // has any. The files are sorted so that conflicting declarations are
+    // resolved the same way every time.
+    fixity_declarations: Arc<BTreeMap<Url, Vec<(String, sail_parser::Fixity)>>>,
+    pub project_config: project::ProjectConfig,
+    // The files in each target. Names are only resolved in the active one.
+    pub project: Arc<project::Project>,
+    pub compiler_config: diagnostics::CompilerConfig,
+    // Errors from the last time the Sail compiler was run. These are kept
+    // until it is run again.
+    pub compiler_diagnostics: Arc<HashMap<Url, Vec<Diagnostic>>>,
+    // The latest version of each open file, from the client.
+    pub versions: HashMap<Url, i32>,
+    // Whether the client supports `$/progress` notifications.
+    pub work_done_progress: bool,
+    // Whether the client can watch files for us. If not we do it ourselves.
+    pub client_watches_files: bool,
+    // The units of the characters in positions, agreed with the client.
+    pub position_encoding: PositionEncoding,
+    pub cache_config: cache::CacheConfig,
+    pub scan_config: filter::ScanConfig,
+}
+
+impl State {
+    /// Get all the files in the active target, ignoring files on disk that
+    /// are also open. Open files are always included.
+    pub fn all_files(&self) -> impl Iterator<Item = (&Url, &File)> {
+        self.open_files
+            .iter()
+            .map(|(uri, file)| (uri, file.as_ref()))
+            .chain(self.disk_files.all_files().filter(|(uri, _)| {
+                !self.open_files.contains_key(uri) && self.project.contains(uri)
+            }))
+    }
+
+    /// The diagnostics for a file, from parsing it (if it is open) and from
+    /// the compiler.
+    pub fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        let parse = self.open_files.get(uri).map(|file| &file.diagnostics);
+        let compiler = self.compiler_diagnostics.get(uri);
+        parse
+            .into_iter()
+            .chain(compiler)
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Reload the project from the configuration and project files.
+    /// Returns messages to log, including any errors.
+    pub fn load_project(&mut self) -> Vec<(MessageType, String)> {
+        let (project, errors) =
+            project::Project::load(&self.project_config, self.disk_files.folders());
+        self.project = Arc::new(project);
+
+        let mut messages = errors
+            .into_iter()
+            .map(|error| (MessageType::ERROR, format!("project error: {}", error)))
+            .collect::<Vec<_>>();
+        if let Some(target) = self.project.active_target() {
+            messages.push((
+                MessageType::INFO,
+                format!(
+                    "active target: {} ({} files)",
+                    target.name,
+                    target.files.len()
+                ),
+            ));
+        }
+        messages
+    }
+
+    /// A file in the active target. Open files are always included.
+    fn file(&self, uri: &Url) -> Option<&File> {
+        match self.open_files.get(uri) {
+            Some(file) => Some(file),
+            None => self
+                .disk_files
+                .get(uri)
+                .filter(|_| self.project.contains(uri)),
+        }
+    }
+
+    /// Check the declarations in all files, e.g. after the active target has
+    /// changed. If the fixity table has changed then every file is reparsed
+    /// and this returns true.
+    pub fn update_fixities(&mut self) -> bool {
+        let declarations = self
+            .all_files()
+            .filter(|(_, file)| !file.fixities.is_empty())
+            .map(|(uri, file)| (uri.clone(), file.fixities.clone()))
+            .collect::<BTreeMap<_, _>>();
+        if declarations == *self.fixity_declarations {
+            return false;
+        }
+        self.fixity_declarations = Arc::new(declarations);
+        self.rebuild_fixities()
+    }
+
+    /// Like `update_fixities()` but only checks `uris`, which have been
+    /// changed, opened, closed or removed.
+    pub fn update_fixities_of<'a>(&mut self, uris: impl IntoIterator<Item = &'a Url>) -> bool {
+        let mut changed = false;
+        for uri in uris {
+            let declarations = self
+                .file(uri)
+                .map(|file| &file.fixities)
+                .filter(|fixities| !fixities.is_empty());
+            if declarations == self.fixity_declarations.get(uri) {
+                continue;
+            }
+            let declarations = declarations.cloned();
+            let all = Arc::make_mut(&mut self.fixity_declarations);
+            match declarations {
+                Some(declarations) => all.insert(uri.clone(), declarations),
+                None => all.remove(uri),
+            };
+            changed = true;
+        }
+        changed && self.rebuild_fixities()
+    }
+
+    /// Rebuild the fixity table from the declarations. If it has changed
+    /// then every file is reparsed and this returns true.
+    fn rebuild_fixities(&mut self) -> bool {
+        let mut fixities = sail_parser::FixityTable::default();
+        for declarations in self.fixity_declarations.values() {
+            fixities.extend(declarations.iter().cloned());
+        }
+        if fixities == self.fixities {
+            return false;
+        }
+        self.fixities = fixities;
+
+        // Edited files are parsed when they are analysed.
+        for file in self
+            .open_files
+            .values_mut()
+            .filter(|file| !file.dirty)
+            .map(Arc::make_mut)
+            .chain(Arc::make_mut(&mut self.disk_files).all_files_mut())
+        {
+            file.parse(&self.fixities);
+        }
+        true
+    }
+
+    /// Add the files from a workspace scan, which were parsed with
+    /// `fixities`. Returns true if other files were reparsed.
+    pub fn add_scanned(
+        &mut self,
+        files: HashMap<Url, File>,
+        fixities: sail_parser::FixityTable,
+    ) -> bool {
+        let mut reparsed = false;
+        if fixities != self.fixities {
+            self.fixities = fixities;
+            for file in self
+                .open_files
+                .values_mut()
+                .filter(|file| !file.dirty)
+                .map(Arc::make_mut)
+                .chain(Arc::make_mut(&mut self.disk_files).all_files_mut())
+            {
+                file.parse(&self.fixities);
+            }
+            reparsed = true;
+        }
+        Arc::make_mut(&mut self.disk_files).add_scanned(files);
+        self.update_fixities() || reparsed
+    }
+
+    /// Update the files on disk after a rescan. `files` were read and parsed
+    /// with `fixities`, and `removed` no longer exist. Returns true if the
+    /// fixities changed and all the files were reparsed.
+    pub fn update_disk_files(
+        &mut self,
+        files: HashMap<Url, File>,
+        fixities: sail_parser::FixityTable,
+        removed: &[Url],
+    ) -> bool {
+        let disk_files = Arc::make_mut(&mut self.disk_files);
+        for uri in removed {
+            disk_files.remove_file(uri);
+        }
+        let uris = files.keys().cloned().collect::<Vec<_>>();
+        for (uri, file) in files {
+            disk_files.add_file(uri, file);
+        }
+        if self.update_fixities_of(removed.iter().chain(&uris)) {
+            return true;
+        }
+        // The fixities were guessed before the files were read.
+        if fixities != self.fixities {
+            for uri in &uris {
+                if let Some(file) = Arc::make_mut(&mut self.disk_files).get_mut(uri) {
+                    file.parse(&self.fixities);
+                }
+            }
+        }
+        false
+    }
+
+    /// Analyse any open files that have been edited since they were last
+    /// analysed. Returns true if the fixities changed.
+    pub fn analyze_edited_files(&mut self) -> bool {
+        let mut analyzed = Vec::new();
+        for (uri, file) in self.open_files.iter_mut().filter(|(_, file)| file.dirty) {
+            Arc::make_mut(file).analyze(&self.fixities);
+            analyzed.push(uri.clone());
+        }
+        self.update_fixities_of(&analyzed)
+    }
+}
+
+/// The current state, shared between handlers. Any number of readers can
+/// take snapshots while one writer at a time makes changes.
+#[derive(Default)]
+pub struct SharedState {
+    current: RwLock<Arc<State>>,
+    writer: Mutex<()>,
+}
+
+impl SharedState {
+    /// The current state. Later changes don't affect it.
+    pub fn snapshot(&self) -> Arc<State> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Start changing the state, after any other writer has finished. The
+    /// changes are published when the writer is dropped.
+    pub async fn write(&self) -> StateWriter<'_> {
+        let guard = self.writer.lock().await;
+        self.writer_with(guard)
+    }
+
+    /// Like `write()` but returns `None` instead of waiting.
+    pub fn try_write(&self) -> Option<StateWriter<'_>> {
+        let guard = self.writer.try_lock().ok()?;
+        Some(self.writer_with(guard))
+    }
+
+    fn writer_with<'a>(&'a self, guard: MutexGuard<'a, ()>) -> StateWriter<'a> {
+        StateWriter {
+            shared: self,
+            state: Some(State::clone(&self.snapshot())),
+            _guard: guard,
+        }
+    }
+}
+
+/// A copy of the state being changed. See `SharedState::write()`.
+pub struct StateWriter<'a> {
+    shared: &'a SharedState,
+    // Only `None` while it is being published.
+    state: Option<State>,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl StateWriter<'_> {
+    /// Publish the changes now, and return the new snapshot.
+    pub fn commit(mut self) -> Arc<State> {
+        self.publish()
+    }
+
+    fn publish(&mut self) -> Arc<State> {
+        let state = Arc::new(self.state.take().unwrap_or_default());
+        *self.shared.current.write().unwrap() = state.clone();
+        state
+    }
+}
+
+impl Deref for StateWriter<'_> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        self.state.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for StateWriter<'_> {
+    fn deref_mut(&mut self) -> &mut State {
+        self.state.as_mut().unwrap()
+    }
+}
+
+impl Drop for StateWriter<'_> {
+    fn drop(&mut self) {
+        if self.state.is_some() {
+            self.publish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let shared = SharedState::default();
+        let uri = Url::parse("file:///ws/a.sail").unwrap();
+        let fixities = sail_parser::FixityTable::default();
+
+        let before = shared.snapshot();
+        let mut writer = shared.write().await;
+        writer.open_files.insert(
+            uri.clone(),
+            Arc::new(File::new(
+                "register x : int".to_string(),
+                PositionEncoding::default(),
+                &fixities,
+            )),
+        );
+
+        // Readers see the old state until the writer has finished, and
+        // don't have to wait for it.
+        assert!(shared.snapshot().open_files.is_empty());
+        assert!(shared.try_write().is_none());
+        drop(writer);
+
+        let after = shared.snapshot();
+        assert!(before.open_files.is_empty());
+        assert!(after.open_files.contains_key(&uri));
+
+        // Unchanged files are shared.
+        let committed = shared.try_write().unwrap().commit();
+        assert!(Arc::ptr_eq(
+            &after.open_files[&uri],
+            &committed.open_files[&uri]
+        ));
+        // So are the files on disk when only open files change.
+        assert!(Arc::ptr_eq(&before.disk_files, &after.disk_files));
+        assert!(Arc::ptr_eq(&before.project, &after.project));
+    }
+
+    #[test]
+    fn test_update_fixities_of() {
+        let mut state = State::default();
+        let a = Url::parse("file:///ws/a.sail").unwrap();
+        let b = Url::parse("file:///ws/b.sail").unwrap();
+        let open = |state: &mut State, uri: &Url, text: &str| {
+            let file = File::new(
+                text.to_string(),
+                PositionEncoding::default(),
+                &state.fixities,
+            );
+            state.open_files.insert(uri.clone(), Arc::new(file));
+        };
+
+        open(&mut state, &a, "infixl 5 <<<\n");
+        assert!(state.update_fixities_of([&a]));
+        assert_eq!(state.fixities.get("<<<").level, 5);
+
+        // Files without declarations, or with the same ones, don't change
+        // the table.
+        open(&mut state, &b, "register x : int\n");
+        assert!(!state.update_fixities_of([&b]));
+        open(&mut state, &a, "infixl 5 <<<\nregister y : int\n");
+        assert!(!state.update_fixities_of([&a]));
+        assert_eq!(state.fixity_declarations.len(), 1);
+
+        state.open_files.remove(&a);
+        assert!(state.update_fixities_of([&a]));
+        assert_eq!(state.fixities, sail_parser::FixityTable::default());
+        assert!(!state.update_fixities());
+    }
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+
+use sail_parser::{Def, Ident, ScatteredDef, Span};
+use tower_lsp::lsp_types::{DocumentSymbol, Location, Range, SymbolInformation, SymbolKind, Url};
+
+use crate::definitions::DefinitionKind;
+use crate::file::File;
+use crate::fuzzy::fuzzy_score;
+
+/// The maximum number of workspace symbols to return. Editors refine the
+/// query as you type so there's no point sending thousands.
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+
+/// Text for a span on one line, shortened if it is very long.
+fn summary(text: &str, span: Span) -> String {
+    let summary = text[span.start..span.end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    match summary.char_indices().nth(60) {
+        Some((end, _)) => format!("{}…", &summary[..end]),
+        None => summary,
+    }
+}
+
+struct Builder<'a> {
+    file: &'a File,
+}
+
+impl Builder<'_> {
+    fn range(&self, span: Span) -> Range {
+        Range::new(
+            self.file.source.position_at(span.start),
+            self.file.source.position_at(span.end),
+        )
+    }
+
+    fn summary(&self, span: Span) -> String {
+        summary(self.file.source.text(), span)
+    }
+
+    #[allow(deprecated)]
+    fn symbol(
+        &self,
+        name: String,
+        detail: Option<String>,
+        kind: SymbolKind,
+        span: Span,
+        selection_span: Span,
+        children: Vec<DocumentSymbol>,
+    ) -> DocumentSymbol {
+        DocumentSymbol {
+            // Editors don't like empty names.
+            name: if name.is_empty() {
+                "_".to_string()
+            } else {
+                name
+            },
+            detail,
+            kind,
+            tags: None,
+            deprecated: None,
+            range: self.range(span),
+            selection_range: self.range(selection_span),
+            children: (!children.is_empty()).then_some(children),
+        }
+    }
+
+    /// A symbol for a definition with a name.
+    fn named(
+        &self,
+        (name, name_span): &Ident,
+        detail: Option<String>,
+        kind: SymbolKind,
+        span: Span,
+        children: Vec<DocumentSymbol>,
+    ) -> DocumentSymbol {
+        self.symbol(name.clone(), detail, kind, span, *name_span, children)
+    }
+
+    /// A symbol for something that doesn't have a name, like a mapping
+    /// clause. The text is used as the name.
+    fn unnamed(&self, kind: SymbolKind, span: Span) -> DocumentSymbol {
+        self.symbol(self.summary(span), None, kind, span, span, Vec::new())
+    }
+}
+
+/// Add a clause to its scattered definition if that is in this file,
+/// otherwise add `orphan` at the top level.
+fn add_clause(
+    symbols: &mut Vec<DocumentSymbol>,
+    scattered: &HashMap<(&str, &str), usize>,
+    key: (&str, &str),
+    child: DocumentSymbol,
+    orphan: DocumentSymbol,
+) {
+    match scattered.get(&key) {
+        Some(&index) => {
+            // The parent's range has to contain its children, otherwise
+            // breadcrumbs don't work.
+            let parent = &mut symbols[index];
+            parent.range.end = child.range.end;
+            parent.children.get_or_insert_with(Vec::new).push(child);
+        }
+        None => symbols.push(orphan),
+    }
+}
+
+/// The outline of a file. Types contain their fields, members or
+/// constructors, and scattered definitions contain the clauses for them in
+/// this file.
+pub fn document_symbols(file: &File) -> Vec<DocumentSymbol> {
+    let b = Builder { file };
+    let mut symbols = Vec::new();
+    // Scattered definitions in this file, as (kind, name) -> index in
+    // `symbols`. Clauses are added to these if possible.
+    let mut scattered: HashMap<(&str, &str), usize> = HashMap::new();
+
+    for (def, span) in &file.ast.defs {
+        let span = *span;
+        match def {
+            Def::Val(val) => {
+                let detail = b.summary(val.typschm.1);
+                symbols.push(b.named(
+                    &val.name,
+                    Some(detail),
+                    SymbolKind::FUNCTION,
+                    span,
+                    Vec::new(),
+                ));
+            }
+            Def::Function(function) => {
+                for (clause, clause_span) in &function.clauses {
+                    symbols.push(b.named(
+                        &clause.name,
+                        None,
+                        SymbolKind::FUNCTION,
+                        *clause_span,
+                        Vec::new(),
+                    ));
+                }
+            }
+            Def::FunctionClause(clause) => {
+                let params = b.summary(clause.params.1);
+                add_clause(
+                    &mut symbols,
+                    &scattered,
+                    ("function", &clause.name.0),
+                    b.symbol(
+                        params.clone(),
+                        None,
+                        SymbolKind::FUNCTION,
+                        span,
+                        clause.params.1,
+                        Vec::new(),
+                    ),
+                    b.named(
+                        &clause.name,
+                        Some(params),
+                        SymbolKind::FUNCTION,
+                        span,
+                        Vec::new(),
+                    ),
+                );
+            }
+            Def::Mapping(mapping) => {
+                let children = mapping
+                    .clauses
+                    .iter()
+                    .map(|(_, span)| b.unnamed(SymbolKind::FUNCTION, *span))
+                    .collect();
+                let detail = mapping.typschm.as_ref().map(|(_, span)| b.summary(*span));
+                symbols.push(b.named(&mapping.name, detail, SymbolKind::FUNCTION, span, children));
+            }
+            Def::MappingClause { name, clause } => {
+                add_clause(
+                    &mut symbols,
+                    &scattered,
+                    ("mapping", &name.0),
+                    b.unnamed(SymbolKind::FUNCTION, clause.1),
+                    b.named(
+                        name,
+                        Some(b.summary(clause.1)),
+                        SymbolKind::FUNCTION,
+                        span,
+                        Vec::new(),
+                    ),
+                );
+            }
+            Def::Type(type_) => {
+                let detail = type_.typ.as_ref().map(|(_, span)| b.summary(*span));
+                symbols.push(b.named(&type_.name, detail, SymbolKind::CLASS, span, Vec::new()));
+            }
+            Def::Struct(struct_) => {
+                let children = struct_
+                    .fields
+                    .iter()
+                    .map(|(name, (_, typ_span))| {
+                        b.named(
+                            name,
+                            Some(b.summary(*typ_span)),
+                            SymbolKind::FIELD,
+                            Span::new(name.1.start, typ_span.end),
+                            Vec::new(),
+                        )
+                    })
+                    .collect();
+                symbols.push(b.named(&struct_.name, None, SymbolKind::STRUCT, span, children));
+            }
+            Def::Union(union) => {
+                let children = union
+                    .variants
+                    .iter()
+                    .map(|variant| {
+                        b.named(
+                            &variant.name,
+                            Some(b.summary(variant.typ.1)),
+                            SymbolKind::CONSTRUCTOR,
+                            Span::new(variant.name.1.start, variant.typ.1.end),
+                            Vec::new(),
+                        )
+                    })
+                    .collect();
+                symbols.push(b.named(&union.name, None, SymbolKind::ENUM, span, children));
+            }
+            Def::UnionClause { name, variant } => {
+                let detail = b.summary(variant.typ.1);
+                add_clause(
+                    &mut symbols,
+                    &scattered,
+                    ("union", &name.0),
+                    b.named(
+                        &variant.name,
+                        Some(detail.clone()),
+                        SymbolKind::CONSTRUCTOR,
+                        span,
+                        Vec::new(),
+                    ),
+                    b.named(
+                        &variant.name,
+                        Some(format!("{}: {}", name.0, detail)),
+                        SymbolKind::CONSTRUCTOR,
+                        span,
+                        Vec::new(),
+                    ),
+                );
+            }
+            Def::Enum(enum_) => {
+                let children = enum_
+                    .members
+                    .iter()
+                    .map(|member| {
+                        let span = match &member.value {
+                            Some((_, value_span)) => Span::new(member.name.1.start, value_span.end),
+                            None => member.name.1,
+                        };
+                        b.named(
+                            &member.name,
+                            None,
+                            SymbolKind::ENUM_MEMBER,
+                            span,
+                            Vec::new(),
+                        )
+                    })
+                    .collect();
+                symbols.push(b.named(&enum_.name, None, SymbolKind::ENUM, span, children));
+            }
+            Def::EnumClause { name, member } => {
+                add_clause(
+                    &mut symbols,
+                    &scattered,
+                    ("enum", &name.0),
+                    b.named(member, None, SymbolKind::ENUM_MEMBER, span, Vec::new()),
+                    b.named(
+                        member,
+                        Some(name.0.clone()),
+                        SymbolKind::ENUM_MEMBER,
+                        span,
+                        Vec::new(),
+                    ),
+                );
+            }
+            Def::Bitfield(bitfield) => {
+                let children = bitfield
+                    .fields
+                    .iter()
+                    .map(|field| {
+                        let end = field.low.as_ref().unwrap_or(&field.high).1.end;
+                        let bits = Span::new(field.high.1.start, end);
+                        b.named(
+                            &field.name,
+                            Some(b.summary(bits)),
+                            SymbolKind::FIELD,
+                            Span::new(field.name.1.start, end),
+                            Vec::new(),
+                        )
+                    })
+                    .collect();
+                symbols.push(b.named(
+                    &bitfield.name,
+                    Some(b.summary(bitfield.typ.1)),
+                    SymbolKind::STRUCT,
+                    span,
+                    children,
+                ));
+            }
+            Def::Register(register) => {
+                symbols.push(b.named(
+                    &register.name,
+                    Some(b.summary(register.typ.1)),
+                    SymbolKind::VARIABLE,
+                    span,
+                    Vec::new(),
+                ));
+            }
+            Def::Overload(overload) => {
+                let members = overload
+                    .members
+                    .iter()
+                    .map(|(member, _)| member.as_str())
+                    .collect::<Vec<_>>();
+                symbols.push(b.named(
+                    &overload.name,
+                    Some(members.join(", ")),
+                    SymbolKind::FUNCTION,
+                    span,
+                    Vec::new(),
+                ));
+            }
+            Def::Scattered(scattered_def) => {
+                let (key, name, kind) = match scattered_def {
+                    ScatteredDef::Function(name) => ("function", name, SymbolKind::FUNCTION),
+                    ScatteredDef::Mapping { name, .. } => ("mapping", name, SymbolKind::FUNCTION),
+                    ScatteredDef::Union { name, .. } => ("union", name, SymbolKind::ENUM),
+                    ScatteredDef::Enum(name) => ("enum", name, SymbolKind::ENUM),
+                };
+                scattered.insert((key, &name.0), symbols.len());
+                symbols.push(b.named(name, None, kind, span, Vec::new()));
+            }
+            Def::Let(binding) => {
+                symbols.push(b.symbol(
+                    b.summary(binding.pat.1),
+                    None,
+                    SymbolKind::CONSTANT,
+                    span,
+                    binding.pat.1,
+                    Vec::new(),
+                ));
+            }
+            Def::End(_)
+            | Def::TerminationMeasure { .. }
+            | Def::DefaultOrder(_)
+            | Def::Fixity(_)
+            | Def::Directive(_)
+            | Def::Attribute
+            | Def::Error(_) => {}
+        }
+    }
+    symbols
+}
+
+/// Search for definitions in the whole workspace by fuzzy matching their
+/// names. Clauses are skipped because there can be hundreds for a single
+/// name (e.g. `execute`), and the scattered definition is enough.
+#[allow(deprecated)]
+pub fn workspace_symbols<'a>(
+    files: impl Iterator<Item = (&'a Url, &'a File)>,
+    query: &str,
+) -> Vec<SymbolInformation> {
+    let mut matches = Vec::new();
+    for (uri, file) in files {
+        for (name, definitions) in &file.definitions {
+            let Some(score) = fuzzy_score(query, name) else {
+                continue;
+            };
+            for definition in definitions {
+                if matches!(
+                    definition.kind,
+                    DefinitionKind::FunctionClause | DefinitionKind::MappingClause
+                ) {
+                    continue;
+                }
+                matches.push((score, name, definition, uri, file));
+            }
+        }
+    }
+
+    // Best first, then sort so the order is deterministic.
+    matches.sort_by(|a, b| {
+        b.0.cmp(&a.0)
+            .then_with(|| a.1.cmp(b.1))
+            .then_with(|| a.2.kind.cmp(&b.2.kind))
+            .then_with(|| a.3.cmp(b.3))
+            .then_with(|| a.2.span.start.cmp(&b.2.span.start))
+    });
+    matches.truncate(MAX_WORKSPACE_SYMBOLS);
+
+    matches
+        .into_iter()
+        .map(|(_, name, definition, uri, file)| SymbolInformation {
+            name: name.clone(),
+            kind: definition.kind.lsp_kinds().0,
+            tags: None,
+            deprecated: None,
+            location: Location::new(
+                uri.clone(),
+                Range::new(
+                    file.source.position_at(definition.span.start),
+                    file.source.position_at(definition.span.end),
+                ),
+            ),
+            container_name: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text_document::PositionEncoding;
+
+    /// Show the outline as indented `kind name (detail)` lines.
+    fn outline(symbols: &[DocumentSymbol], depth: usize, out: &mut String) {
+        for symbol in symbols {
+            out.push_str(&format!(
+                "{}{:?} {}",
+                "  ".repeat(depth),
+                symbol.kind,
+                symbol.name
+            ));
+            if let Some(detail) = &symbol.detail {
+                out.push_str(&format!(" ({})", detail));
+            }
+            out.push('\n');
+            outline(
+                symbol.children.as_deref().unwrap_or_default(),
+                depth + 1,
+                out,
+            );
+        }
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let file = File::new(
+            r#"struct point = { x : int, y : int }
+enum colour = { Red, Green }
+bitfield Status : bits(8) = { HIGH : 7 .. 4, LOW : 0 }
+scattered union ast
+union clause ast = ADD : (bits(5), bits(5))
+union clause instr = NOP : unit
+mapping encdec : ast <-> bits(32) = {
+  ADD(a, b) <-> a @ b
+}
+val execute : ast -> unit
+scattered function execute
+function clause execute(ADD(a, b)) = ()
+"#
+            .to_string(),
+            PositionEncoding::default(),
+            &sail_parser::FixityTable::default(),
+        );
+        let mut out = String::new();
+        outline(&document_symbols(&file), 0, &mut out);
+        assert_eq!(
+            out,
+            "Struct point
+  Field x (int)
+  Field y (int)
+Enum colour
+  EnumMember Red
+  EnumMember Green
+Struct Status (bits(8))
+  Field HIGH (7 .. 4)
+  Field LOW (0)
+Enum ast
+  Constructor ADD ((bits(5), bits(5)))
+Constructor NOP (instr: unit)
+Function encdec (ast <-> bits(32))
+  Function ADD(a, b) <-> a @ b
+Function execute (ast -> unit)
+Function execute
+  Function ADD(a, b)
+"
+        );
+    }
+
+    #[test]
+    fn test_workspace_symbols() {
+        let fixities = sail_parser::FixityTable::default();
+        let a = File::new(
+            "val read_xreg : int -> int\nscattered function execute\nfunction clause execute(x) = x\n"
+                .to_string(), PositionEncoding::default(),
+            &fixities,
+        );
+        let b = File::new(
+            "register xregs : int\ntype xreg = bits(5)\n".to_string(),
+            PositionEncoding::default(),
+            &fixities,
+        );
+        let a_uri = Url::parse("file:///ws/a.sail").unwrap();
+        let b_uri = Url::parse("file:///ws/b.sail").unwrap();
+        let files = [(&a_uri, &a), (&b_uri, &b)];
+
+        let symbols = workspace_symbols(files.into_iter(), "xreg")
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.kind, symbol.location.uri == b_uri))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            symbols,
+            [
+                ("xreg".to_string(), SymbolKind::CLASS, true),
+                ("xregs".to_string(), SymbolKind::VARIABLE, true),
+                ("read_xreg".to_string(), SymbolKind::FUNCTION, false),
+            ]
+        );
+
+        // Only the scattered definition, not the clauses.
+        let symbols = workspace_symbols(files.into_iter(), "exec");
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].location.range.start.line, 1);
+    }
+}
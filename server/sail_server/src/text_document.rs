@@ -1,181 +1,176 @@
 #![allow(unused)]
 
+use std::sync::{Arc, OnceLock};
+
 use tower_lsp::lsp_types::{
-    Position as LspPosition, Range as LspRange, TextDocumentContentChangeEvent,
+    Position as LspPosition, PositionEncodingKind, Range as LspRange,
+    TextDocumentContentChangeEvent,
 };
 
+use crate::rope::Rope;
+
 type ByteIndex = usize;
 type LineIndex = usize;
-// VSCode "characters" are UTF-16 code points.
-type CharIndexUTF16 = usize;
+// LSP "characters", in the units of the position encoding.
+type CharIndex = usize;
+
+/// What the character in an LSP position counts. This is negotiated with
+/// the client when it starts. UTF-16 is the default because that is what
+/// VSCode uses, but other editors often prefer UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub const ALL: [PositionEncoding; 3] = [Self::Utf8, Self::Utf16, Self::Utf32];
+
+    /// Choose an encoding from the ones the client supports, in its order of
+    /// preference. UTF-8 is used if possible because then no conversion is
+    /// needed.
+    pub fn negotiate(client: Option<&[PositionEncodingKind]>) -> Self {
+        let supported = client
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Self::from_kind)
+            .collect::<Vec<_>>();
+        if supported.contains(&Self::Utf8) {
+            return Self::Utf8;
+        }
+        supported.first().copied().unwrap_or_default()
+    }
+
+    pub fn from_kind(kind: &PositionEncodingKind) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|encoding| encoding.kind() == *kind)
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// The length of a character in this encoding.
+    fn len(self, ch: char) -> CharIndex {
+        match self {
+            Self::Utf8 => ch.len_utf8(),
+            Self::Utf16 => ch.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct TextDocument {
-    // The text.
-    content: String,
-    // The start of each line in bytes. These could be lazily calculated but
-    // that is a bit tricky because of the borrow checker.
-    line_offsets: Vec<ByteIndex>,
+    // The text, as a rope of lines so that edits and finding lines are quick
+    // even in very long files.
+    rope: Rope,
+    // The text as one string. This is only made when it is needed, e.g. to
+    // lex it, and is shared with copies until one of them is changed.
+    content: Arc<OnceLock<String>>,
+    // The units of the characters in positions.
+    encoding: PositionEncoding,
 }
 
 impl TextDocument {
-    pub fn new(content: String) -> Self {
-        let line_offsets = compute_line_offsets(&content, true, 0);
+    pub fn new(content: String, encoding: PositionEncoding) -> Self {
         Self {
-            content,
-            line_offsets,
+            rope: Rope::new(&content),
+            content: Arc::new(OnceLock::from(content)),
+            encoding,
         }
     }
 
     pub fn text(&self) -> &str {
-        &self.content
+        self.content.get_or_init(|| self.rope.text())
     }
 
     #[cfg(test)]
     pub fn line_count(&self) -> usize {
-        self.line_offsets.len()
+        self.rope.line_count()
     }
 
     #[cfg(test)]
     pub fn text_range(&self, range: &LspRange) -> &str {
         let byte_begin = self.offset_at(&range.start);
         let byte_end = self.offset_at(&range.end);
-        &self.content[byte_begin..byte_end]
+        &self.text()[byte_begin..byte_end]
     }
 
-    // Based on this code https://github.com/microsoft/vscode-languageserver-node/blob/master/textDocument/src/main.ts#L222
-    // Apply a change to the document.
+    // Apply a change to the document. This only takes time proportional to
+    // the size of the change and the log of the size of the document.
     pub fn update(&mut self, change: &TextDocumentContentChangeEvent) {
         if let Some(range) = change.range {
             // Get the corresponding byte range.
             let byte_begin = self.offset_at(&range.start);
             let byte_end = self.offset_at(&range.end);
-            self.content
-                .replace_range(byte_begin..byte_end, &change.text);
-
-            // Calculate the line offsets for the inserted text.
-            let added_line_offsets = compute_line_offsets(&change.text, false, byte_begin);
-
-            // The line offsets that we need to delete. It is the lines one past
-            // the actual positions. Because if you edit line 10, you need to change
-            // the line offset for line 11.
-            let delete_line_offset_begin = range.start.line as usize + 1;
-            let delete_line_offset_end = range.end.line as usize + 1;
-
-            // Update the offsets after the splice according to the length change
-            // of the modified region.
-            let len_before = byte_end - byte_begin;
-            let len_after = change.text.len();
-            if len_before != len_after {
-                for offset in &mut self.line_offsets[delete_line_offset_end..] {
-                    *offset += len_after;
-                    *offset -= len_before;
-                }
-            }
-
-            // Insert the new offsets.
-            self.line_offsets.splice(
-                delete_line_offset_begin..delete_line_offset_end,
-                added_line_offsets.clone(),
-            );
+            self.rope.replace(byte_begin..byte_end, &change.text);
         } else {
             // Just completely change the text.
-            self.content = change.text.clone();
-            self.line_offsets = compute_line_offsets(&self.content, true, 0);
+            self.rope = Rope::new(&change.text);
         }
+        self.content = Arc::default();
     }
 
     // Convert a row/column position to a byte index.
     pub fn offset_at(&self, position: &LspPosition) -> ByteIndex {
-        let line_begin = self.line_start(position.line as usize);
-        let line_end = self.line_start(position.line as usize + 1);
-        let line = &self.content[line_begin..line_end];
-
-        line_begin + character_to_line_offset(line, position.character as usize)
+        match self.rope.line(position.line as LineIndex) {
+            Some((line_begin, line)) => {
+                line_begin
+                    + character_to_line_offset(line, position.character as usize, self.encoding)
+            }
+            None => self.rope.len(),
+        }
     }
 
     // Convert a byte index to a row/column position.
     pub fn position_at(&self, offset: usize) -> LspPosition {
         // Clamp to valid range.
-        let offset = std::cmp::min(offset, self.content.len());
-
-        // Binary search for a line offset that is greater than the offset.
-        match self.line_offsets.binary_search(&offset) {
-            Ok(line) => {
-                // offset actually matches one of the line_offsets. Just return it.
-                LspPosition {
-                    line: line as u32,
-                    character: 0,
-                }
-            }
-            Err(line) => {
-                // line is the line one past the one that contains the offset.
-                let line = line - 1;
-                let character = self.position_at_line(line, offset);
-
-                LspPosition {
-                    line: line as u32,
-                    character: character as u32,
-                }
-            }
-        }
-    }
-
-    fn line_start(&self, line_index: LineIndex) -> ByteIndex {
-        self.line_offsets
-            .get(line_index)
-            .copied()
-            .unwrap_or_else(|| self.content.len())
-    }
+        let offset = std::cmp::min(offset, self.rope.len());
 
-    // Given a byte offset, what is the corresponding character?
-    fn position_at_line(&self, line: LineIndex, offset: usize) -> CharIndexUTF16 {
-        let line_start = self.line_offsets[line];
-
-        assert!(line_start <= offset);
+        let (line, line_begin, line_text) = self.rope.line_at(offset);
 
         // We have to scan through the line, counting the characters.
-        let line_text = &self.content[line_start..offset];
-        line_text.chars().map(char::len_utf16).sum()
+        let line_text = &line_text[..offset - line_begin];
+        let character: CharIndex = match self.encoding {
+            PositionEncoding::Utf8 => line_text.len(),
+            encoding => line_text.chars().map(|ch| encoding.len(ch)).sum(),
+        };
+
+        LspPosition {
+            line: line as u32,
+            character: character as u32,
+        }
     }
 }
 
-// Given a UTF-16 codepoint offset in a bit of text, convert it to a byte offset.
-// Out-of-bounds offsets just return line.len().
-fn character_to_line_offset(line: &str, character: CharIndexUTF16) -> ByteIndex {
-    let mut utf16_pos = 0;
+// Given a character offset in a bit of text, convert it to a byte offset.
+// Out-of-bounds offsets just return line.len(), and offsets in the middle
+// of a character are rounded up to the next one.
+fn character_to_line_offset(
+    line: &str,
+    character: CharIndex,
+    encoding: PositionEncoding,
+) -> ByteIndex {
+    let mut pos = 0;
 
     for (byte_pos, ch) in line.char_indices() {
-        if utf16_pos == character {
+        if pos >= character {
             return byte_pos;
         }
-        utf16_pos += ch.len_utf16();
+        pos += encoding.len(ch);
     }
 
     line.len()
 }
 
-fn compute_line_offsets(text: &str, is_at_line_start: bool, text_offset: usize) -> Vec<usize> {
-    // VSCode treats `\r\n` or `\n` *or* `\r` as a single line end.
-    let mut line_offsets = Vec::new();
-    if is_at_line_start {
-        line_offsets.push(text_offset);
-    }
-    let text = text.as_bytes();
-    for i in 0..text.len() {
-        match text[i] {
-            b'\n' => line_offsets.push(text_offset + i + 1),
-            b'\r' => {
-                // This is a new line *unless* the next character is \n.
-                if text.get(i + 1) != Some(&b'\n') {
-                    line_offsets.push(text_offset + i + 1)
-                }
-            }
-            _ => {}
-        }
-    }
-    line_offsets
-}
-
 #[cfg(test)]
 mod test {
 
@@ -183,19 +178,17 @@ mod test {
 
     use tower_lsp::lsp_types::Range as LspRange;
 
-    #[test]
-    fn empty_content() {
+    fn empty_content(encoding: PositionEncoding) {
         let text = "".to_string();
-        let document = TextDocument::new(text);
+        let document = TextDocument::new(text, encoding);
         assert_eq!(document.line_count(), 1);
         assert_eq!(document.offset_at(&LspPosition::new(0, 0)), 0);
         assert_eq!(document.position_at(0), LspPosition::new(0, 0));
     }
 
-    #[test]
-    fn single_line() {
+    fn single_line(encoding: PositionEncoding) {
         let text = "Hello World".to_string();
-        let document = TextDocument::new(text.clone());
+        let document = TextDocument::new(text.clone(), encoding);
         assert_eq!(document.line_count(), 1);
 
         for (char_index, (byte_index, _)) in text.char_indices().enumerate() {
@@ -210,10 +203,9 @@ mod test {
         }
     }
 
-    #[test]
-    fn multiple_lines() {
+    fn multiple_lines(encoding: PositionEncoding) {
         let text = "ABCDE\nFGHIJ\nKLMNO\n".to_string();
-        let document = TextDocument::new(text.clone());
+        let document = TextDocument::new(text.clone(), encoding);
         assert_eq!(document.line_count(), 4);
 
         for (char_index, (byte_index, _)) in text.char_indices().enumerate() {
@@ -236,40 +228,37 @@ mod test {
         assert_eq!(document.position_at(19), LspPosition::new(3, 0));
     }
 
-    #[test]
-    fn starts_with_new_line() {
-        let document = TextDocument::new("\nABCDE".to_string());
+    fn starts_with_new_line(encoding: PositionEncoding) {
+        let document = TextDocument::new("\nABCDE".to_string(), encoding);
         assert_eq!(document.line_count(), 2);
         assert_eq!(document.position_at(0), LspPosition::new(0, 0));
         assert_eq!(document.position_at(1), LspPosition::new(1, 0));
         assert_eq!(document.position_at(6), LspPosition::new(1, 5));
     }
 
-    #[test]
-    fn new_line_characters() {
+    fn new_line_characters(encoding: PositionEncoding) {
         let text = "ABCDE\rFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 2);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 2);
 
         let text = "ABCDE\nFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 2);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 2);
 
         let text = "ABCDE\r\nFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 2);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 2);
 
         let text = "ABCDE\n\nFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 3);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 3);
 
         let text = "ABCDE\r\rFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 3);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 3);
 
         let text = "ABCDE\n\rFGHIJ".to_string();
-        assert_eq!(TextDocument::new(text).line_count(), 3);
+        assert_eq!(TextDocument::new(text, encoding).line_count(), 3);
     }
 
-    #[test]
-    fn get_text_range() {
+    fn get_text_range(encoding: PositionEncoding) {
         let text = "12345\n12345\n12345".to_string();
-        let document = TextDocument::new(text.clone());
+        let document = TextDocument::new(text.clone(), encoding);
         assert_eq!(document.text(), text);
         // assert_eq!(document.text_range(&LspRange::new(LspPosition::new(-1, 0), LspPosition::new(0, 5))), "12345");
         assert_eq!(
@@ -309,10 +298,9 @@ mod test {
         );
     }
 
-    #[test]
-    fn invalid_inputs() {
+    fn invalid_inputs(encoding: PositionEncoding) {
         let text = "Hello World".to_string();
-        let document = TextDocument::new(text.clone());
+        let document = TextDocument::new(text.clone(), encoding);
 
         // invalid position
         assert_eq!(
@@ -342,9 +330,8 @@ mod test {
 
     // Full updates.
 
-    #[test]
-    fn one_full_update() {
-        let mut document = TextDocument::new("abc123".to_string());
+    fn one_full_update(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("abc123".to_string(), encoding);
         document.update(&TextDocumentContentChangeEvent {
             text: "efg456".to_string(),
             range: None,
@@ -353,9 +340,8 @@ mod test {
         assert_eq!(document.text(), "efg456");
     }
 
-    #[test]
-    fn several_full_content_updates() {
-        let mut document = TextDocument::new("abc123".to_string());
+    fn several_full_content_updates(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("abc123".to_string(), encoding);
         document.update(&TextDocumentContentChangeEvent {
             text: "hello".to_string(),
             range: None,
@@ -401,10 +387,11 @@ mod test {
         }
     }
 
-    #[test]
-    fn incrementally_removing_content() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn incrementally_removing_content(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -417,10 +404,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_removing_multi_line_content() {
-        let mut document =
-            TextDocument::new("function abc() {\n  foo();\n  bar();\n  \n}".to_string());
+    fn incrementally_removing_multi_line_content(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  foo();\n  bar();\n  \n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 5);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -433,10 +421,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_removing_multi_line_content_2() {
-        let mut document =
-            TextDocument::new("function abc() {\n  foo();\n  bar();\n  \n}".to_string());
+    fn incrementally_removing_multi_line_content_2(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  foo();\n  bar();\n  \n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 5);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -449,10 +438,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_adding_content() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello\");\n}".to_string());
+    fn incrementally_adding_content(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -468,10 +458,10 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_adding_multi_line_content() {
+    fn incrementally_adding_multi_line_content(encoding: PositionEncoding) {
         let mut document = TextDocument::new(
             "function abc() {\n  while (true) {\n    foo();\n  };\n}".to_string(),
+            encoding,
         );
         assert_eq!(document.line_count(), 5);
         assert_valid_line_numbers(&document);
@@ -488,10 +478,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_single_line_content_more_chars() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn incrementally_replacing_single_line_content_more_chars(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -507,10 +498,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_single_line_content_less_chars() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn incrementally_replacing_single_line_content_less_chars(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -526,10 +518,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_single_line_content_same_num_of_chars() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn incrementally_replacing_single_line_content_same_num_of_chars(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -545,10 +538,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_multi_line_content_more_lines() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn incrementally_replacing_multi_line_content_more_lines(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         assert_eq!(document.line_count(), 3);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -564,9 +558,9 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_multi_line_content_less_lines() {
-        let mut document = TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string());
+    fn incrementally_replacing_multi_line_content_less_lines(encoding: PositionEncoding) {
+        let mut document =
+            TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string(), encoding);
         assert_eq!(document.line_count(), 9);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -579,9 +573,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_multi_line_content_same_num_of_lines_and_chars() {
-        let mut document = TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string());
+    fn incrementally_replacing_multi_line_content_same_num_of_lines_and_chars(
+        encoding: PositionEncoding,
+    ) {
+        let mut document =
+            TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string(), encoding);
         assert_eq!(document.line_count(), 9);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -594,9 +590,11 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn incrementally_replacing_multi_line_content_same_num_of_lines_but_diff_chars() {
-        let mut document = TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string());
+    fn incrementally_replacing_multi_line_content_same_num_of_lines_but_diff_chars(
+        encoding: PositionEncoding,
+    ) {
+        let mut document =
+            TextDocument::new("a1\nb1\na2\nb2\na3\nb3\na4\nb4\n".to_string(), encoding);
         assert_eq!(document.line_count(), 9);
         assert_valid_line_numbers(&document);
         document.update(&TextDocumentContentChangeEvent {
@@ -611,7 +609,7 @@ mod test {
 
     // #[test]
     // fn incrementally_replacing_multi_line_content_huge_number_of_lines() {
-    // 	let mut document = TextDocument::new("a1\ncc\nb1".to_string());
+    // 	let mut document = TextDocument::new("a1\ncc\nb1".to_string(), encoding);
     // 	assert_eq!(document.line_count(), 3);
     // 	assert_valid_line_numbers(&document);
     // 	let text = new Array(20000).join("\ndd"); // a string with 19999 `\n`
@@ -626,10 +624,11 @@ mod test {
     // 	assert_valid_line_numbers(&document);
     // }
 
-    #[test]
-    fn several_incremental_content_changes() {
-        let mut document =
-            TextDocument::new("function abc() {\n  console.log(\"hello, world!\");\n}".to_string());
+    fn several_incremental_content_changes(encoding: PositionEncoding) {
+        let mut document = TextDocument::new(
+            "function abc() {\n  console.log(\"hello, world!\");\n}".to_string(),
+            encoding,
+        );
         document.update(&TextDocumentContentChangeEvent {
             text: "defg".to_string(),
             range: Some(LspRange::new(
@@ -662,9 +661,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn basic_append() {
-        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string());
+    fn basic_append(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(2, 0)), 10);
 
@@ -681,9 +679,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn multi_line_append() {
-        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string());
+    fn multi_line_append(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(2, 0)), 10);
 
@@ -701,9 +698,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn basic_delete() {
-        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string());
+    fn basic_delete(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(2, 0)), 10);
 
@@ -720,9 +716,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn multi_line_delete() {
-        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string());
+    fn multi_line_delete(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(2, 0)), 10);
 
@@ -739,9 +734,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn single_character_replace() {
-        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string());
+    fn single_character_replace(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foooo\nbar\nbaz".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(2, 0)), 10);
 
@@ -758,9 +752,8 @@ mod test {
         assert_valid_line_numbers(&document);
     }
 
-    #[test]
-    fn multi_character_replace() {
-        let mut document = TextDocument::new("foo\nbar".to_string());
+    fn multi_character_replace(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
 
         assert_eq!(document.offset_at(&LspPosition::new(1, 0)), 4);
 
@@ -779,10 +772,9 @@ mod test {
 
     /* TODO: Not clear that these should pass.
 
-    #[test]
-    fn invalid_update_ranges() {
+    fn invalid_update_ranges(encoding: PositionEncoding) {
         // // Before the document starts -> before the document starts
-        // let mut document = TextDocument::new("foo\nbar".to_string());
+        // let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
         // document.update(&TextDocumentContentChangeEvent {
         // 	text: "abc123",
         // 	range: Some(LspRange::new(LspPosition::new(-2, 0), LspPosition::new(-1, 3))),
@@ -792,7 +784,7 @@ mod test {
         // assert_valid_line_numbers(&document);
 
         // // Before the document starts -> the middle of document
-        // let mut document = TextDocument::new("foo\nbar".to_string());
+        // let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
         // document.update(&TextDocumentContentChangeEvent {
         // 	text: "foobar".to_string(),
         // 	range: Some(LspRange::new(LspPosition::new(-1, 0), LspPosition::new(0, 3))),
@@ -803,7 +795,7 @@ mod test {
         // assert_valid_line_numbers(&document);
 
         // The middle of document -> after the document ends
-        let mut document = TextDocument::new("foo\nbar".to_string());
+        let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
         document.update(&TextDocumentContentChangeEvent {
             text: "foobar".to_string(),
             range: Some(LspRange::new(LspPosition::new(1, 0), LspPosition::new(1, 10))),
@@ -814,7 +806,7 @@ mod test {
         assert_valid_line_numbers(&document);
 
         // After the document ends -> after the document ends
-        let mut document = TextDocument::new("foo\nbar".to_string());
+        let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
         document.update(&TextDocumentContentChangeEvent {
             text: "abc123".to_string(),
             range: Some(LspRange::new(LspPosition::new(3, 0), LspPosition::new(6, 10))),
@@ -824,7 +816,7 @@ mod test {
         assert_valid_line_numbers(&document);
 
         // // Before the document starts -> after the document ends
-        // let mut document = TextDocument::new("foo\nbar".to_string());
+        // let mut document = TextDocument::new("foo\nbar".to_string(), encoding);
         // document.update(&TextDocumentContentChangeEvent {
         // 	text: "entirely new content".to_string(),
         // 	range: Some(LspRange::new(LspPosition::new(-1, 1), LspPosition::new(2, 10000))),
@@ -837,6 +829,250 @@ mod test {
 
     */
 
-    // TODO: Test non-ASCII characters, emojis, etc.
+    // Non-ASCII characters.
+
+    fn non_ascii(encoding: PositionEncoding) {
+        // 1, 2, 3 and 4 bytes in UTF-8.
+        let text = "a\u{e9}\u{4e2d}\u{1f600}b\n\u{1f600}".to_string();
+        let document = TextDocument::new(text.clone(), encoding);
+
+        let mut column = 0;
+        for (byte_index, ch) in text[..text.find('\n').unwrap()].char_indices() {
+            assert_eq!(document.offset_at(&LspPosition::new(0, column)), byte_index);
+            assert_eq!(
+                document.position_at(byte_index),
+                LspPosition::new(0, column)
+            );
+            column += match encoding {
+                PositionEncoding::Utf8 => ch.len_utf8(),
+                PositionEncoding::Utf16 => ch.len_utf16(),
+                PositionEncoding::Utf32 => 1,
+            } as u32;
+        }
+        let end = match encoding {
+            PositionEncoding::Utf8 => 11,
+            PositionEncoding::Utf16 => 6,
+            PositionEncoding::Utf32 => 5,
+        };
+        assert_eq!(column, end);
+        assert_eq!(document.position_at(11), LspPosition::new(0, end));
+        assert_eq!(document.position_at(12), LspPosition::new(1, 0));
+        let emoji = match encoding {
+            PositionEncoding::Utf8 => 4,
+            PositionEncoding::Utf16 => 2,
+            PositionEncoding::Utf32 => 1,
+        };
+        assert_eq!(document.position_at(16), LspPosition::new(1, emoji));
+
+        // Positions in the middle of a character go to the next one.
+        if encoding != PositionEncoding::Utf32 {
+            assert_eq!(document.offset_at(&LspPosition::new(1, 1)), 16);
+        }
+    }
+
+    fn non_ascii_update(encoding: PositionEncoding) {
+        let mut document = TextDocument::new("\u{e9}\u{1f600}\nfoo".to_string(), encoding);
+        let range = range_after_substring(&document, "\u{1f600}");
+        document.update(&TextDocumentContentChangeEvent {
+            text: "\u{4e2d}x\n".to_string(),
+            range: Some(range),
+            range_length: None,
+        });
+        assert_eq!(document.text(), "\u{e9}\u{1f600}\u{4e2d}x\n\nfoo");
+        assert_eq!(document.line_count(), 3);
+        assert_eq!(
+            document.text_range(&range_for_substring(&document, "\u{1f600}\u{4e2d}")),
+            "\u{1f600}\u{4e2d}"
+        );
+        assert_valid_line_numbers(&document);
+    }
+
+    // Run the tests with each encoding. ASCII is the same in all of them.
+    macro_rules! encoding_tests {
+        ($module:ident, $encoding:expr) => {
+            mod $module {
+                use super::PositionEncoding;
+
+                #[test]
+                fn empty_content() {
+                    super::empty_content($encoding);
+                }
+
+                #[test]
+                fn single_line() {
+                    super::single_line($encoding);
+                }
+
+                #[test]
+                fn multiple_lines() {
+                    super::multiple_lines($encoding);
+                }
+
+                #[test]
+                fn starts_with_new_line() {
+                    super::starts_with_new_line($encoding);
+                }
+
+                #[test]
+                fn new_line_characters() {
+                    super::new_line_characters($encoding);
+                }
+
+                #[test]
+                fn get_text_range() {
+                    super::get_text_range($encoding);
+                }
+
+                #[test]
+                fn invalid_inputs() {
+                    super::invalid_inputs($encoding);
+                }
+
+                #[test]
+                fn one_full_update() {
+                    super::one_full_update($encoding);
+                }
+
+                #[test]
+                fn several_full_content_updates() {
+                    super::several_full_content_updates($encoding);
+                }
+
+                #[test]
+                fn incrementally_removing_content() {
+                    super::incrementally_removing_content($encoding);
+                }
+
+                #[test]
+                fn incrementally_removing_multi_line_content() {
+                    super::incrementally_removing_multi_line_content($encoding);
+                }
+
+                #[test]
+                fn incrementally_removing_multi_line_content_2() {
+                    super::incrementally_removing_multi_line_content_2($encoding);
+                }
+
+                #[test]
+                fn incrementally_adding_content() {
+                    super::incrementally_adding_content($encoding);
+                }
+
+                #[test]
+                fn incrementally_adding_multi_line_content() {
+                    super::incrementally_adding_multi_line_content($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_single_line_content_more_chars() {
+                    super::incrementally_replacing_single_line_content_more_chars($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_single_line_content_less_chars() {
+                    super::incrementally_replacing_single_line_content_less_chars($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_single_line_content_same_num_of_chars() {
+                    super::incrementally_replacing_single_line_content_same_num_of_chars($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_multi_line_content_more_lines() {
+                    super::incrementally_replacing_multi_line_content_more_lines($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_multi_line_content_less_lines() {
+                    super::incrementally_replacing_multi_line_content_less_lines($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_multi_line_content_same_num_of_lines_and_chars() {
+                    super::incrementally_replacing_multi_line_content_same_num_of_lines_and_chars($encoding);
+                }
+
+                #[test]
+                fn incrementally_replacing_multi_line_content_same_num_of_lines_but_diff_chars() {
+                    super::incrementally_replacing_multi_line_content_same_num_of_lines_but_diff_chars($encoding);
+                }
+
+                #[test]
+                fn several_incremental_content_changes() {
+                    super::several_incremental_content_changes($encoding);
+                }
+
+                #[test]
+                fn basic_append() {
+                    super::basic_append($encoding);
+                }
+
+                #[test]
+                fn multi_line_append() {
+                    super::multi_line_append($encoding);
+                }
+
+                #[test]
+                fn basic_delete() {
+                    super::basic_delete($encoding);
+                }
+
+                #[test]
+                fn multi_line_delete() {
+                    super::multi_line_delete($encoding);
+                }
+
+                #[test]
+                fn single_character_replace() {
+                    super::single_character_replace($encoding);
+                }
+
+                #[test]
+                fn multi_character_replace() {
+                    super::multi_character_replace($encoding);
+                }
+
+                #[test]
+                fn non_ascii() {
+                    super::non_ascii($encoding);
+                }
+
+                #[test]
+                fn non_ascii_update() {
+                    super::non_ascii_update($encoding);
+                }
+            }
+        };
+    }
+
+    encoding_tests!(utf8, PositionEncoding::Utf8);
+    encoding_tests!(utf16, PositionEncoding::Utf16);
+    encoding_tests!(utf32, PositionEncoding::Utf32);
+
+    #[test]
+    fn negotiate() {
+        let negotiate = |kinds: Option<Vec<PositionEncodingKind>>| {
+            PositionEncoding::negotiate(kinds.as_deref())
+        };
+        assert_eq!(negotiate(None), PositionEncoding::Utf16);
+        assert_eq!(negotiate(Some(vec![])), PositionEncoding::Utf16);
+        assert_eq!(
+            negotiate(Some(vec![
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ])),
+            PositionEncoding::Utf8
+        );
+        assert_eq!(
+            negotiate(Some(vec![
+                PositionEncodingKind::new("utf-7"),
+                PositionEncodingKind::UTF32,
+                PositionEncodingKind::UTF16
+            ])),
+            PositionEncoding::Utf32
+        );
+    }
+
     // TODO: Fuzzing!
 }
@@ -0,0 +1,228 @@
+// A file watcher for clients that can't watch files for us. It watches each
+// directory in the workspace folders that the scan filter doesn't exclude,
+// so big excluded trees like `build/` aren't watched, and reports changes as
+// LSP file events so they are handled the same way as
+// `workspace/didChangeWatchedFiles`. New directories are added when their
+// events are handled.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher as _,
+};
+use tower_lsp::lsp_types::{FileChangeType, FileEvent, Url};
+
+use crate::filter::Filter;
+
+pub struct Watcher {
+    watcher: RecommendedWatcher,
+    // The folders that are being watched.
+    folders: HashSet<PathBuf>,
+    // The directories being watched in them.
+    dirs: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Start a watcher. `send` is called from another thread with the events
+    /// for each change.
+    pub fn new(send: impl Fn(Vec<FileEvent>) + Send + 'static) -> notify::Result<Self> {
+        let watcher = notify::recommended_watcher(move |event| match event {
+            Ok(event) => {
+                let events = file_events(event);
+                if !events.is_empty() {
+                    send(events);
+                }
+            }
+            Err(e) => eprintln!("Error watching files: {:?}", e),
+        })?;
+        Ok(Self {
+            watcher,
+            folders: HashSet::new(),
+            dirs: HashSet::new(),
+        })
+    }
+
+    /// Watch these folders and stop watching any others. Returns errors for
+    /// directories that can't be watched.
+    pub fn set_folders(&mut self, folders: &HashSet<Url>, filter: &Filter) -> Vec<String> {
+        let paths = folders
+            .iter()
+            .filter_map(|folder| folder.to_file_path().ok())
+            .collect::<HashSet<_>>();
+        let removed = self.folders.difference(&paths).cloned().collect::<Vec<_>>();
+        for old in removed {
+            self.remove_dir(&old);
+            self.folders.remove(&old);
+        }
+
+        let mut errors = Vec::new();
+        for folder in paths {
+            if self.folders.insert(folder.clone()) {
+                errors.extend(self.watch(filter.find_dirs(&folder)));
+            }
+        }
+        errors
+    }
+
+    /// Watch a new directory in the folders, and the ones in it.
+    pub fn add_dir(&mut self, folders: &HashSet<Url>, filter: &Filter, dir: &Path) -> Vec<String> {
+        self.watch(filter.find_dirs_in(folders, dir))
+    }
+
+    /// Stop watching a directory that has been deleted or moved, and the ones
+    /// in it.
+    pub fn remove_dir(&mut self, dir: &Path) {
+        let removed = self
+            .dirs
+            .iter()
+            .filter(|watched| watched.starts_with(dir))
+            .cloned()
+            .collect::<Vec<_>>();
+        for watched in removed {
+            let _ = self.watcher.unwatch(&watched);
+            self.dirs.remove(&watched);
+        }
+    }
+
+    fn watch(&mut self, dirs: Vec<PathBuf>) -> Vec<String> {
+        let mut errors = Vec::new();
+        for dir in dirs {
+            if self.dirs.contains(&dir) {
+                continue;
+            }
+            match self.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.dirs.insert(dir);
+                }
+                Err(e) => errors.push(format!("couldn't watch {}: {}", dir.display(), e)),
+            }
+        }
+        errors
+    }
+}
+
+/// Convert an event from `notify` to LSP events. Changes in `.git` are
+/// ignored because there are lots of them and they are never Sail files.
+fn file_events(event: notify::Event) -> Vec<FileEvent> {
+    let in_git = |path: &Path| path.components().any(|c| c.as_os_str() == ".git");
+    event
+        .paths
+        .iter()
+        .enumerate()
+        .filter(|(_, path)| !in_git(path))
+        .filter_map(|(i, path)| {
+            let typ = match event.kind {
+                EventKind::Create(_) => FileChangeType::CREATED,
+                EventKind::Remove(_) => FileChangeType::DELETED,
+                // Both paths are given as (from, to).
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if i == 0 => {
+                    FileChangeType::DELETED
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both)) => {
+                    FileChangeType::CREATED
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChangeType::DELETED,
+                // We don't know which end of the rename it is.
+                EventKind::Modify(ModifyKind::Name(_)) if path.exists() => FileChangeType::CREATED,
+                EventKind::Modify(ModifyKind::Name(_)) => FileChangeType::DELETED,
+                EventKind::Modify(_) => FileChangeType::CHANGED,
+                _ => return None,
+            };
+            Some(FileEvent {
+                uri: Url::from_file_path(path).ok()?,
+                typ,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, DataChange};
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_events() {
+        let dir = std::env::temp_dir();
+        let event = |kind, paths: &[&str]| {
+            let event = notify::Event::new(kind);
+            let paths = paths.iter().map(|path| dir.join(path)).collect();
+            file_events(notify::Event { paths, ..event })
+                .into_iter()
+                .map(|event| (event.uri, event.typ))
+                .collect::<Vec<_>>()
+        };
+        let uri = |path| Url::from_file_path(dir.join(path)).unwrap();
+
+        assert_eq!(
+            event(EventKind::Create(CreateKind::File), &["a.sail"]),
+            [(uri("a.sail"), FileChangeType::CREATED)]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                &["a.sail"]
+            ),
+            [(uri("a.sail"), FileChangeType::CHANGED)]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["old", "new"]
+            ),
+            [
+                (uri("old"), FileChangeType::DELETED),
+                (uri("new"), FileChangeType::CREATED)
+            ]
+        );
+        assert!(event(EventKind::Create(CreateKind::File), &[".git/index"]).is_empty());
+        assert!(event(EventKind::Access(AccessKind::Any), &["a.sail"]).is_empty());
+    }
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join(format!("sail_watcher_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("build")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "build/\n").unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut watcher = Watcher::new(move |events| {
+            let _ = sender.send(events);
+        })
+        .unwrap();
+        let folder = Url::from_directory_path(&dir).unwrap();
+        let folders = HashSet::from([folder]);
+        let filter = Filter::new(&Default::default());
+        assert!(watcher.set_folders(&folders, &filter).is_empty());
+        assert_eq!(watcher.dirs, HashSet::from([dir.clone()]));
+
+        // Wait for an event for a file.
+        let wait_for = |path: PathBuf| {
+            let uri = Url::from_file_path(path).unwrap();
+            std::iter::from_fn(|| receiver.recv_timeout(Duration::from_secs(5)).ok())
+                .flatten()
+                .any(|event| event.uri == uri)
+        };
+        std::fs::write(dir.join("a.sail"), "register a : int").unwrap();
+        assert!(wait_for(dir.join("a.sail")));
+
+        // New directories are watched once they are added.
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        assert!(wait_for(dir.join("sub")));
+        assert!(watcher
+            .add_dir(&folders, &filter, &dir.join("sub"))
+            .is_empty());
+        std::fs::write(dir.join("sub/b.sail"), "").unwrap();
+        assert!(wait_for(dir.join("sub/b.sail")));
+
+        watcher.remove_dir(&dir.join("sub"));
+        assert!(watcher.set_folders(&HashSet::new(), &filter).is_empty());
+        assert!(watcher.dirs.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}